@@ -0,0 +1,67 @@
+use clap::ValueEnum;
+use serde::Serialize;
+
+/// How a point was recorded, as far as the source tells us.
+#[derive(
+    Clone, Copy, Debug, Default, PartialEq, Eq, Hash, PartialOrd, Ord, ValueEnum, Serialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum ActivityKind {
+    Walk,
+    Run,
+    Hike,
+    Ride,
+    Drive,
+    Transit,
+    Flight,
+    Other,
+    #[default]
+    Unknown,
+}
+
+impl ActivityKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ActivityKind::Walk => "walk",
+            ActivityKind::Run => "run",
+            ActivityKind::Hike => "hike",
+            ActivityKind::Ride => "ride",
+            ActivityKind::Drive => "drive",
+            ActivityKind::Transit => "transit",
+            ActivityKind::Flight => "flight",
+            ActivityKind::Other => "other",
+            ActivityKind::Unknown => "unknown",
+        }
+    }
+
+    /// Maps the activity labels used by the different sources onto a kind,
+    /// e.g. FIT `e_biking`, Strava `Trail Run` or Google `in passenger vehicle`.
+    pub fn from_label(label: &str) -> Self {
+        let label = label.to_lowercase().replace(['_', '-'], " ");
+        let words: Vec<&str> = label.split_whitespace().collect();
+        let contains = |needles: &[&str]| needles.iter().any(|n| label.contains(n));
+        let has_word = |needles: &[&str]| needles.iter().any(|n| words.contains(n));
+
+        if words.is_empty() || contains(&["unknown"]) {
+            ActivityKind::Unknown
+        } else if contains(&["fly", "flight"]) {
+            ActivityKind::Flight
+        } else if contains(&["run"]) {
+            ActivityKind::Run
+        } else if contains(&["hik"]) {
+            ActivityKind::Hike
+        } else if contains(&["walk"]) {
+            ActivityKind::Walk
+        } else if contains(&["vehicle", "driv", "motor", "automotive"]) || has_word(&["car"]) {
+            ActivityKind::Drive
+        } else if contains(&["cycl", "bik", "ride"]) {
+            ActivityKind::Ride
+        } else if contains(&["train", "rail", "subway", "metro", "ferry"])
+            || has_word(&["bus", "tram"])
+        {
+            ActivityKind::Transit
+        } else {
+            ActivityKind::Other
+        }
+    }
+}
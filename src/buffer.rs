@@ -1,106 +1,316 @@
-use geo::{MultiPoint, Point};
-use geos::{BufferParams, BufferParamsBuilder, Geom, Geometry};
+use chrono::{DateTime, Utc};
+use geo::{BoundingRect, Coord, MultiPoint, Point, Rect};
+use geos::{BufferParams, BufferParamsBuilder, GResult, Geom, Geometry};
 use indicatif::ParallelProgressIterator;
 use rayon::prelude::*;
+use rstar::primitives::{GeomWithData, Rectangle};
+use rstar::{AABB, RTree};
+
+use crate::coverage::CoverageIndex;
+use crate::hashable_point::VisitedCell;
+use crate::measure::HALF_WORLD_M;
+use crate::progress::Progress;
+
+/// Points that are buffered with the same radius, e.g. all points of one activity type.
+pub struct BufferGroup<'a> {
+    pub points: &'a [Point<f64>],
+    pub radius_m: f64, // e.g., 50.0
+}
+
+/// Extent and tiling of the fog layer.
+#[derive(Clone, Debug)]
+pub struct FogOptions {
+    /// Area covered by fog in EPSG_METERS, the whole world if `None`
+    pub extent: Option<Rect>,
+    /// Fog next to explored areas is cut into tiles no larger than this, e.g. 10_000.0
+    pub tile_size_m: f64,
+}
 
 pub fn build_buffered_geometries(
-    points: &[Point<f64>],
-    radius_m: f64,             // e.g., 50.0
+    groups: &[BufferGroup],
     quad_segs: i32,            // e.g., 8
     chunk_size: usize,         // e.g., 100_000
     simplify_tol: Option<f64>, // e.g., Some(0.5) to reduce vertices a bit
-) -> Vec<Geometry> {
-    let buf_params: BufferParams = BufferParamsBuilder::default()
-        .quadrant_segments(quad_segs)
-        .build()
-        .expect("Buffer params to be built");
+    progress: Progress,
+) -> GResult<Vec<Geometry>> {
+    let buf_params = buffer_params(quad_segs)?;
 
     // Buffer in chunks to keep memory predictable using parallel processing.
-    let chunks: Vec<_> = points.chunks(chunk_size).collect();
+    let chunks: Vec<_> = groups
+        .iter()
+        .flat_map(|group| {
+            group
+                .points
+                .chunks(chunk_size)
+                .map(move |chunk| (chunk, group.radius_m))
+        })
+        .collect();
     let total_chunks = chunks.len();
-    println!(
+    progress!(
+        progress,
         "Processing {} chunks of {} points each...",
-        total_chunks, chunk_size
+        total_chunks,
+        chunk_size
     );
 
     let buffered_parts: Vec<Geometry> = chunks
         .into_par_iter()
-        .progress()
-        .map(|chunk| {
+        .progress_with(progress.bar(total_chunks))
+        .map(|(chunk, radius_m)| {
             // MultiPoint -> GEOS
             let mp = MultiPoint::from(chunk.to_vec());
-            let g = Geometry::try_from(&mp).expect("geo->geos conversion failed");
+            let g = Geometry::try_from(&mp)?;
 
             // Buffer this chunk (returns MultiPolygon or Polygon)
             g.buffer_with_params(radius_m, &buf_params)
-                .expect("buffer failed")
         })
-        .collect();
+        .collect::<GResult<_>>()?;
 
-    println!("Dissolving chunks");
+    progress!(progress, "Dissolving chunks");
 
     // Dissolve across chunks.
-    let coll =
-        Geometry::create_geometry_collection(buffered_parts).expect("geometry collection failed");
-    let mut dissolved = coll.unary_union().expect("unary_union failed");
+    let coll = Geometry::create_geometry_collection(buffered_parts)?;
+    let mut dissolved = coll.unary_union()?;
 
     // Optional light simplification (topology-preserving).
     if let Some(tol) = simplify_tol {
-        dissolved = dissolved
-            .topology_preserve_simplify(tol)
-            .expect("simplify failed");
+        dissolved = dissolved.topology_preserve_simplify(tol)?;
     }
 
     // Explode to individual Polygon geometries.
-    let polygons = explode_polygons(dissolved);
+    let polygons = explode_polygons(dissolved)?;
 
     // Remove small holes from each polygon
-    println!("Removing small holes...");
-    let min_hole_area = std::f64::consts::PI * radius_m * radius_m; // Area of circle with given radius
+    progress!(progress, "Removing small holes...");
+    let radius_m = groups.iter().map(|g| g.radius_m).fold(0.0, f64::max);
+    let min_hole_area = std::f64::consts::PI * radius_m * radius_m; // Area of circle with largest radius
+    let bar = progress.bar(polygons.len());
     polygons
         .into_par_iter()
-        .progress()
+        .progress_with(bar)
         .map(|poly| remove_small_holes(poly, min_hole_area))
         .collect()
 }
 
+/// Buffers the few points of e.g. one activity into a single dissolved geometry,
+/// `None` without points. Unlike `build_buffered_geometries` it neither chunks nor
+/// reports progress, so many groups can be buffered in parallel.
+pub fn buffer_points(
+    groups: &[BufferGroup],
+    quad_segs: i32,
+    simplify_tol: Option<f64>,
+) -> GResult<Option<Geometry>> {
+    let buf_params = buffer_params(quad_segs)?;
+    let parts: Vec<Geometry> = groups
+        .iter()
+        .filter(|group| !group.points.is_empty())
+        .map(|group| {
+            let mp = MultiPoint::from(group.points.to_vec());
+            Geometry::try_from(&mp)?.buffer_with_params(group.radius_m, &buf_params)
+        })
+        .collect::<GResult<_>>()?;
+    if parts.is_empty() {
+        return Ok(None);
+    }
+
+    let dissolved = Geometry::create_geometry_collection(parts)?.unary_union()?;
+    Ok(Some(match simplify_tol {
+        Some(tol) => dissolved.topology_preserve_simplify(tol)?,
+        None => dissolved,
+    }))
+}
+
+fn buffer_params(quad_segs: i32) -> GResult<BufferParams> {
+    BufferParamsBuilder::default()
+        .quadrant_segments(quad_segs)
+        .build()
+}
+
+/// What a dissolved buffer polygon was built from.
+#[derive(Default)]
+pub struct BufferStats {
+    /// Number of points in the polygon
+    pub points: u64,
+    /// Number of distinct activities with points in the polygon
+    pub activities: u64,
+    pub first_seen: Option<DateTime<Utc>>,
+    pub last_seen: Option<DateTime<Utc>>,
+}
+
+/// Sums up the cells lying in every polygon of `coverage`, in the order of its polygons.
+pub fn buffer_stats(coverage: &CoverageIndex, cells: &[VisitedCell]) -> Vec<BufferStats> {
+    let located: Vec<(usize, &VisitedCell)> = cells
+        .par_iter()
+        .filter_map(|cell| Some((coverage.polygon_at(&cell.point)?, cell)))
+        .collect();
+
+    let mut stats: Vec<BufferStats> = coverage
+        .polygons()
+        .iter()
+        .map(|_| BufferStats::default())
+        .collect();
+    let mut activities: Vec<Vec<usize>> = vec![Vec::new(); stats.len()];
+    for (i, cell) in located {
+        let polygon_stats = &mut stats[i];
+        polygon_stats.points += cell.count;
+        polygon_stats.first_seen = match (polygon_stats.first_seen, cell.first_seen) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+        polygon_stats.last_seen = polygon_stats.last_seen.max(cell.last_seen);
+        activities[i].extend(&cell.activities);
+    }
+
+    stats
+        .par_iter_mut()
+        .zip(activities)
+        .for_each(|(polygon_stats, mut activities)| {
+            activities.sort_unstable();
+            activities.dedup();
+            polygon_stats.activities = activities.len() as u64;
+        });
+    stats
+}
+
+/// Cuts the dissolved `coverage` out of the fog extent. The extent is split into
+/// quarters where it touches the coverage until the pieces are no larger than
+/// `tile_size_m`, so the fog consists of many small polygons next to explored areas
+/// and few large ones far away from them.
+pub fn build_fog_geometries(
+    coverage: &[Geometry],
+    options: &FogOptions,
+    progress: Progress,
+) -> GResult<Vec<Geometry>> {
+    let extent = options.extent.unwrap_or_else(|| {
+        Rect::new(
+            Coord {
+                x: -HALF_WORLD_M,
+                y: -HALF_WORLD_M,
+            },
+            Coord {
+                x: HALF_WORLD_M,
+                y: HALF_WORLD_M,
+            },
+        )
+    });
+
+    let envelopes: Vec<GeomWithData<Rectangle<[f64; 2]>, usize>> = coverage
+        .iter()
+        .enumerate()
+        .filter_map(|(i, g)| {
+            let rect = geo::Geometry::try_from(g).ok()?.bounding_rect()?;
+            let corners = Rectangle::from_corners(rect.min().into(), rect.max().into());
+            Some(GeomWithData::new(corners, i))
+        })
+        .collect();
+    let tree = RTree::bulk_load(envelopes);
+
+    let mut pending = vec![extent];
+    let mut tiles = Vec::new();
+    while let Some(rect) = pending.pop() {
+        let envelope = AABB::from_corners(rect.min().into(), rect.max().into());
+        let covered: Vec<usize> = tree
+            .locate_in_envelope_intersecting(&envelope)
+            .map(|entry| entry.data)
+            .collect();
+        if covered.is_empty() || rect.width().max(rect.height()) <= options.tile_size_m {
+            tiles.push((rect, covered));
+        } else {
+            pending.extend(split_in_quarters(rect));
+        }
+    }
+
+    progress!(
+        progress,
+        "Cutting the coverage out of {} fog tiles...",
+        tiles.len()
+    );
+    let bar = progress.bar(tiles.len());
+    let pieces: Vec<Vec<Geometry>> = tiles
+        .into_par_iter()
+        .progress_with(bar)
+        .map(|(rect, covered)| {
+            let tile = Geometry::try_from(&rect.to_polygon())?;
+            if covered.is_empty() {
+                return Ok(vec![tile]);
+            }
+            // the coverage is dissolved, so its polygons don't overlap
+            let covered = Geometry::create_multipolygon(
+                covered
+                    .into_iter()
+                    .map(|i| Clone::clone(&coverage[i]))
+                    .collect(),
+            )?;
+            // fully explored tiles leave an empty polygon
+            Ok(explode_polygons(tile.difference(&covered)?)?
+                .into_iter()
+                .filter(|polygon| !polygon.is_empty().unwrap_or(true))
+                .collect())
+        })
+        .collect::<GResult<_>>()?;
+    Ok(pieces.concat())
+}
+
+fn split_in_quarters(rect: Rect) -> [Rect; 4] {
+    let (min, center, max) = (rect.min(), rect.center(), rect.max());
+    [
+        Rect::new(min, center),
+        Rect::new(
+            Coord {
+                x: center.x,
+                y: min.y,
+            },
+            Coord {
+                x: max.x,
+                y: center.y,
+            },
+        ),
+        Rect::new(
+            Coord {
+                x: min.x,
+                y: center.y,
+            },
+            Coord {
+                x: center.x,
+                y: max.y,
+            },
+        ),
+        Rect::new(center, max),
+    ]
+}
+
 /// Extracts all Polygon parts (flattens MultiPolygon/GeometryCollection).
-fn explode_polygons(g: Geometry) -> Vec<Geometry> {
+pub fn explode_polygons(g: Geometry) -> GResult<Vec<Geometry>> {
     match g.geometry_type() {
-        geos::GeometryTypes::Polygon => vec![g],
+        geos::GeometryTypes::Polygon => Ok(vec![g]),
         geos::GeometryTypes::MultiPolygon | geos::GeometryTypes::GeometryCollection => {
-            let n = g.get_num_geometries().expect("get geometries");
+            let n = g.get_num_geometries()?;
             let mut out = Vec::with_capacity(n);
-            for i in 1..n {
-                let sub: Geometry = g.get_geometry_n(i).expect("geometry to exist").clone();
+            for i in 0..n {
+                let sub: Geometry = g.get_geometry_n(i)?.clone();
                 match sub.geometry_type() {
                     geos::GeometryTypes::Polygon => out.push(sub),
                     geos::GeometryTypes::MultiPolygon | geos::GeometryTypes::GeometryCollection => {
-                        out.extend(explode_polygons(sub));
+                        out.extend(explode_polygons(sub)?);
                     }
                     _ => { /* ignore non-polygonal pieces */ }
                 }
             }
-            out
+            Ok(out)
         }
-        _ => Vec::new(),
+        _ => Ok(Vec::new()),
     }
 }
 
 /// Removes holes from a polygon that have an area smaller than the given threshold.
-fn remove_small_holes(polygon: Geometry, min_area: f64) -> Geometry {
+fn remove_small_holes(polygon: Geometry, min_area: f64) -> GResult<Geometry> {
     match polygon.geometry_type() {
         geos::GeometryTypes::Polygon => {
             // Get the exterior ring
-            let exterior = polygon
-                .get_exterior_ring()
-                .expect("polygon should have exterior ring")
-                .clone();
+            let exterior = polygon.get_exterior_ring()?.clone();
 
             // Get all interior rings (holes)
-            let num_holes = polygon
-                .get_num_interior_rings()
-                .expect("should get interior ring count");
+            let num_holes = polygon.get_num_interior_rings()?;
             let mut large_holes = Vec::new();
 
             for i in 0..num_holes {
@@ -118,8 +328,8 @@ fn remove_small_holes(polygon: Geometry, min_area: f64) -> Geometry {
             }
 
             // Create new polygon with only large holes
-            Geometry::create_polygon(exterior, large_holes).unwrap_or(polygon) // Fall back to original if creation fails
+            Ok(Geometry::create_polygon(exterior, large_holes).unwrap_or(polygon)) // Fall back to original if creation fails
         }
-        _ => polygon, // Return unchanged if not a polygon
+        _ => Ok(polygon), // Return unchanged if not a polygon
     }
 }
@@ -0,0 +1,420 @@
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use geo::{Coord, Rect};
+use std::path::PathBuf;
+
+use point_cloud::activity::ActivityKind;
+use point_cloud::crs::OutputCrs;
+use point_cloud::export::ExportRule;
+use point_cloud::period::Period;
+use point_cloud::raster::{ColorRamp, parse_hex_color};
+use point_cloud::render::PaperSize;
+use point_cloud::tracks::TrackFormat;
+use point_cloud::{DATA_DIR, OUT_DIR, UI_PUBLIC_DIR};
+
+#[derive(Parser)]
+#[command(version, about = "Convert location history into fog of war map layers")]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    #[command(flatten)]
+    pub build: BuildArgs,
+
+    /// Directory with the exports to read
+    #[arg(long, global = true, default_value = DATA_DIR)]
+    pub data_dir: PathBuf,
+
+    /// Directory the layers, tables and reports are written to
+    #[arg(long, global = true, default_value = OUT_DIR)]
+    pub out_dir: PathBuf,
+
+    /// Directory the PMTiles and manifests for the UI are written to
+    #[arg(long, global = true, default_value = UI_PUBLIC_DIR)]
+    pub ui_dir: PathBuf,
+
+    /// Also write layers as `geojson`, `gpkg` or `shapefile`, e.g. `gpkg` for all layers or
+    /// `out_buffer_100=shapefile` and `heatmap_*=geojson` for some. Append `:web-mercator`
+    /// or `:wgs84` to pick the CRS of an export
+    #[arg(long, global = true, value_parser = ExportRule::parse)]
+    pub export: Vec<ExportRule>,
+
+    /// CRS the layers are written in, `wgs84` (EPSG:4326) or `web-mercator` (EPSG:3857)
+    #[arg(long, global = true, value_enum, default_value_t = OutputCrs::Wgs84)]
+    pub crs: OutputCrs,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Report which share of the streets of an OSM extract the buffered coverage reaches
+    StreetCoverage(StreetCoverageArgs),
+    /// Report the explored area per administrative region
+    Stats(StatsArgs),
+    /// Render the fog of a previous run to an SVG or PNG poster
+    Render(RenderArgs),
+    /// Serve the built UI and the generated layers on a local HTTP server
+    Serve(ServeArgs),
+    /// Build the layers and rebuild them whenever files in the data directory change
+    Watch(WatchArgs),
+}
+
+/// Building the map layers, the default without a subcommand
+#[derive(Args)]
+pub struct BuildArgs {
+    /// Additionally build layers per calendar period, plus cumulative ones
+    #[arg(long, value_enum)]
+    pub period: Option<Period>,
+
+    /// Only use points of these activity types, e.g. `walk,run,hike`
+    #[arg(long, value_enum, value_delimiter = ',')]
+    pub activity: Vec<ActivityKind>,
+
+    /// Drop points of these activity types, e.g. `flight,transit`
+    #[arg(long, value_enum, value_delimiter = ',')]
+    pub exclude_activity: Vec<ActivityKind>,
+
+    /// Exclude segments travelled faster than this many km/h, e.g. by train or plane
+    #[arg(long)]
+    pub max_speed: Option<f64>,
+
+    /// Resample activities to one point every this many meters
+    #[arg(long, value_parser = parse_positive)]
+    pub resample: Option<f64>,
+
+    /// When resampling, tracing tiles or exporting tracks, don't connect points further apart
+    /// than this many meters
+    #[arg(long, default_value_t = 500.0)]
+    pub max_gap_m: f64,
+
+    /// When resampling, don't connect points recorded further apart than this many seconds
+    #[arg(long, default_value_t = 300)]
+    pub max_gap_s: i64,
+
+    /// Write the cleaned activities as `gpx` and/or `kml` tracks
+    #[arg(long, value_enum, value_delimiter = ',')]
+    pub export_tracks: Vec<TrackFormat>,
+
+    /// Write one track file per activity instead of a single one
+    #[arg(long, requires = "export_tracks")]
+    pub split_tracks: bool,
+
+    /// Count visited slippy map tiles at these zoom levels, e.g. `14,17` for explorer
+    /// tiles and squadrats
+    #[arg(long, value_delimiter = ',', value_parser = clap::value_parser!(u8).range(0..=24))]
+    pub tiles: Vec<u8>,
+
+    /// Aggregate points into H3 cells at these resolutions, e.g. `8,10`
+    #[arg(long, value_delimiter = ',', value_parser = clap::value_parser!(u8).range(0..=15))]
+    pub h3: Vec<u8>,
+
+    /// Also render the heatmap to a GeoTIFF, a PNG and raster tiles
+    #[arg(long)]
+    pub raster: bool,
+
+    /// Radius of the raster heatmap kernel in pixels
+    #[arg(long, default_value_t = 8.0, value_parser = parse_positive)]
+    pub raster_radius: f64,
+
+    /// Colour ramp of the raster heatmap: `heat`, `viridis`, `greys` or hex colours
+    /// like `#000000,#ff0000,#ffff00`
+    #[arg(long, default_value = "heat", value_parser = ColorRamp::parse)]
+    pub raster_ramp: ColorRamp,
+
+    /// Scale raster heatmap densities logarithmically
+    #[arg(long)]
+    pub raster_log: bool,
+
+    /// Highest zoom level of the raster heatmap tiles
+    #[arg(long, default_value_t = 14, value_parser = clap::value_parser!(u8).range(0..=20))]
+    pub raster_max_zoom: u8,
+
+    /// Pixel size of the heatmap GeoTIFF in meters
+    #[arg(long, default_value_t = 10.0, value_parser = parse_positive)]
+    pub raster_pixel_m: f64,
+
+    /// Also write the fog, everything outside of the 100m buffers, as its own layer
+    #[arg(long)]
+    pub fog: bool,
+
+    /// Area covered by fog as `min_lon,min_lat,max_lon,max_lat`, by default the world
+    #[arg(long, value_parser = parse_bbox)]
+    pub fog_extent: Option<Rect>,
+
+    /// Cut the fog next to explored areas into tiles of at most this many meters
+    #[arg(long, default_value_t = 10_000.0, value_parser = parse_positive)]
+    pub fog_tile_size: f64,
+
+    /// Snap activities onto the streets and paths of this `.osm.pbf` extract
+    #[arg(long, value_name = "OSM_PBF")]
+    pub map_match: Option<PathBuf>,
+
+    /// When map matching, only consider streets within this many meters of a point
+    #[arg(long, default_value_t = 50.0, value_parser = parse_positive)]
+    pub match_radius: f64,
+
+    /// When map matching, the typical GPS error in meters
+    #[arg(long, default_value_t = 10.0, value_parser = parse_positive)]
+    pub match_gps_sigma: f64,
+
+    /// When map matching, how many meters a route typically differs from the straight
+    /// line between two points
+    #[arg(long, default_value_t = 30.0, value_parser = parse_positive)]
+    pub match_beta: f64,
+
+    /// When map matching, the number of nearest streets considered per point
+    #[arg(long, default_value_t = 8)]
+    pub match_candidates: usize,
+
+    /// Scale the buffer radius of an activity type, e.g. `drive=0.2` or `flight=0`
+    #[arg(long, value_parser = parse_radius_factor)]
+    pub radius_factor: Vec<(ActivityKind, f64)>,
+
+    /// Work out the ground every activity explored first, for a table in
+    /// `data/new_ground.csv` and a layer coloured by date
+    #[arg(long)]
+    pub new_ground: bool,
+
+    /// Summarize the run in `data/report.html` and `data/report.md`
+    #[arg(long)]
+    pub report: bool,
+
+    /// GeoJSON, FlatGeobuf or `.osm.pbf` file with admin boundaries to list the most
+    /// explored regions in the report
+    #[arg(long, requires = "report")]
+    pub report_regions: Option<PathBuf>,
+
+    /// Property holding the name of a region of `--report-regions`
+    #[arg(long, default_value = "name")]
+    pub report_region_name: String,
+}
+
+#[derive(Args)]
+pub struct StreetCoverageArgs {
+    /// `.osm.pbf` extract with the streets to check
+    #[arg(long, value_name = "OSM_PBF")]
+    pub osm: PathBuf,
+
+    /// Buffer layer written by a previous run, `out_buffer_100.fgb` in the output directory
+    /// by default
+    #[arg(long)]
+    pub coverage: Option<PathBuf>,
+
+    /// GeoJSON, FlatGeobuf or `.osm.pbf` file with admin boundaries to report coverage per area
+    #[arg(long)]
+    pub areas: Option<PathBuf>,
+
+    /// Property holding the name of an area
+    #[arg(long, default_value = "name")]
+    pub area_name: String,
+
+    /// Highway types not to count, e.g. roads one can't walk along
+    #[arg(
+        long,
+        value_delimiter = ',',
+        default_value = "motorway,motorway_link,trunk,trunk_link"
+    )]
+    pub exclude_highway: Vec<String>,
+}
+
+#[derive(Args)]
+pub struct StatsArgs {
+    /// GeoJSON, FlatGeobuf or `.osm.pbf` file with the admin boundaries
+    #[arg(long)]
+    pub regions: PathBuf,
+
+    /// Property holding the name of a region
+    #[arg(long, default_value = "name")]
+    pub region_name: String,
+
+    /// Only use regions of these OSM admin levels, e.g. `4,6,8`
+    #[arg(long, value_delimiter = ',')]
+    pub admin_level: Vec<String>,
+
+    /// Buffer layer written by a previous run, `out_buffer_100.fgb` in the output directory
+    /// by default
+    #[arg(long)]
+    pub coverage: Option<PathBuf>,
+}
+
+#[derive(Args)]
+pub struct RenderArgs {
+    /// Buffer layer written by a previous run, the fog covers everything outside of it,
+    /// `out_buffer_100.fgb` in the output directory by default
+    #[arg(long)]
+    pub coverage: Option<PathBuf>,
+
+    /// Area to show as `min_lon,min_lat,max_lon,max_lat`, by default all of the coverage
+    #[arg(long, value_parser = parse_bbox)]
+    pub bbox: Option<Rect>,
+
+    /// `a0` to `a5`, `letter`, `tabloid`, or `WIDTHxHEIGHT` in millimeters
+    #[arg(long, default_value = "a3", value_parser = PaperSize::parse)]
+    pub paper: PaperSize,
+
+    /// Turn the paper sideways
+    #[arg(long)]
+    pub landscape: bool,
+
+    /// Resolution of the poster
+    #[arg(long, default_value_t = 300, value_parser = clap::value_parser!(u32).range(1..=1200))]
+    pub dpi: u32,
+
+    /// Colour of the explored area, `#rrggbb` or `#rrggbbaa`
+    #[arg(long, default_value = "#f4f1ea", value_parser = parse_hex_color)]
+    pub background: [u8; 4],
+
+    /// Colour of the fog, `#rrggbb` or `#rrggbbaa`
+    #[arg(long, default_value = "#22252d", value_parser = parse_hex_color)]
+    pub fog_color: [u8; 4],
+
+    /// Also draw the heatmap written by a previous run, `heatmap.fgb` in the output
+    /// directory unless given
+    #[arg(long, value_name = "FGB", num_args = 0..=1)]
+    pub heatmap: Option<Option<String>>,
+
+    /// Radius of the heatmap kernel in millimeters
+    #[arg(long, default_value_t = 1.0, value_parser = parse_positive)]
+    pub heatmap_radius: f64,
+
+    /// Colour ramp of the heatmap: `heat`, `viridis`, `greys` or hex colours
+    #[arg(long, default_value = "heat", value_parser = ColorRamp::parse)]
+    pub heatmap_ramp: ColorRamp,
+
+    /// Scale heatmap densities logarithmically
+    #[arg(long)]
+    pub heatmap_log: bool,
+
+    /// `.svg` or `.png` file to write, `poster.svg` in the output directory by default
+    #[arg(long, short)]
+    pub output: Option<PathBuf>,
+}
+
+fn parse_radius_factor(value: &str) -> Result<(ActivityKind, f64), String> {
+    let (kind, factor) = value
+        .split_once('=')
+        .ok_or_else(|| format!("expected TYPE=FACTOR, got '{}'", value))?;
+    let kind = ActivityKind::from_str(kind, true)?;
+    let factor: f64 = factor
+        .parse()
+        .map_err(|e| format!("invalid factor '{}': {}", factor, e))?;
+    if factor < 0.0 {
+        return Err(format!("factor must not be negative, got {}", factor));
+    }
+    Ok((kind, factor))
+}
+
+fn parse_positive(value: &str) -> Result<f64, String> {
+    let number: f64 = value
+        .parse()
+        .map_err(|e| format!("invalid number '{}': {}", value, e))?;
+    if number <= 0.0 {
+        return Err(format!("must be positive, got {}", number));
+    }
+    Ok(number)
+}
+
+/// `min_lon,min_lat,max_lon,max_lat` in WGS84 degrees
+fn parse_bbox(value: &str) -> Result<Rect, String> {
+    let numbers = value
+        .split(',')
+        .map(|number| {
+            number
+                .trim()
+                .parse::<f64>()
+                .map_err(|e| format!("invalid number '{}': {}", number, e))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let [min_lon, min_lat, max_lon, max_lat] = numbers[..] else {
+        return Err(format!(
+            "expected min_lon,min_lat,max_lon,max_lat, got '{}'",
+            value
+        ));
+    };
+    if min_lon >= max_lon || min_lat >= max_lat {
+        return Err(format!(
+            "the minimum must be below the maximum, got '{}'",
+            value
+        ));
+    }
+    if min_lon < -180.0 || max_lon > 180.0 || min_lat < -85.0 || max_lat > 85.0 {
+        return Err(format!("coordinates out of range, got '{}'", value));
+    }
+    Ok(Rect::new(
+        Coord {
+            x: min_lon,
+            y: min_lat,
+        },
+        Coord {
+            x: max_lon,
+            y: max_lat,
+        },
+    ))
+}
+
+#[derive(Args)]
+pub struct ServerArgs {
+    /// Address to listen on
+    #[arg(long, default_value = "127.0.0.1")]
+    pub host: String,
+
+    #[arg(long, default_value_t = 8080)]
+    pub port: u16,
+
+    /// Number of requests handled in parallel
+    #[arg(long, default_value_t = 8)]
+    pub threads: usize,
+}
+
+#[derive(Args)]
+pub struct ServeArgs {
+    #[command(flatten)]
+    pub server: ServerArgs,
+
+    /// Build the layers first, with the options given before `serve`
+    #[arg(long)]
+    pub build: bool,
+}
+
+#[derive(Args)]
+pub struct WatchArgs {
+    /// Seconds between checks for changed files
+    #[arg(long, default_value_t = 10, value_parser = clap::value_parser!(u64).range(1..))]
+    pub interval: u64,
+
+    /// Also serve the map, which reloads the layers after every rebuild
+    #[arg(long)]
+    pub serve: bool,
+
+    #[command(flatten)]
+    pub server: ServerArgs,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builds_with_the_options_before_serve() {
+        let cli =
+            Cli::try_parse_from(["point-cloud", "--period", "year", "serve", "--build"]).unwrap();
+
+        assert!(matches!(
+            cli.command,
+            Some(Command::Serve(ServeArgs { build: true, .. }))
+        ));
+        assert!(matches!(cli.build.period, Some(Period::Year)));
+    }
+
+    #[test]
+    fn rebuilds_with_the_options_before_watch() {
+        let cli =
+            Cli::try_parse_from(["point-cloud", "--fog", "--tiles", "14", "watch", "--serve"])
+                .unwrap();
+
+        assert!(matches!(
+            cli.command,
+            Some(Command::Watch(WatchArgs { serve: true, .. }))
+        ));
+        assert!(cli.build.fog);
+        assert_eq!(cli.build.tiles, [14]);
+    }
+}
@@ -0,0 +1,82 @@
+use geo::{BoundingRect, Contains, Geometry, Point, Polygon, Rect};
+use rstar::primitives::{GeomWithData, Rectangle};
+use rstar::{AABB, RTree};
+use std::path::Path;
+
+use crate::crs::Projector;
+use crate::io::read_flatgeobuf_in_meters;
+
+type IndexedRect = GeomWithData<Rectangle<[f64; 2]>, usize>;
+
+/// Buffered coverage polygons, indexed by their bounding boxes.
+pub struct CoverageIndex {
+    polygons: Vec<Polygon>,
+    tree: RTree<IndexedRect>,
+}
+
+impl CoverageIndex {
+    pub fn new(polygons: Vec<Polygon>) -> Self {
+        let rects = polygons
+            .iter()
+            .enumerate()
+            .filter_map(|(i, polygon)| {
+                let rect = polygon.bounding_rect()?;
+                let corners = Rectangle::from_corners(rect.min().into(), rect.max().into());
+                Some(GeomWithData::new(corners, i))
+            })
+            .collect();
+        CoverageIndex {
+            polygons,
+            tree: RTree::bulk_load(rects),
+        }
+    }
+
+    /// Reads the polygons of a buffer layer, e.g. `data/out_buffer_100.fgb`.
+    pub fn from_flatgeobuf(
+        path: &Path,
+        projector: Projector,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let polygons = read_flatgeobuf_in_meters(path, projector)?
+            .into_iter()
+            .flat_map(|feature| match feature.geometry {
+                Geometry::Polygon(polygon) => vec![polygon],
+                Geometry::MultiPolygon(multi_polygon) => multi_polygon.0,
+                _ => Vec::new(),
+            })
+            .collect();
+        Ok(Self::new(polygons))
+    }
+
+    /// Polygons whose bounding box intersects `rect`
+    pub fn candidates(&self, rect: &Rect) -> impl Iterator<Item = &Polygon> {
+        let envelope = AABB::from_corners(rect.min().into(), rect.max().into());
+        self.tree
+            .locate_in_envelope_intersecting(&envelope)
+            .map(|entry| &self.polygons[entry.data])
+    }
+
+    /// Bounding box of all polygons, `None` without any
+    pub fn bounds(&self) -> Option<Rect> {
+        if self.tree.size() == 0 {
+            return None;
+        }
+        let envelope = self.tree.root().envelope();
+        Some(Rect::new(envelope.lower(), envelope.upper()))
+    }
+
+    pub fn polygons(&self) -> &[Polygon] {
+        &self.polygons
+    }
+
+    /// Index of the polygon containing `point`, if any
+    pub fn polygon_at(&self, point: &Point) -> Option<usize> {
+        self.tree
+            .locate_all_at_point(&[point.x(), point.y()])
+            .map(|entry| entry.data)
+            .find(|&i| self.polygons[i].contains(point))
+    }
+
+    pub fn covers(&self, point: &Point) -> bool {
+        self.polygon_at(point).is_some()
+    }
+}
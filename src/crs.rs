@@ -0,0 +1,97 @@
+use clap::ValueEnum;
+use geo::{Coord, MapCoords};
+use proj::Proj;
+
+use crate::{EPSG_METERS, EPSG_WGS84};
+
+thread_local! {
+    // PROJ instances can't be shared between threads, so every rayon worker creates its
+    // own, or keeps the error if PROJ lacks the EPSG database
+    static PROJ_METER: Result<Proj, String> = Proj::new_known_crs(
+        &format!("EPSG:{}", EPSG_WGS84),
+        &format!("EPSG:{}", EPSG_METERS),
+        None,
+    )
+    .map_err(|e| e.to_string());
+}
+
+/// Transforms coordinates between WGS84 and EPSG_METERS with PROJ, returning errors
+/// instead of panicking. PROJ is set up per thread on first use.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Projector;
+
+impl Projector {
+    /// WGS84 degrees to EPSG_METERS
+    pub fn to_meters<C: proj::Coord<f64>>(self, coord: C) -> Result<C, String> {
+        PROJ_METER.with(|proj| {
+            let proj = proj.as_ref().map_err(Clone::clone)?;
+            proj.convert(coord).map_err(|e| e.to_string())
+        })
+    }
+
+    /// EPSG_METERS to WGS84 degrees
+    pub fn to_wgs84<C: proj::Coord<f64>>(self, coord: C) -> Result<C, String> {
+        PROJ_METER.with(|proj| {
+            let proj = proj.as_ref().map_err(Clone::clone)?;
+            proj.project(coord, true).map_err(|e| e.to_string())
+        })
+    }
+}
+
+/// Coordinate reference systems outputs can be written in. Everything is computed in
+/// EPSG_METERS and only transformed on write.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum OutputCrs {
+    /// Longitude and latitude in degrees, what most tools expect
+    #[default]
+    #[value(alias = "4326")]
+    Wgs84,
+    /// Web Mercator in meters, the working projection
+    #[value(alias = "3857")]
+    WebMercator,
+}
+
+/// WKT of EPSG_METERS, for the GeoPackage and the `.prj` of shapefiles
+const EPSG_METERS_WKT: &str = r#"PROJCS["WGS 84 / Pseudo-Mercator",GEOGCS["WGS 84",DATUM["WGS_1984",SPHEROID["WGS 84",6378137,298.257223563,AUTHORITY["EPSG","7030"]],AUTHORITY["EPSG","6326"]],PRIMEM["Greenwich",0,AUTHORITY["EPSG","8901"]],UNIT["degree",0.0174532925199433,AUTHORITY["EPSG","9122"]],AUTHORITY["EPSG","4326"]],PROJECTION["Mercator_1SP"],PARAMETER["central_meridian",0],PARAMETER["scale_factor",1],PARAMETER["false_easting",0],PARAMETER["false_northing",0],UNIT["metre",1,AUTHORITY["EPSG","9001"]],AXIS["Easting",EAST],AXIS["Northing",NORTH],EXTENSION["PROJ4","+proj=merc +a=6378137 +b=6378137 +lat_ts=0 +lon_0=0 +x_0=0 +y_0=0 +k=1 +units=m +nadgrids=@null +wktext +no_defs"],AUTHORITY["EPSG","3857"]]"#;
+
+const EPSG_WGS84_WKT: &str = r#"GEOGCS["WGS 84",DATUM["WGS_1984",SPHEROID["WGS 84",6378137,298.257223563,AUTHORITY["EPSG","7030"]],AUTHORITY["EPSG","6326"]],PRIMEM["Greenwich",0,AUTHORITY["EPSG","8901"]],UNIT["degree",0.0174532925199433,AUTHORITY["EPSG","9122"]],AUTHORITY["EPSG","4326"]]"#;
+
+impl OutputCrs {
+    pub fn epsg(self) -> i32 {
+        match self {
+            OutputCrs::Wgs84 => EPSG_WGS84,
+            OutputCrs::WebMercator => EPSG_METERS,
+        }
+    }
+
+    pub fn wkt(self) -> &'static str {
+        match self {
+            OutputCrs::Wgs84 => EPSG_WGS84_WKT,
+            OutputCrs::WebMercator => EPSG_METERS_WKT,
+        }
+    }
+
+    /// Transforms a geometry in EPSG_METERS into this CRS.
+    pub fn project<G>(self, geometry: &G, projector: Projector) -> Result<G, String>
+    where
+        G: MapCoords<f64, f64, Output = G>,
+    {
+        match self {
+            OutputCrs::Wgs84 => geometry.try_map_coords(|coord: Coord| projector.to_wgs84(coord)),
+            OutputCrs::WebMercator => Ok(geometry.map_coords(|coord| coord)),
+        }
+    }
+}
+
+/// Transforms a geometry read from a file in EPSG `epsg` into EPSG_METERS. Files
+/// without a CRS (0) are taken to be WGS84.
+pub fn to_meters<G>(geometry: G, epsg: i32, projector: Projector) -> Result<G, String>
+where
+    G: MapCoords<f64, f64, Output = G>,
+{
+    match epsg {
+        EPSG_METERS => Ok(geometry),
+        EPSG_WGS84 | 0 => geometry.try_map_coords(|coord: Coord| projector.to_meters(coord)),
+        other => Err(format!("unsupported CRS EPSG:{}", other)),
+    }
+}
@@ -0,0 +1,536 @@
+use clap::ValueEnum;
+use flatgeobuf::{ColumnType, FallibleStreamingIterator, FeatureProperties, FgbReader};
+use geo::{BoundingRect, Geometry};
+use geojson::{FeatureCollection, JsonObject, JsonValue};
+use geozero::{CoordDimensions, ToGeo, ToWkb};
+use rusqlite::{Connection, params};
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::path::Path;
+
+use crate::GPKG_FILE;
+use crate::crs::{OutputCrs, Projector, to_meters};
+use crate::io::Output;
+
+/// Formats layers can be exported to, next to the FlatGeobuf the PMTiles are built from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum OutputFormat {
+    /// `data/<layer>.geojson`, always in WGS84
+    Geojson,
+    /// A table per layer in `data/layers.gpkg`
+    Gpkg,
+    /// `data/<layer>.shp` with `.shx`, `.dbf` and `.prj`
+    #[value(alias = "shp")]
+    Shapefile,
+}
+
+/// Exports the layers matching `layer`, all if `None`, to `format`.
+#[derive(Clone, Debug)]
+pub struct ExportRule {
+    /// File stem of the layer, e.g. `out_buffer_100`, or a prefix ending in `*`
+    pub layer: Option<String>,
+    pub format: OutputFormat,
+    /// CRS of the exported layer, that of the FlatGeobuf layers if `None`
+    pub crs: Option<OutputCrs>,
+}
+
+impl ExportRule {
+    /// `FORMAT` for every layer or `LAYER=FORMAT`, optionally with a CRS, e.g.
+    /// `heatmap_*=gpkg:web-mercator`
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let (layer, format) = match value.split_once('=') {
+            Some((layer, format)) => (Some(layer.to_string()), format),
+            None => (None, value),
+        };
+        let (format, crs) = match format.split_once(':') {
+            Some((format, crs)) => (format, Some(OutputCrs::from_str(crs, true)?)),
+            None => (format, None),
+        };
+        let format = OutputFormat::from_str(format, true)?;
+        if format == OutputFormat::Geojson && crs.is_some_and(|crs| crs != OutputCrs::Wgs84) {
+            return Err("GeoJSON is always WGS84".to_string());
+        }
+        Ok(ExportRule { layer, format, crs })
+    }
+
+    fn matches(&self, layer: &str) -> bool {
+        match self.layer.as_deref() {
+            None => true,
+            Some(pattern) => match pattern.strip_suffix('*') {
+                Some(prefix) => layer.starts_with(prefix),
+                None => layer == pattern,
+            },
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum ColumnKind {
+    Integer,
+    Real,
+    Boolean,
+    Text,
+}
+
+impl From<ColumnType> for ColumnKind {
+    fn from(column_type: ColumnType) -> Self {
+        match column_type {
+            ColumnType::Byte
+            | ColumnType::UByte
+            | ColumnType::Short
+            | ColumnType::UShort
+            | ColumnType::Int
+            | ColumnType::UInt
+            | ColumnType::Long
+            | ColumnType::ULong => ColumnKind::Integer,
+            ColumnType::Float | ColumnType::Double => ColumnKind::Real,
+            ColumnType::Bool => ColumnKind::Boolean,
+            _ => ColumnKind::Text,
+        }
+    }
+}
+
+#[derive(Clone)]
+enum Value {
+    Null,
+    Integer(i64),
+    Real(f64),
+    Boolean(bool),
+    Text(String),
+}
+
+impl Value {
+    /// Values that don't parse as their column's kind are missing, so typed fields only
+    /// ever get values of their type.
+    fn parse(kind: ColumnKind, value: &str) -> Self {
+        match kind {
+            ColumnKind::Integer => value.parse().map_or(Value::Null, Value::Integer),
+            ColumnKind::Real => value.parse().map_or(Value::Null, Value::Real),
+            ColumnKind::Boolean => value.parse().map_or(Value::Null, Value::Boolean),
+            ColumnKind::Text => Value::Text(value.to_string()),
+        }
+    }
+}
+
+/// A written layer read back with typed attributes.
+struct Layer {
+    name: String,
+    crs: OutputCrs,
+    columns: Vec<(String, ColumnKind)>,
+    features: Vec<(Geometry, Vec<Value>)>,
+}
+
+impl Layer {
+    /// The same layer with its geometries transformed from EPSG_METERS into `crs`
+    fn to_crs(&self, crs: OutputCrs, projector: Projector) -> Result<Layer, String> {
+        Ok(Layer {
+            name: self.name.clone(),
+            crs,
+            columns: self.columns.clone(),
+            features: self
+                .features
+                .iter()
+                .map(|(geometry, values)| Ok((crs.project(geometry, projector)?, values.clone())))
+                .collect::<Result<_, String>>()?,
+        })
+    }
+}
+
+/// Reads a layer back into EPSG_METERS, whatever CRS it was written in.
+fn read_layer(fgb_path: &Path, projector: Projector) -> Result<Layer, Box<dyn std::error::Error>> {
+    let mut reader = BufReader::new(File::open(fgb_path)?);
+    let mut fgb = FgbReader::open(&mut reader)?.select_all()?;
+    let epsg = fgb.header().crs().map(|crs| crs.code()).unwrap_or(0);
+    let columns: Vec<(String, ColumnKind)> = fgb
+        .header()
+        .columns()
+        .into_iter()
+        .flat_map(|columns| columns.iter())
+        .map(|column| (column.name().to_string(), ColumnKind::from(column.type_())))
+        .collect();
+
+    let mut features = Vec::new();
+    while let Some(feature) = fgb.next()? {
+        let properties = feature.properties()?;
+        let values = columns
+            .iter()
+            .map(|(name, kind)| match properties.get(name) {
+                Some(value) => Value::parse(*kind, value),
+                None => Value::Null,
+            })
+            .collect();
+        features.push((to_meters(feature.to_geo()?, epsg, projector)?, values));
+    }
+
+    let name = fgb_path
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .ok_or("invalid layer path")?
+        .to_string();
+    Ok(Layer {
+        name,
+        crs: OutputCrs::WebMercator,
+        columns,
+        features,
+    })
+}
+
+/// Writes the FlatGeobuf layer at `fgb_path` to every format `output` exports it to.
+pub fn export_layer(fgb_path: &str, output: &Output) -> Result<(), Box<dyn std::error::Error>> {
+    let path = Path::new(fgb_path);
+    let layer_name = path
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .ok_or("invalid layer path")?;
+    let mut formats: Vec<(OutputFormat, OutputCrs)> = output
+        .export
+        .iter()
+        .filter(|rule| rule.matches(layer_name))
+        .map(|rule| match rule.format {
+            OutputFormat::Geojson => (rule.format, OutputCrs::Wgs84),
+            _ => (rule.format, rule.crs.unwrap_or(output.crs)),
+        })
+        .collect();
+    // a layer is written once per format, in the CRS of the first matching rule
+    formats.sort_by_key(|&(format, _)| format);
+    formats.dedup_by_key(|&mut (format, _)| format);
+    if formats.is_empty() {
+        return Ok(());
+    }
+
+    let layer = read_layer(path, output.projector)?;
+    for (format, crs) in formats {
+        let projected = layer.to_crs(crs, output.projector)?;
+        let written = match format {
+            OutputFormat::Geojson => {
+                let output_path = path.with_extension("geojson");
+                write_geojson(&projected, &output_path)?;
+                output_path.display().to_string()
+            }
+            OutputFormat::Gpkg => {
+                let gpkg_path = output.path(GPKG_FILE);
+                write_gpkg(&projected, Path::new(&gpkg_path))?;
+                format!("{} ({})", gpkg_path, layer.name)
+            }
+            OutputFormat::Shapefile => {
+                let output_path = path.with_extension("shp");
+                write_shapefile(&projected, &output_path)?;
+                output_path.display().to_string()
+            }
+        };
+        progress!(output.progress, "✓ Exported {} to {}", layer.name, written);
+    }
+
+    Ok(())
+}
+
+fn write_geojson(layer: &Layer, output_path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let features = layer
+        .features
+        .iter()
+        .map(|(geometry, values)| {
+            let mut properties = JsonObject::new();
+            for ((name, _), value) in layer.columns.iter().zip(values) {
+                let value = match value {
+                    Value::Null => JsonValue::Null,
+                    Value::Integer(number) => JsonValue::from(*number),
+                    Value::Real(number) => JsonValue::from(*number),
+                    Value::Boolean(flag) => JsonValue::from(*flag),
+                    Value::Text(text) => JsonValue::from(text.as_str()),
+                };
+                properties.insert(name.clone(), value);
+            }
+            geojson::Feature {
+                geometry: Some(geojson::Geometry::from(geometry)),
+                properties: Some(properties),
+                ..Default::default()
+            }
+        })
+        .collect();
+
+    let collection = FeatureCollection {
+        bbox: None,
+        features,
+        foreign_members: None,
+    };
+    fs::write(output_path, collection.to_string())?;
+    Ok(())
+}
+
+/// Creates the tables every GeoPackage needs if they don't exist yet.
+fn init_gpkg(connection: &Connection) -> rusqlite::Result<()> {
+    connection.execute_batch(
+        "PRAGMA application_id = 1196444487;
+        PRAGMA user_version = 10300;
+        CREATE TABLE IF NOT EXISTS gpkg_spatial_ref_sys (
+            srs_name TEXT NOT NULL,
+            srs_id INTEGER PRIMARY KEY,
+            organization TEXT NOT NULL,
+            organization_coordsys_id INTEGER NOT NULL,
+            definition TEXT NOT NULL,
+            description TEXT
+        );
+        CREATE TABLE IF NOT EXISTS gpkg_contents (
+            table_name TEXT NOT NULL PRIMARY KEY,
+            data_type TEXT NOT NULL,
+            identifier TEXT UNIQUE,
+            description TEXT DEFAULT '',
+            last_change DATETIME NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%fZ','now')),
+            min_x DOUBLE,
+            min_y DOUBLE,
+            max_x DOUBLE,
+            max_y DOUBLE,
+            srs_id INTEGER REFERENCES gpkg_spatial_ref_sys(srs_id)
+        );
+        CREATE TABLE IF NOT EXISTS gpkg_geometry_columns (
+            table_name TEXT NOT NULL,
+            column_name TEXT NOT NULL,
+            geometry_type_name TEXT NOT NULL,
+            srs_id INTEGER NOT NULL REFERENCES gpkg_spatial_ref_sys(srs_id),
+            z TINYINT NOT NULL,
+            m TINYINT NOT NULL,
+            CONSTRAINT pk_geom_cols PRIMARY KEY (table_name, column_name),
+            CONSTRAINT fk_gc_tn FOREIGN KEY (table_name) REFERENCES gpkg_contents(table_name)
+        );",
+    )?;
+
+    let mut insert = connection.prepare(
+        "INSERT OR IGNORE INTO gpkg_spatial_ref_sys
+        (srs_name, srs_id, organization, organization_coordsys_id, definition, description)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+    )?;
+    insert.execute(params![
+        "Undefined cartesian SRS",
+        -1,
+        "NONE",
+        -1,
+        "undefined",
+        "undefined cartesian coordinate reference system"
+    ])?;
+    insert.execute(params![
+        "Undefined geographic SRS",
+        0,
+        "NONE",
+        0,
+        "undefined",
+        "undefined geographic coordinate reference system"
+    ])?;
+    insert.execute(params![
+        "WGS 84 geodetic",
+        OutputCrs::Wgs84.epsg(),
+        "EPSG",
+        OutputCrs::Wgs84.epsg(),
+        OutputCrs::Wgs84.wkt(),
+        "longitude/latitude coordinates in decimal degrees on the WGS 84 spheroid"
+    ])?;
+    insert.execute(params![
+        "WGS 84 / Pseudo-Mercator",
+        OutputCrs::WebMercator.epsg(),
+        "EPSG",
+        OutputCrs::WebMercator.epsg(),
+        OutputCrs::WebMercator.wkt(),
+        "web mercator"
+    ])?;
+    Ok(())
+}
+
+/// Name of the geometry type of all features, `GEOMETRY` if they differ
+fn gpkg_geometry_type(features: &[(Geometry, Vec<Value>)]) -> &'static str {
+    let name = |geometry: &Geometry| match geometry {
+        Geometry::Point(_) => "POINT",
+        Geometry::LineString(_) | Geometry::Line(_) => "LINESTRING",
+        Geometry::Polygon(_) | Geometry::Rect(_) | Geometry::Triangle(_) => "POLYGON",
+        Geometry::MultiPoint(_) => "MULTIPOINT",
+        Geometry::MultiLineString(_) => "MULTILINESTRING",
+        Geometry::MultiPolygon(_) => "MULTIPOLYGON",
+        Geometry::GeometryCollection(_) => "GEOMETRYCOLLECTION",
+    };
+    let mut names = features.iter().map(|(geometry, _)| name(geometry));
+    match names.next() {
+        Some(first) if names.all(|other| other == first) => first,
+        _ => "GEOMETRY",
+    }
+}
+
+/// Replaces the table of the layer in the GeoPackage at `gpkg_path`, keeping the others.
+fn write_gpkg(layer: &Layer, gpkg_path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    if let Some(parent) = gpkg_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut connection = Connection::open(gpkg_path)?;
+    init_gpkg(&connection)?;
+
+    let transaction = connection.transaction()?;
+    let table = format!("\"{}\"", layer.name.replace('"', "\"\""));
+    transaction.execute(&format!("DROP TABLE IF EXISTS {}", table), [])?;
+    transaction.execute(
+        "DELETE FROM gpkg_geometry_columns WHERE table_name = ?1",
+        [&layer.name],
+    )?;
+    transaction.execute(
+        "DELETE FROM gpkg_contents WHERE table_name = ?1",
+        [&layer.name],
+    )?;
+
+    let geometry_type = gpkg_geometry_type(&layer.features);
+    let mut definitions = vec![
+        "fid INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL".to_string(),
+        format!("geom {}", geometry_type),
+    ];
+    for (name, kind) in &layer.columns {
+        let sql_type = match kind {
+            ColumnKind::Integer => "INTEGER",
+            ColumnKind::Real => "DOUBLE",
+            ColumnKind::Boolean => "BOOLEAN",
+            ColumnKind::Text => "TEXT",
+        };
+        definitions.push(format!("\"{}\" {}", name.replace('"', "\"\""), sql_type));
+    }
+    transaction.execute(
+        &format!("CREATE TABLE {} ({})", table, definitions.join(", ")),
+        [],
+    )?;
+
+    let placeholders: Vec<String> = (1..=layer.columns.len() + 1)
+        .map(|i| format!("?{}", i))
+        .collect();
+    let column_names: Vec<String> = std::iter::once("geom".to_string())
+        .chain(
+            layer
+                .columns
+                .iter()
+                .map(|(name, _)| format!("\"{}\"", name.replace('"', "\"\""))),
+        )
+        .collect();
+    {
+        let mut insert = transaction.prepare(&format!(
+            "INSERT INTO {} ({}) VALUES ({})",
+            table,
+            column_names.join(", "),
+            placeholders.join(", ")
+        ))?;
+        for (geometry, values) in &layer.features {
+            let envelope = geometry
+                .bounding_rect()
+                .map(|rect| vec![rect.min().x, rect.max().x, rect.min().y, rect.max().y])
+                .unwrap_or_default();
+            let mut row = vec![rusqlite::types::Value::Blob(geometry.to_gpkg_wkb(
+                CoordDimensions::xy(),
+                Some(layer.crs.epsg()),
+                envelope,
+            )?)];
+            row.extend(values.iter().map(|value| match value {
+                Value::Null => rusqlite::types::Value::Null,
+                Value::Integer(number) => rusqlite::types::Value::Integer(*number),
+                Value::Real(number) => rusqlite::types::Value::Real(*number),
+                Value::Boolean(flag) => rusqlite::types::Value::Integer(*flag as i64),
+                Value::Text(text) => rusqlite::types::Value::Text(text.clone()),
+            }));
+            insert.execute(rusqlite::params_from_iter(row))?;
+        }
+    }
+
+    let bounds = layer
+        .features
+        .iter()
+        .filter_map(|(geometry, _)| geometry.bounding_rect())
+        .reduce(|a, b| {
+            geo::Rect::new(
+                geo::coord! { x: a.min().x.min(b.min().x), y: a.min().y.min(b.min().y) },
+                geo::coord! { x: a.max().x.max(b.max().x), y: a.max().y.max(b.max().y) },
+            )
+        });
+    transaction.execute(
+        "INSERT INTO gpkg_contents (table_name, data_type, identifier, min_x, min_y, max_x, max_y, srs_id)
+        VALUES (?1, 'features', ?1, ?2, ?3, ?4, ?5, ?6)",
+        params![
+            layer.name,
+            bounds.map(|rect| rect.min().x),
+            bounds.map(|rect| rect.min().y),
+            bounds.map(|rect| rect.max().x),
+            bounds.map(|rect| rect.max().y),
+            layer.crs.epsg()
+        ],
+    )?;
+    transaction.execute(
+        "INSERT INTO gpkg_geometry_columns (table_name, column_name, geometry_type_name, srs_id, z, m)
+        VALUES (?1, 'geom', ?2, ?3, 0, 0)",
+        params![layer.name, geometry_type, layer.crs.epsg()],
+    )?;
+    transaction.commit()?;
+
+    Ok(())
+}
+
+/// dBase field names are at most 10 characters long. Names that are the same once
+/// truncated end in `_1`, `_2` and so on, e.g. `first_seen` and `first_seen_at`.
+fn dbase_field_names(columns: &[(String, ColumnKind)]) -> Vec<String> {
+    let mut names: Vec<String> = Vec::with_capacity(columns.len());
+    for (name, _) in columns {
+        let mut field_name: String = name.chars().take(10).collect();
+        let mut n = 1;
+        while names.contains(&field_name) {
+            let suffix = format!("_{}", n);
+            field_name = name.chars().take(10 - suffix.len()).collect::<String>() + &suffix;
+            n += 1;
+        }
+        names.push(field_name);
+    }
+    names
+}
+
+/// Writes the layer as a shapefile. All features must have the same kind of geometry,
+/// which holds for every layer written here.
+fn write_shapefile(layer: &Layer, output_path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let field_names = dbase_field_names(&layer.columns);
+    let mut table = dbase::TableWriterBuilder::new();
+    for ((_, kind), name) in layer.columns.iter().zip(&field_names) {
+        let field_name = dbase::FieldName::try_from(name.as_str())?;
+        table = match kind {
+            ColumnKind::Integer => table.add_numeric_field(field_name, 20, 0),
+            ColumnKind::Real => table.add_numeric_field(field_name, 24, 8),
+            ColumnKind::Boolean => table.add_logical_field(field_name),
+            ColumnKind::Text => table.add_character_field(field_name, 254),
+        };
+    }
+
+    let mut writer = shapefile::Writer::from_path(output_path, table)?;
+    for (geometry, values) in &layer.features {
+        let mut record = dbase::Record::default();
+        for (((_, kind), name), value) in layer.columns.iter().zip(&field_names).zip(values) {
+            let value = match value {
+                Value::Null => match kind {
+                    ColumnKind::Integer | ColumnKind::Real => dbase::FieldValue::Numeric(None),
+                    ColumnKind::Boolean => dbase::FieldValue::Logical(None),
+                    ColumnKind::Text => dbase::FieldValue::Character(None),
+                },
+                Value::Integer(number) => dbase::FieldValue::Numeric(Some(*number as f64)),
+                Value::Real(number) => dbase::FieldValue::Numeric(Some(*number)),
+                Value::Boolean(flag) => dbase::FieldValue::Logical(Some(*flag)),
+                // longer texts don't fit into a character field
+                Value::Text(text) => dbase::FieldValue::Character(Some(
+                    text.char_indices()
+                        .take_while(|(i, c)| i + c.len_utf8() <= 254)
+                        .map(|(_, c)| c)
+                        .collect(),
+                )),
+            };
+            record.insert(name.clone(), value);
+        }
+
+        match shapefile::Shape::try_from(geometry.clone())? {
+            shapefile::Shape::Point(point) => writer.write_shape_and_record(&point, &record)?,
+            shapefile::Shape::Polyline(line) => writer.write_shape_and_record(&line, &record)?,
+            shapefile::Shape::Polygon(polygon) => {
+                writer.write_shape_and_record(&polygon, &record)?
+            }
+            shapefile::Shape::Multipoint(points) => {
+                writer.write_shape_and_record(&points, &record)?
+            }
+            _ => return Err(format!("unsupported geometry in {}", layer.name).into()),
+        }
+    }
+
+    fs::write(output_path.with_extension("prj"), layer.crs.wkt())?;
+    Ok(())
+}
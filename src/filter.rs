@@ -0,0 +1,47 @@
+use geo::{Distance, Haversine};
+
+use crate::parsers::Activity;
+
+/// Drops points that were only passed at more than `max_speed_kmh`, e.g. on a train or plane.
+/// A point is dropped if a segment to a neighbouring point is too fast and none is slow
+/// enough, so where a fast ride started or ended within the same activity still counts.
+/// Expects WGS84 coordinates. Returns the number of removed points.
+pub fn exclude_fast_segments(activity: &mut Activity, max_speed_kmh: f64) -> usize {
+    let points = &activity.points;
+
+    // only points with a timestamp can tell us a speed
+    let timed: Vec<usize> = (0..points.len())
+        .filter(|&i| points[i].time.is_some())
+        .collect();
+
+    // for every segment between consecutive timed points: Some(is_fast), None if it can't be timed
+    let segment_is_fast: Vec<Option<bool>> = timed
+        .windows(2)
+        .map(|pair| {
+            let (from, to) = (&points[pair[0]], &points[pair[1]]);
+            let seconds = (to.time? - from.time?).num_milliseconds() as f64 / 1000.0;
+            if seconds <= 0.0 {
+                return None;
+            }
+            let meters = Haversine.distance(from.point, to.point);
+            Some(meters / seconds * 3.6 > max_speed_kmh)
+        })
+        .collect();
+
+    let mut keep = vec![true; points.len()];
+    for (position, &index) in timed.iter().enumerate() {
+        let before = position
+            .checked_sub(1)
+            .and_then(|segment| segment_is_fast[segment]);
+        let after = segment_is_fast.get(position).copied().flatten();
+
+        let any_fast = before == Some(true) || after == Some(true);
+        let any_slow = before == Some(false) || after == Some(false);
+        keep[index] = any_slow || !any_fast;
+    }
+
+    let before_count = activity.points.len();
+    let mut keep = keep.into_iter();
+    activity.points.retain(|_| keep.next().unwrap_or(true));
+    before_count - activity.points.len()
+}
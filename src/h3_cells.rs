@@ -0,0 +1,169 @@
+use chrono::{DateTime, Utc};
+use geo::{BoundingRect, LineString, Point, Polygon};
+use h3o::{CellIndex, LatLng, Resolution};
+use rayon::prelude::*;
+use serde::Serialize;
+use std::collections::HashMap;
+
+use crate::H3_FILE;
+use crate::crs::Projector;
+use crate::io::{Output, pmtiles_filename, suffixed_path, write_features_to_flatgeobuf};
+use crate::layers::{GeometryType, LayerEntry, LayerSummary, union_rect};
+use crate::measure::to_wgs84;
+use crate::parsers::Activity;
+use crate::pipeline::Error;
+
+/// What is known about one cell.
+#[derive(Clone, Copy, Default)]
+struct CellVisits {
+    points: u64,
+    /// Number of activities passing through the cell
+    visits: u64,
+    first_visit: Option<DateTime<Utc>>,
+    last_visit: Option<DateTime<Utc>>,
+}
+
+impl CellVisits {
+    fn merge(&mut self, other: &CellVisits) {
+        self.points += other.points;
+        self.visits += other.visits;
+        self.first_visit = earliest(self.first_visit, other.first_visit);
+        self.last_visit = self.last_visit.max(other.last_visit);
+    }
+}
+
+fn earliest(a: Option<DateTime<Utc>>, b: Option<DateTime<Utc>>) -> Option<DateTime<Utc>> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        _ => a.or(b),
+    }
+}
+
+#[derive(Serialize)]
+struct CellFeature {
+    geo: Polygon,
+    cell: String,
+    points: u64,
+    visits: u64,
+    first_visit: Option<String>,
+    last_visit: Option<String>,
+}
+
+/// Counts the points and activities per H3 cell at `resolution`.
+/// Expects EPSG_METERS coordinates.
+fn count_cells(activities: &[Activity], resolution: Resolution) -> HashMap<CellIndex, CellVisits> {
+    activities
+        .par_iter()
+        .map(|activity| {
+            let mut cells: HashMap<CellIndex, CellVisits> = HashMap::new();
+            for track_point in &activity.points {
+                let coord = to_wgs84(track_point.point.0);
+                let Ok(lat_lng) = LatLng::new(coord.y, coord.x) else {
+                    continue;
+                };
+                let cell = cells.entry(lat_lng.to_cell(resolution)).or_default();
+                cell.points += 1;
+                cell.visits = 1;
+                cell.first_visit = earliest(cell.first_visit, track_point.time);
+                cell.last_visit = cell.last_visit.max(track_point.time);
+            }
+            cells
+        })
+        .reduce(HashMap::new, |mut merged, cells| {
+            for (index, visits) in cells {
+                merged.entry(index).or_default().merge(&visits);
+            }
+            merged
+        })
+}
+
+/// The outline of a cell in EPSG_METERS.
+fn cell_polygon(cell: CellIndex, projector: Projector) -> Result<Polygon, String> {
+    let ring: LineString = cell
+        .boundary()
+        .iter()
+        .map(|vertex| projector.to_meters(Point::new(vertex.lng(), vertex.lat())))
+        .collect::<Result<_, _>>()?;
+    Ok(Polygon::new(ring, Vec::new()))
+}
+
+/// Writes the visited H3 cells of every resolution as polygons with their visit counts
+/// and first and last visit, e.g. to `data/h3_9.fgb`. `suffix` is appended to every
+/// output filename. Returns the `layers.json` entry of every resolution.
+pub fn build_h3_layers(
+    activities: &[Activity],
+    resolutions: &[u8],
+    suffix: &str,
+    output: &Output,
+) -> Result<Vec<LayerEntry>, Error> {
+    let mut entries = Vec::new();
+    for &resolution in resolutions {
+        progress!(
+            output.progress,
+            "\nCounting H3 cells at resolution {}...",
+            resolution
+        );
+        let h3_resolution = Resolution::try_from(resolution)
+            .map_err(|e| Error::Option(format!("H3 resolution {}: {}", resolution, e)))?;
+        let cells = count_cells(activities, h3_resolution);
+
+        let polygons: Vec<(CellIndex, CellVisits, Polygon)> = cells
+            .into_par_iter()
+            .map(|(cell, visits)| Ok((cell, visits, cell_polygon(cell, output.projector)?)))
+            .collect::<Result<_, String>>()
+            .map_err(Error::Projection)?;
+        let summary = LayerSummary::new(
+            "cells",
+            polygons.len(),
+            union_rect(
+                polygons
+                    .iter()
+                    .filter_map(|(_, _, polygon)| polygon.bounding_rect()),
+            ),
+            polygons
+                .iter()
+                .flat_map(|(_, visits, _)| [visits.first_visit, visits.last_visit])
+                .flatten(),
+        );
+
+        let mut features: Vec<CellFeature> = polygons
+            .into_par_iter()
+            .map(|(cell, visits, polygon)| {
+                Ok(CellFeature {
+                    geo: output.project(&polygon)?,
+                    cell: cell.to_string(),
+                    points: visits.points,
+                    visits: visits.visits,
+                    first_visit: visits.first_visit.map(|time| time.to_rfc3339()),
+                    last_visit: visits.last_visit.map(|time| time.to_rfc3339()),
+                })
+            })
+            .collect::<Result<_, String>>()
+            .map_err(Error::Projection)?;
+        // stable output regardless of hashing
+        features.sort_unstable_by(|a, b| a.cell.cmp(&b.cell));
+
+        let path = output.path(&suffixed_path(
+            H3_FILE,
+            &format!("{}_{}", suffix, resolution),
+        ));
+        progress!(output.progress, "Writing H3 cells to {}...", path);
+        write_features_to_flatgeobuf(&features, &path, output)
+            .map_err(|e| Error::write(&path, e))?;
+        progress!(
+            output.progress,
+            "✓ Successfully wrote {} H3 cells to {}",
+            features.len(),
+            path
+        );
+        entries.push(LayerEntry::new(
+            &format!("h3_{}", resolution),
+            &format!("H3 r{}", resolution),
+            &pmtiles_filename(&path).map_err(|e| Error::write(&path, e))?,
+            GeometryType::Polygons,
+            &summary,
+        ));
+    }
+
+    Ok(entries)
+}
@@ -1,20 +1,18 @@
+use chrono::{DateTime, Utc};
 use geo::Point;
 use rayon::prelude::*;
-use std::collections::HashSet;
+use std::collections::HashMap;
 use std::hash::{Hash, Hasher};
 
+use crate::parsers::TrackPoint;
+use crate::progress::Progress;
+
 pub fn round_to_10_meters(point: Point) -> Point {
     let x = (point.x() / 10.0).round() * 10.0;
     let y = (point.y() / 10.0).round() * 10.0;
     Point::new(x, y)
 }
 
-pub fn round_to_1_meter(point: Point) -> Point {
-    let x = point.x().round();
-    let y = point.y().round();
-    Point::new(x, y)
-}
-
 #[derive(Clone)]
 pub struct HashablePoint {
     x_rounded: i64,
@@ -54,12 +52,56 @@ impl From<HashablePoint> for Point {
     }
 }
 
-pub fn sanitize(points: Vec<Point>) -> (Vec<Point>, SanitizeStats) {
+/// A 10m cell and how often and when it was visited.
+pub struct VisitedCell {
+    pub point: Point,
+    /// Number of points in the cell
+    pub count: u64,
+    pub first_seen: Option<DateTime<Utc>>,
+    pub last_seen: Option<DateTime<Utc>>,
+    /// Distinct activities with points in the cell, sorted
+    pub activities: Vec<usize>,
+}
+
+#[derive(Default)]
+struct CellAggregate {
+    count: u64,
+    first_seen: Option<DateTime<Utc>>,
+    last_seen: Option<DateTime<Utc>>,
+    activities: Vec<usize>,
+}
+
+impl CellAggregate {
+    fn add(&mut self, track_point: &TrackPoint) {
+        self.count += 1;
+        if let Some(time) = track_point.time {
+            self.first_seen = Some(self.first_seen.map_or(time, |first| first.min(time)));
+            self.last_seen = Some(self.last_seen.map_or(time, |last| last.max(time)));
+        }
+        // points of an activity are mostly consecutive, duplicates are removed at the end
+        if self.activities.last() != Some(&track_point.activity) {
+            self.activities.push(track_point.activity);
+        }
+    }
+
+    fn merge(&mut self, mut other: CellAggregate) {
+        self.count += other.count;
+        self.first_seen = match (self.first_seen, other.first_seen) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+        self.last_seen = self.last_seen.max(other.last_seen);
+        self.activities.append(&mut other.activities);
+    }
+}
+
+/// Rounds points to 10m cells and aggregates the points of every cell.
+pub fn sanitize(points: &[TrackPoint], progress: Progress) -> (Vec<VisitedCell>, SanitizeStats) {
     let original_count = points.len();
 
     if original_count == 0 {
         return (
-            points,
+            Vec::new(),
             SanitizeStats {
                 final_count: 0,
                 removed_count: 0,
@@ -68,17 +110,47 @@ pub fn sanitize(points: Vec<Point>) -> (Vec<Point>, SanitizeStats) {
         );
     }
 
-    println!(
-        "Sanitizing {} points (rounding to 10m and deduplicating)...",
+    progress!(
+        progress,
+        "Sanitizing {} points (rounding to 10m and aggregating)...",
         original_count
     );
 
-    let unique_points: HashSet<HashablePoint> =
-        points.into_par_iter().map(HashablePoint::from).collect();
-
-    let sanitized_points: Vec<Point> = unique_points.into_iter().map(Point::from).collect();
+    let cells: HashMap<HashablePoint, CellAggregate> = points
+        .par_iter()
+        .fold(
+            HashMap::new,
+            |mut cells: HashMap<HashablePoint, CellAggregate>, track_point| {
+                cells
+                    .entry(HashablePoint::from(track_point.point))
+                    .or_default()
+                    .add(track_point);
+                cells
+            },
+        )
+        .reduce(HashMap::new, |mut merged, cells| {
+            for (cell, aggregate) in cells {
+                merged.entry(cell).or_default().merge(aggregate);
+            }
+            merged
+        });
+
+    let sanitized_cells: Vec<VisitedCell> = cells
+        .into_par_iter()
+        .map(|(cell, mut aggregate)| {
+            aggregate.activities.sort_unstable();
+            aggregate.activities.dedup();
+            VisitedCell {
+                point: Point::from(cell),
+                count: aggregate.count,
+                first_seen: aggregate.first_seen,
+                last_seen: aggregate.last_seen,
+                activities: aggregate.activities,
+            }
+        })
+        .collect();
 
-    let final_count = sanitized_points.len();
+    let final_count = sanitized_cells.len();
     let removed_count = original_count - final_count;
     let removal_percentage = (removed_count as f64 / original_count as f64) * 100.0;
 
@@ -88,29 +160,77 @@ pub fn sanitize(points: Vec<Point>) -> (Vec<Point>, SanitizeStats) {
         removal_percentage,
     };
 
-    (sanitized_points, stats)
+    (sanitized_cells, stats)
 }
 
-pub fn sanitize_to_1m_no_dedup(points: Vec<Point>) -> Vec<Point> {
-    let original_count = points.len();
-    
-    if original_count == 0 {
-        return points;
-    }
+/// A grid cell weighted by the number of points in it.
+pub struct WeightedCell {
+    pub point: Point,
+    pub count: u64,
+}
 
-    println!(
-        "Sanitizing {} points to 1m accuracy (no deduplication)...",
-        original_count
+/// Counts the points per square cell of `cell_size_m`, e.g. for a heatmap.
+/// Cells are represented by their center.
+pub fn aggregate_to_grid(
+    points: &[Point],
+    cell_size_m: f64,
+    progress: Progress,
+) -> Vec<WeightedCell> {
+    progress!(
+        progress,
+        "Aggregating {} points into {}m cells...",
+        points.len(),
+        cell_size_m
     );
 
-    let sanitized_points: Vec<Point> = points
-        .into_par_iter()
-        .map(round_to_1_meter)
-        .collect();
+    let counts: HashMap<(i64, i64), u64> = points
+        .par_iter()
+        .fold(
+            HashMap::new,
+            |mut counts: HashMap<(i64, i64), u64>, point| {
+                let cell = (
+                    (point.x() / cell_size_m).floor() as i64,
+                    (point.y() / cell_size_m).floor() as i64,
+                );
+                *counts.entry(cell).or_default() += 1;
+                counts
+            },
+        )
+        .reduce(HashMap::new, |mut merged, counts| {
+            for (cell, count) in counts {
+                *merged.entry(cell).or_default() += count;
+            }
+            merged
+        });
+
+    progress!(progress, "Final cell count: {}", counts.len());
+
+    counts
+        .into_iter()
+        .map(|((x, y), count)| WeightedCell {
+            point: Point::new(
+                (x as f64 + 0.5) * cell_size_m,
+                (y as f64 + 0.5) * cell_size_m,
+            ),
+            count,
+        })
+        .collect()
+}
 
-    println!("Final point count: {}", sanitized_points.len());
-    
-    sanitized_points
+/// Drops points of a track that fall into the same 10m cell as the point before them,
+/// e.g. while standing still. The first point of every run is kept as recorded.
+pub fn dedup_consecutive(points: &[TrackPoint]) -> Vec<TrackPoint> {
+    let mut previous: Option<HashablePoint> = None;
+    points
+        .iter()
+        .filter(|track_point| {
+            let cell = HashablePoint::from(track_point.point);
+            let is_new = previous.as_ref() != Some(&cell);
+            previous = Some(cell);
+            is_new
+        })
+        .copied()
+        .collect()
 }
 
 #[derive(Debug)]
@@ -121,11 +241,13 @@ pub struct SanitizeStats {
 }
 
 impl SanitizeStats {
-    pub fn print(&self) {
-        println!(
+    pub fn print(&self, progress: Progress) {
+        progress!(
+            progress,
             "Removed {} duplicate points ({:.2}% reduction)",
-            self.removed_count, self.removal_percentage
+            self.removed_count,
+            self.removal_percentage
         );
-        println!("Final point count: {}", self.final_count);
+        progress!(progress, "Final point count: {}", self.final_count);
     }
 }
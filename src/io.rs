@@ -1,142 +1,487 @@
 use fgbfile::FgbFile;
-use geo::{Geometry as GeoGeometry, Point, Polygon};
+use flatgeobuf::{FallibleStreamingIterator, FeatureProperties, FgbReader};
+use geo::{Geometry as GeoGeometry, LineString, MapCoords, MultiPolygon, Point, Polygon};
 use geos::Geometry;
+use geozero::ToGeo;
 use rayon::prelude::*;
 use serde::Serialize;
-use std::fs;
-use std::path::Path;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
 use std::process::Command;
 
-use crate::EPSG_METERS;
+use crate::buffer::buffer_stats;
+use crate::coverage::CoverageIndex;
+use crate::crs::{OutputCrs, Projector, to_meters};
+use crate::export::{ExportRule, export_layer};
+use crate::hashable_point::{VisitedCell, WeightedCell};
+use crate::measure::{area_m2, perimeter_m};
+use crate::progress::Progress;
+use crate::{OUT_DIR, UI_PUBLIC_DIR};
 
+/// Where and how outputs are written, set on the `PipelineBuilder` and passed to every
+/// writer.
+#[derive(Clone, Debug)]
+pub struct Output {
+    /// Directory of the FlatGeobuf layers, their exports, tables and reports
+    pub dir: PathBuf,
+    /// Directory the UI loads the PMTiles and manifests from
+    pub ui_dir: PathBuf,
+    /// CRS the FlatGeobuf layers are written in
+    pub crs: OutputCrs,
+    /// Formats the FlatGeobuf layers are exported to
+    pub export: Vec<ExportRule>,
+    pub progress: Progress,
+    pub projector: Projector,
+}
+
+impl Default for Output {
+    fn default() -> Self {
+        Output {
+            dir: PathBuf::from(OUT_DIR),
+            ui_dir: PathBuf::from(UI_PUBLIC_DIR),
+            crs: OutputCrs::default(),
+            export: Vec::new(),
+            progress: Progress::default(),
+            projector: Projector,
+        }
+    }
+}
+
+impl Output {
+    /// `file` in the output directory, e.g. `data/out.fgb`
+    pub fn path(&self, file: &str) -> String {
+        self.dir.join(file).display().to_string()
+    }
+
+    /// `file` in the UI directory, e.g. `ui/public/layers.json`
+    pub fn ui_path(&self, file: &str) -> String {
+        self.ui_dir.join(file).display().to_string()
+    }
+
+    /// Transforms a geometry in EPSG_METERS into the output CRS.
+    pub fn project<G>(&self, geometry: &G) -> Result<G, String>
+    where
+        G: MapCoords<f64, f64, Output = G>,
+    {
+        self.crs.project(geometry, self.projector)
+    }
+}
+
+/// A visited cell, timestamps as Unix seconds so the UI can style by recency.
 #[derive(Serialize)]
-pub struct PointGeometry {
+pub struct CellGeometry {
     pub geo: Point,
+    pub count: u64,
+    pub first_seen: Option<i64>,
+    pub last_seen: Option<i64>,
+    pub activities: u64,
 }
 
+#[derive(Serialize)]
+pub struct WeightedGeometry {
+    pub geo: Point,
+    pub count: u64,
+}
+
+/// A dissolved buffer polygon and what it was built from, timestamps as Unix seconds.
 #[derive(Serialize)]
 pub struct BufferedGeometry {
     pub geo: Polygon,
+    pub area_m2: f64,
+    pub perimeter_m: f64,
+    pub points: u64,
+    pub activities: u64,
+    pub first_seen: Option<i64>,
+    pub last_seen: Option<i64>,
 }
 
-pub fn write_to_flatgeobuf(
-    points: &Vec<Point>,
+#[derive(Serialize)]
+pub struct LineGeometry {
+    pub geo: LineString,
+}
+
+#[derive(Serialize)]
+pub struct PolygonGeometry {
+    pub geo: Polygon,
+}
+
+pub fn write_cells_to_flatgeobuf(
+    cells: &[VisitedCell],
     output_path: &str,
+    output: &Output,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let point_geometries: Vec<PointGeometry> = points
-        .into_par_iter()
-        .map(|p| PointGeometry { geo: p.to_owned() })
-        .collect();
-    FgbFile::create(output_path)
-        .unwrap()
-        .epsg(EPSG_METERS)
-        .write_features(&point_geometries)
-        .expect("file to be written");
+    let cell_geometries: Vec<CellGeometry> = cells
+        .par_iter()
+        .map(|cell| {
+            Ok(CellGeometry {
+                geo: output.project(&cell.point)?,
+                count: cell.count,
+                first_seen: cell.first_seen.map(|time| time.timestamp()),
+                last_seen: cell.last_seen.map(|time| time.timestamp()),
+                activities: cell.activities.len() as u64,
+            })
+        })
+        .collect::<Result<_, String>>()?;
+    FgbFile::create(output_path)?
+        .epsg(output.crs.epsg())
+        .write_features(&cell_geometries)?;
+    export_layer(output_path, output)?;
 
-    // Generate PMTiles file
-    generate_pmtiles_for_points(output_path)?;
+    // keep the totals of cells clustered at low zoom levels
+    generate_pmtiles_for_points(
+        output_path,
+        output,
+        None,
+        &[
+            "--accumulate-attribute=count:sum",
+            "--accumulate-attribute=first_seen:min",
+            "--accumulate-attribute=last_seen:max",
+            "--accumulate-attribute=activities:max",
+        ],
+    )?;
 
     Ok(())
 }
 
+/// One resolution of the heatmap and the zoom levels it is shown at.
+pub struct HeatmapLevel {
+    pub cell_size_m: f64,
+    pub min_zoom: u8,
+    pub max_zoom: u8,
+    pub cells: Vec<WeightedCell>,
+}
+
+/// Writes every level to its own FlatGeobuf, e.g. `data/heatmap_20m.fgb`, and joins
+/// them into a single PMTiles file named after `output_path`.
+pub fn write_heatmap_to_flatgeobuf(
+    levels: &[HeatmapLevel],
+    output_path: &str,
+    output: &Output,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut level_pmtiles = Vec::new();
+
+    for level in levels {
+        let level_path = suffixed_path(output_path, &format!("_{}m", level.cell_size_m));
+        let weighted_geometries: Vec<WeightedGeometry> = level
+            .cells
+            .par_iter()
+            .map(|cell| {
+                Ok(WeightedGeometry {
+                    geo: output.project(&cell.point)?,
+                    count: cell.count,
+                })
+            })
+            .collect::<Result<_, String>>()?;
+        FgbFile::create(&level_path)?
+            .epsg(output.crs.epsg())
+            .write_features(&weighted_geometries)?;
+        export_layer(&level_path, output)?;
+
+        level_pmtiles.push(generate_pmtiles_for_points(
+            &level_path,
+            output,
+            Some((level.min_zoom, level.max_zoom)),
+            &["--layer=heatmap", "--accumulate-attribute=count:sum"],
+        )?);
+    }
+
+    let pmtiles_path = output.ui_path(&pmtiles_filename(output_path)?);
+
+    progress!(
+        output.progress,
+        "Joining heatmap levels into {}...",
+        pmtiles_path
+    );
+
+    // the zoom ranges don't overlap, so every zoom level comes from exactly one level
+    let result = Command::new("tile-join")
+        .args(["-o", &pmtiles_path, "--force"])
+        .args(&level_pmtiles)
+        .output()?;
+
+    if !result.status.success() {
+        let stderr = String::from_utf8_lossy(&result.stderr);
+        return Err(format!("tile-join failed: {}", stderr).into());
+    }
+    // the levels were only tiled to be joined
+    for level_path in &level_pmtiles {
+        fs::remove_file(level_path)?;
+    }
+
+    progress!(output.progress, "✓ Generated PMTiles: {}", pmtiles_path);
+    Ok(())
+}
+
+/// Writes buffer polygons with their area and perimeter on the ground and the totals
+/// of the `cells` lying in them.
 pub fn write_buffered_to_flatgeobuf(
-    geometries: &Vec<Geometry>,
+    geometries: &[Geometry],
+    cells: &[VisitedCell],
+    output_path: &str,
+    output: &Output,
+) -> Result<(), Box<dyn std::error::Error>> {
+    write_polygons_to_flatgeobuf(to_polygons(geometries), cells, output_path, output)
+}
+
+/// Writes polygons without any attributes, e.g. the fog.
+pub fn write_shapes_to_flatgeobuf(
+    geometries: &[Geometry],
+    output_path: &str,
+    output: &Output,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let polygon_geometries: Vec<PolygonGeometry> = to_polygons(geometries)
+        .par_iter()
+        .map(|polygon| {
+            Ok(PolygonGeometry {
+                geo: output.project(polygon)?,
+            })
+        })
+        .collect::<Result<_, String>>()?;
+    write_features_to_flatgeobuf(&polygon_geometries, output_path, output)
+}
+
+/// The polygons of GEOS geometries, other geometries are skipped.
+fn to_polygons(geometries: &[Geometry]) -> Vec<Polygon> {
+    geometries
+        .par_iter()
+        .filter_map(|g| match GeoGeometry::try_from(g).ok()? {
+            GeoGeometry::Polygon(poly) => Some(poly),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Like `write_buffered_to_flatgeobuf`, for polygons in EPSG_METERS.
+pub fn write_polygons_to_flatgeobuf(
+    polygons: Vec<Polygon>,
+    cells: &[VisitedCell],
     output_path: &str,
+    output: &Output,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let buffered_geometries: Vec<BufferedGeometry> = geometries
-        .into_par_iter()
-        .filter_map(|g| {
-            // Convert GEOS geometry to geo polygon
-            GeoGeometry::try_from(g).ok().and_then(|geo_geom| {
-                match geo_geom {
-                    GeoGeometry::Polygon(poly) => Some(BufferedGeometry { geo: poly }),
-                    _ => None, // Skip non-polygon geometries
-                }
+    let coverage = CoverageIndex::new(polygons);
+    let stats = buffer_stats(&coverage, cells);
+
+    let buffered_geometries: Vec<BufferedGeometry> = coverage
+        .polygons()
+        .par_iter()
+        .zip(stats)
+        .map(|(polygon, stats)| {
+            Ok(BufferedGeometry {
+                geo: output.project(polygon)?,
+                area_m2: area_m2(&MultiPolygon::new(vec![polygon.clone()])),
+                perimeter_m: perimeter_m(polygon),
+                points: stats.points,
+                activities: stats.activities,
+                first_seen: stats.first_seen.map(|time| time.timestamp()),
+                last_seen: stats.last_seen.map(|time| time.timestamp()),
             })
         })
-        .collect();
-    FgbFile::create(output_path)
-        .unwrap()
-        .epsg(EPSG_METERS)
-        .write_features(&buffered_geometries)
-        .expect("file to be written");
+        .collect::<Result<_, String>>()?;
+    FgbFile::create(output_path)?
+        .epsg(output.crs.epsg())
+        .write_features(&buffered_geometries)?;
+    export_layer(output_path, output)?;
 
     // Generate PMTiles file
-    generate_pmtiles_for_buffered(output_path)?;
+    generate_pmtiles_for_shapes(output_path, output)?;
 
     Ok(())
 }
 
-/// Generate PMTiles for point data using tippecanoe
-fn generate_pmtiles_for_points(fgb_path: &str) -> Result<(), Box<dyn std::error::Error>> {
+pub fn write_lines_to_flatgeobuf(
+    lines: &[LineString],
+    output_path: &str,
+    output: &Output,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let line_geometries: Vec<LineGeometry> = lines
+        .par_iter()
+        .map(|l| {
+            Ok(LineGeometry {
+                geo: output.project(l)?,
+            })
+        })
+        .collect::<Result<_, String>>()?;
+    FgbFile::create(output_path)?
+        .epsg(output.crs.epsg())
+        .write_features(&line_geometries)?;
+    export_layer(output_path, output)?;
+
+    // Generate PMTiles file
+    generate_pmtiles_for_shapes(output_path, output)?;
+
+    Ok(())
+}
+
+/// Write any serializable features, the first field being the geometry in the output
+/// CRS (see `OutputCrs::project`) and the others becoming properties, and generate
+/// PMTiles for them.
+pub fn write_features_to_flatgeobuf<T: Serialize>(
+    features: &[T],
+    output_path: &str,
+    output: &Output,
+) -> Result<(), Box<dyn std::error::Error>> {
+    FgbFile::create(output_path)?
+        .epsg(output.crs.epsg())
+        .write_features(features)?;
+    export_layer(output_path, output)?;
+
+    generate_pmtiles_for_shapes(output_path, output)?;
+
+    Ok(())
+}
+
+/// A feature read back from a FlatGeobuf file, properties as strings.
+pub struct Feature {
+    pub geometry: GeoGeometry,
+    pub properties: HashMap<String, String>,
+}
+
+/// Read all features of a FlatGeobuf file, along with the EPSG code of its CRS
+/// (0 if the file doesn't declare one).
+pub fn read_flatgeobuf(
+    input_path: &Path,
+) -> Result<(i32, Vec<Feature>), Box<dyn std::error::Error>> {
+    let mut reader = BufReader::new(File::open(input_path)?);
+    let mut fgb = FgbReader::open(&mut reader)?.select_all()?;
+    let epsg = fgb.header().crs().map(|crs| crs.code()).unwrap_or(0);
+
+    let mut features = Vec::new();
+    while let Some(feature) = fgb.next()? {
+        features.push(Feature {
+            geometry: feature.to_geo()?,
+            properties: feature.properties()?,
+        });
+    }
+
+    Ok((epsg, features))
+}
+
+/// Read all features of a FlatGeobuf file in EPSG_METERS or WGS84, e.g. a layer of a
+/// previous run, projected to EPSG_METERS.
+pub fn read_flatgeobuf_in_meters(
+    input_path: &Path,
+    projector: Projector,
+) -> Result<Vec<Feature>, Box<dyn std::error::Error>> {
+    let (epsg, features) = read_flatgeobuf(input_path)?;
+    features
+        .into_iter()
+        .map(|feature| {
+            Ok(Feature {
+                geometry: to_meters(feature.geometry, epsg, projector)
+                    .map_err(|e| format!("{} of {}", e, input_path.display()))?,
+                properties: feature.properties,
+            })
+        })
+        .collect()
+}
+
+/// Appends `suffix` to the file stem of `path`, e.g. `data/out.fgb` -> `data/out_2023.fgb`.
+pub fn suffixed_path(path: &str, suffix: &str) -> String {
+    match path.rsplit_once('.') {
+        Some((stem, extension)) => format!("{}{}.{}", stem, suffix, extension),
+        None => format!("{}{}", path, suffix),
+    }
+}
+
+/// Name of the PMTiles file generated for a FlatGeobuf output
+pub fn pmtiles_filename(fgb_path: &str) -> Result<String, Box<dyn std::error::Error>> {
     // Extract filename and change extension to .pmtiles
     let fgb_filename = Path::new(fgb_path)
         .file_name()
         .and_then(|name| name.to_str())
         .ok_or("Invalid FGB path")?;
-    let pmtiles_filename = fgb_filename.replace(".fgb", ".pmtiles");
+    Ok(fgb_filename.replace(".fgb", ".pmtiles"))
+}
+
+/// Write a value as pretty-printed JSON, e.g. a manifest for the UI
+pub fn write_json<T: Serialize>(
+    value: &T,
+    output_path: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if let Some(parent) = Path::new(output_path).parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let writer = BufWriter::new(File::create(output_path)?);
+    serde_json::to_writer_pretty(writer, value)?;
+    Ok(())
+}
 
-    // Create ui/public directory if it doesn't exist
-    let ui_public_dir = "ui/public";
-    fs::create_dir_all(ui_public_dir)?;
+/// Generate PMTiles for point data using tippecanoe, only for the zoom levels in
+/// `zoom_range` if given. `extra_args` are passed on to it. Returns the PMTiles path.
+fn generate_pmtiles_for_points(
+    fgb_path: &str,
+    output: &Output,
+    zoom_range: Option<(u8, u8)>,
+    extra_args: &[&str],
+) -> Result<String, Box<dyn std::error::Error>> {
+    let pmtiles_filename = pmtiles_filename(fgb_path)?;
 
-    let pmtiles_path = format!("{}/{}", ui_public_dir, pmtiles_filename);
+    // Create the UI directory if it doesn't exist
+    fs::create_dir_all(&output.ui_dir)?;
 
-    println!("Generating PMTiles: {}...", pmtiles_path);
+    let pmtiles_path = output.ui_path(&pmtiles_filename);
 
-    let output = Command::new("tippecanoe")
+    progress!(output.progress, "Generating PMTiles: {}...", pmtiles_path);
+
+    let result = Command::new("tippecanoe")
         .args([
             "-o",
             &pmtiles_path,
-            "--projection=EPSG:3857",
+            &format!("--projection=EPSG:{}", output.crs.epsg()),
             "--force",
             "--cluster-distance=1",
             "-r1",
-            fgb_path,
         ])
+        .args(zoom_range.iter().flat_map(|(min_zoom, max_zoom)| {
+            [
+                format!("--minimum-zoom={}", min_zoom),
+                format!("--maximum-zoom={}", max_zoom),
+            ]
+        }))
+        .args(extra_args)
+        .arg(fgb_path)
         .output()?;
 
-    if !output.status.success() {
-        let stderr = String::from_utf8_lossy(&output.stderr);
+    if !result.status.success() {
+        let stderr = String::from_utf8_lossy(&result.stderr);
         return Err(format!("tippecanoe failed: {}", stderr).into());
     }
 
-    println!("✓ Generated PMTiles: {}", pmtiles_path);
-    Ok(())
+    progress!(output.progress, "✓ Generated PMTiles: {}", pmtiles_path);
+    Ok(pmtiles_path)
 }
 
-/// Generate PMTiles for buffered geometry data using tippecanoe
-fn generate_pmtiles_for_buffered(fgb_path: &str) -> Result<(), Box<dyn std::error::Error>> {
-    // Extract filename and change extension to .pmtiles
-    let fgb_filename = Path::new(fgb_path)
-        .file_name()
-        .and_then(|name| name.to_str())
-        .ok_or("Invalid FGB path")?;
-    let pmtiles_filename = fgb_filename.replace(".fgb", ".pmtiles");
+/// Generate PMTiles for line and polygon data using tippecanoe
+fn generate_pmtiles_for_shapes(
+    fgb_path: &str,
+    output: &Output,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let pmtiles_filename = pmtiles_filename(fgb_path)?;
 
-    // Create ui/public directory if it doesn't exist
-    let ui_public_dir = "ui/public";
-    fs::create_dir_all(ui_public_dir)?;
+    // Create the UI directory if it doesn't exist
+    fs::create_dir_all(&output.ui_dir)?;
 
-    let pmtiles_path = format!("{}/{}", ui_public_dir, pmtiles_filename);
+    let pmtiles_path = output.ui_path(&pmtiles_filename);
 
-    println!("Generating PMTiles: {}...", pmtiles_path);
+    progress!(output.progress, "Generating PMTiles: {}...", pmtiles_path);
 
-    let output = Command::new("tippecanoe")
+    let result = Command::new("tippecanoe")
         .args([
             "-o",
             &pmtiles_path,
-            "--projection=EPSG:3857",
+            &format!("--projection=EPSG:{}", output.crs.epsg()),
             "--force",
             fgb_path,
         ])
         .output()?;
 
-    if !output.status.success() {
-        let stderr = String::from_utf8_lossy(&output.stderr);
+    if !result.status.success() {
+        let stderr = String::from_utf8_lossy(&result.stderr);
         return Err(format!("tippecanoe failed: {}", stderr).into());
     }
 
-    println!("✓ Generated PMTiles: {}", pmtiles_path);
+    progress!(output.progress, "✓ Generated PMTiles: {}", pmtiles_path);
     Ok(())
 }
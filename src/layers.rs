@@ -0,0 +1,571 @@
+use chrono::{DateTime, Utc};
+use geo::{BoundingRect, Centroid, Coord, MultiPoint, Point, Rect};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+
+use crate::activity::ActivityKind;
+use crate::buffer::{BufferGroup, FogOptions, build_buffered_geometries, build_fog_geometries};
+use crate::hashable_point::{aggregate_to_grid, sanitize};
+use crate::io::{
+    HeatmapLevel, Output, pmtiles_filename, suffixed_path, write_buffered_to_flatgeobuf,
+    write_cells_to_flatgeobuf, write_heatmap_to_flatgeobuf, write_shapes_to_flatgeobuf,
+};
+use crate::measure::{HALF_WORLD_M, to_wgs84};
+use crate::parsers::TrackPoint;
+use crate::pipeline::Error;
+use crate::progress::Progress;
+use crate::raster::{RasterOptions, write_heatmap_image, write_heatmap_pmtiles};
+use crate::{
+    FOG_FILE, HEATMAP_FILE, HEATMAP_PNG_FILE, HEATMAP_RASTER_FILE, HEATMAP_TIFF_FILE, OUT_FILE,
+    OUT_FILE_100, OUT_FILE_1000,
+};
+
+/// Heatmap grid cell size in meters and the zoom levels it is used for. A pixel is
+/// about 10m at zoom 14, 150m at zoom 10 and 300m at zoom 9.
+pub const HEATMAP_LEVELS: &[(f64, u8, u8)] = &[(2.0, 14, 16), (20.0, 10, 13), (200.0, 0, 9)];
+
+/// Radius of the points of the 100m and 1km buffer layers, before radius factors.
+pub const BUFFER_100_RADIUS_M: f64 = 50.0;
+pub const BUFFER_1000_RADIUS_M: f64 = 500.0;
+
+/// Cell size in meters for finding the densest area, the map opens on the block of
+/// 3x3 cells with the most points.
+const INITIAL_VIEW_CELL_M: f64 = 5000.0;
+/// Width in pixels the initial zoom is chosen for, about a laptop screen
+const INITIAL_VIEW_WIDTH_PX: f64 = 1024.0;
+
+/// PMTiles filenames of one set of generated layers.
+#[derive(Serialize)]
+pub struct LayerFiles {
+    pub points: String,
+    pub buffer_100: String,
+    pub buffer_1000: String,
+    pub heatmap: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub heatmap_raster: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fog: Option<String>,
+    /// The points of the points, heatmap and raster layers
+    #[serde(skip)]
+    pub points_summary: LayerSummary,
+    /// The points of the buffer layers, without types with a radius factor of 0
+    #[serde(skip)]
+    pub buffered_summary: LayerSummary,
+    #[serde(skip)]
+    pub fog_summary: Option<LayerSummary>,
+}
+
+impl LayerFiles {
+    /// Entries of `layers.json` for these layers.
+    pub fn entries(&self) -> Vec<LayerEntry> {
+        let (points, buffered) = (&self.points_summary, &self.buffered_summary);
+        let mut entries = vec![
+            LayerEntry::new(
+                "points",
+                "Base Points",
+                &self.points,
+                GeometryType::Points,
+                points,
+            ),
+            LayerEntry {
+                radius_m: Some(BUFFER_100_RADIUS_M),
+                ..LayerEntry::new(
+                    "buffer_100",
+                    "Buffer 100m",
+                    &self.buffer_100,
+                    GeometryType::Polygons,
+                    buffered,
+                )
+            },
+            LayerEntry {
+                radius_m: Some(BUFFER_1000_RADIUS_M),
+                ..LayerEntry::new(
+                    "buffer_1000",
+                    "Buffer 1000m",
+                    &self.buffer_1000,
+                    GeometryType::Polygons,
+                    buffered,
+                )
+            },
+            LayerEntry::new(
+                "heatmap",
+                "Heatmap",
+                &self.heatmap,
+                GeometryType::Points,
+                points,
+            ),
+        ];
+        if let Some(heatmap_raster) = &self.heatmap_raster {
+            entries.push(LayerEntry::new(
+                "heatmap_raster",
+                "Heatmap (raster)",
+                heatmap_raster,
+                GeometryType::Raster,
+                points,
+            ));
+        }
+        if let (Some(fog), Some(summary)) = (&self.fog, &self.fog_summary) {
+            entries.push(LayerEntry::new(
+                "fog",
+                "Fog",
+                fog,
+                GeometryType::Polygons,
+                summary,
+            ));
+        }
+        entries
+    }
+}
+
+/// How the UI draws a layer.
+#[derive(Clone, Copy, Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GeometryType {
+    Points,
+    Lines,
+    Polygons,
+    Raster,
+}
+
+/// Number, extent and time range of the features of a layer.
+#[derive(Clone, Debug, Serialize)]
+pub struct LayerSummary {
+    pub feature_count: usize,
+    /// What the features are, e.g. `points` or `tiles`
+    pub feature_name: &'static str,
+    /// `[min_lon, min_lat, max_lon, max_lat]` in WGS84
+    pub bbox: Option<[f64; 4]>,
+    /// Unix seconds of the first and last visit
+    pub first_seen: Option<i64>,
+    pub last_seen: Option<i64>,
+}
+
+impl LayerSummary {
+    /// Summarizes `feature_count` features within `rect` in EPSG_METERS, visited at `times`.
+    pub fn new(
+        feature_name: &'static str,
+        feature_count: usize,
+        rect: Option<Rect>,
+        times: impl Iterator<Item = DateTime<Utc>>,
+    ) -> Self {
+        let (first, last) = times.fold(
+            (None, None),
+            |(first, last): (Option<DateTime<Utc>>, Option<DateTime<Utc>>), time| {
+                (
+                    Some(first.map_or(time, |first| first.min(time))),
+                    Some(last.map_or(time, |last| last.max(time))),
+                )
+            },
+        );
+        LayerSummary {
+            feature_count,
+            feature_name,
+            bbox: rect.map(|rect| {
+                let (min, max) = (to_wgs84(rect.min()), to_wgs84(rect.max()));
+                [min.x, min.y, max.x, max.y]
+            }),
+            first_seen: first.map(|time| time.timestamp()),
+            last_seen: last.map(|time| time.timestamp()),
+        }
+    }
+
+    /// Summarizes projected points.
+    pub fn of_points<'a>(points: impl Iterator<Item = &'a TrackPoint> + Clone) -> Self {
+        LayerSummary::new(
+            "points",
+            points.clone().count(),
+            MultiPoint::from_iter(points.clone().map(|p| p.point)).bounding_rect(),
+            points.filter_map(|p| p.time),
+        )
+    }
+}
+
+/// The rectangle around all `rects`, `None` without any.
+pub fn union_rect(rects: impl IntoIterator<Item = Rect>) -> Option<Rect> {
+    rects.into_iter().reduce(|a, b| {
+        Rect::new(
+            Coord {
+                x: a.min().x.min(b.min().x),
+                y: a.min().y.min(b.min().y),
+            },
+            Coord {
+                x: a.max().x.max(b.max().x),
+                y: a.max().y.max(b.max().y),
+            },
+        )
+    })
+}
+
+/// Where the map opens, on the densest area rather than the bounds of all points,
+/// which can span continents.
+#[derive(Debug, Serialize)]
+pub struct InitialView {
+    /// `[lon, lat]` in WGS84, the mean of the points in the densest area
+    pub center: [f64; 2],
+    pub zoom: f64,
+    /// `[min_lon, min_lat, max_lon, max_lat]` of the points in the densest area
+    pub bounds: [f64; 4],
+}
+
+impl InitialView {
+    /// The view on the densest area of projected points, `None` without points.
+    pub fn new(points: &[TrackPoint]) -> Option<Self> {
+        let (mean, rect) = densest_area(points)?;
+
+        // in EPSG_METERS a pixel covers the same distance on both axes at any latitude,
+        // and MapLibre fits the world into 512px at zoom 0
+        let extent_m = rect
+            .width()
+            .max(rect.height())
+            .max(INITIAL_VIEW_CELL_M / 5.0);
+        let zoom = (2.0 * HALF_WORLD_M * INITIAL_VIEW_WIDTH_PX / (512.0 * extent_m)).log2();
+
+        let center = to_wgs84(mean.0);
+        let (min, max) = (to_wgs84(rect.min()), to_wgs84(rect.max()));
+        Some(InitialView {
+            center: [center.x, center.y],
+            zoom: (zoom.clamp(2.0, 16.0) * 10.0).round() / 10.0,
+            bounds: [min.x, min.y, max.x, max.y],
+        })
+    }
+}
+
+/// Mean and bounding box of the points in the block of 3x3 cells with the most points,
+/// in EPSG_METERS. `None` without points.
+pub fn densest_area(points: &[TrackPoint]) -> Option<(Point, Rect)> {
+    let cell_of = |point: &Point| {
+        (
+            (point.x() / INITIAL_VIEW_CELL_M).floor() as i64,
+            (point.y() / INITIAL_VIEW_CELL_M).floor() as i64,
+        )
+    };
+    let mut counts: HashMap<(i64, i64), usize> = HashMap::new();
+    for point in points {
+        *counts.entry(cell_of(&point.point)).or_default() += 1;
+    }
+    let block_count = |(x, y): (i64, i64)| -> usize {
+        (-1..=1)
+            .flat_map(|dx| (-1..=1).map(move |dy| (x + dx, y + dy)))
+            .filter_map(|cell| counts.get(&cell))
+            .sum()
+    };
+    // ties go to the lowest cell, so the view doesn't change between runs
+    let (center_x, center_y) = counts
+        .keys()
+        .map(|&cell| (block_count(cell), std::cmp::Reverse(cell)))
+        .max()
+        .map(|(_, std::cmp::Reverse(cell))| cell)?;
+
+    let dense: MultiPoint = points
+        .iter()
+        .map(|p| p.point)
+        .filter(|point| {
+            let (x, y) = cell_of(point);
+            (x - center_x).abs() <= 1 && (y - center_y).abs() <= 1
+        })
+        .collect();
+    Some((dense.centroid()?, dense.bounding_rect()?))
+}
+
+/// A generated PMTiles file, as listed in `layers.json` for the UI.
+#[derive(Clone, Debug, Serialize)]
+pub struct LayerEntry {
+    /// Kind of layer, e.g. `buffer_100` or `tiles_14`, the same regardless of suffixes
+    pub id: String,
+    pub name: String,
+    pub filename: String,
+    pub geometry_type: GeometryType,
+    /// Buffer radius of every point
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub radius_m: Option<f64>,
+    #[serde(flatten)]
+    pub summary: LayerSummary,
+}
+
+impl LayerEntry {
+    pub fn new(
+        id: &str,
+        name: &str,
+        filename: &str,
+        geometry_type: GeometryType,
+        summary: &LayerSummary,
+    ) -> Self {
+        LayerEntry {
+            id: id.to_string(),
+            name: name.to_string(),
+            filename: filename.to_string(),
+            geometry_type,
+            radius_m: None,
+            summary: summary.clone(),
+        }
+    }
+}
+
+/// All layers of the last build, so the UI lists exactly what was produced.
+#[derive(Serialize)]
+pub struct LayerManifest {
+    /// Unix seconds, the UI reloads the layers when it changes
+    pub built_at: i64,
+    /// Where the map opens, `None` without points
+    pub view: Option<InitialView>,
+    pub layers: Vec<LayerEntry>,
+}
+
+#[derive(Clone, Default)]
+pub struct LayerOptions {
+    /// Buffer radius multiplier per activity type, 1.0 if not set. 0.0 excludes
+    /// the type from the buffer layers, e.g. to not explore anything by plane.
+    pub radius_factors: HashMap<ActivityKind, f64>,
+    /// Also render the heatmap to raster outputs
+    pub raster: Option<RasterOptions>,
+    /// Also write the fog, the complement of the 100m buffers
+    pub fog: Option<FogOptions>,
+}
+
+impl LayerOptions {
+    pub fn radius_factor(&self, kind: ActivityKind) -> f64 {
+        self.radius_factors.get(&kind).copied().unwrap_or(1.0)
+    }
+}
+
+/// Builds the points, buffer and heatmap layers from projected points.
+/// `suffix` is appended to every output filename, e.g. `_2023`.
+pub fn build_layers(
+    points: Vec<TrackPoint>,
+    suffix: &str,
+    options: &LayerOptions,
+    output: &Output,
+) -> Result<LayerFiles, Error> {
+    let path = |file: &str| output.path(&suffixed_path(file, suffix));
+    let heatmap_path = path(HEATMAP_FILE);
+    let out_path = path(OUT_FILE);
+    let out_path_100 = path(OUT_FILE_100);
+    let out_path_1000 = path(OUT_FILE_1000);
+
+    progress!(output.progress, "\nProcessing heatmap points...");
+
+    // weighted grid cells instead of every single point, coarser for lower zoom levels
+    let projected: Vec<Point> = points.iter().map(|p| p.point).collect();
+    let heatmap_levels: Vec<HeatmapLevel> = HEATMAP_LEVELS
+        .iter()
+        .map(|&(cell_size_m, min_zoom, max_zoom)| HeatmapLevel {
+            cell_size_m,
+            min_zoom,
+            max_zoom,
+            cells: aggregate_to_grid(&projected, cell_size_m, output.progress),
+        })
+        .collect();
+
+    progress!(
+        output.progress,
+        "Writing heatmap cells to {}...",
+        heatmap_path
+    );
+    write_heatmap_to_flatgeobuf(&heatmap_levels, &heatmap_path, output)
+        .map_err(|e| Error::write(&heatmap_path, e))?;
+
+    progress!(
+        output.progress,
+        "✓ Successfully wrote {} heatmap cells to {}",
+        heatmap_levels
+            .iter()
+            .map(|level| level.cells.len())
+            .sum::<usize>(),
+        heatmap_path
+    );
+
+    let heatmap_raster = options
+        .raster
+        .as_ref()
+        .map(|raster| {
+            progress!(output.progress, "\nRendering raster heatmap...");
+            let tiff_path = path(HEATMAP_TIFF_FILE);
+            let png_path = path(HEATMAP_PNG_FILE);
+            write_heatmap_image(&projected, &tiff_path, &png_path, raster, output.progress)
+                .map_err(|e| Error::write(&tiff_path, e))?;
+
+            let raster_path = output.ui_path(&suffixed_path(HEATMAP_RASTER_FILE, suffix));
+            write_heatmap_pmtiles(&projected, &raster_path, raster, output.progress)
+                .map_err(|e| Error::write(&raster_path, e))?;
+            pmtiles_filename(&raster_path).map_err(|e| Error::write(&raster_path, e))
+        })
+        .transpose()?;
+
+    let radius_groups = if options.radius_factors.is_empty() {
+        None
+    } else {
+        Some(group_by_radius_factor(&points, options, output.progress))
+    };
+
+    let (sanitized_cells, stats) = sanitize(&points, output.progress);
+    stats.print(output.progress);
+
+    // without type-specific radii, all points are buffered with the same radius
+    let radius_groups = radius_groups
+        .unwrap_or_else(|| vec![(1.0, sanitized_cells.iter().map(|cell| cell.point).collect())]);
+
+    progress!(output.progress, "\nWriting points to {}...", out_path);
+
+    write_cells_to_flatgeobuf(&sanitized_cells, &out_path, output)
+        .map_err(|e| Error::write(&out_path, e))?;
+
+    progress!(
+        output.progress,
+        "✓ Successfully wrote {} points to {}",
+        sanitized_cells.len(),
+        out_path
+    );
+
+    progress!(output.progress, "\nBuilding buffered 100m geometries...");
+    let buffered_geometries = build_buffered_geometries(
+        &buffer_groups(&radius_groups, BUFFER_100_RADIUS_M),
+        8,         // quadrant segments
+        1_000,     // chunk size
+        Some(0.5), // simplify tolerance
+        output.progress,
+    )
+    .map_err(|e| Error::Buffer(e.to_string()))?;
+
+    progress!(
+        output.progress,
+        "Writing buffered geometries to {}...",
+        out_path_100
+    );
+    write_buffered_to_flatgeobuf(
+        &buffered_geometries,
+        &sanitized_cells,
+        &out_path_100,
+        output,
+    )
+    .map_err(|e| Error::write(&out_path_100, e))?;
+
+    progress!(
+        output.progress,
+        "✓ Successfully wrote {} buffered geometries to {}",
+        buffered_geometries.len(),
+        out_path_100
+    );
+
+    let fog_layer = options.fog.as_ref().map(|fog| {
+        let fog_path = path(FOG_FILE);
+        progress!(output.progress, "\nBuilding fog...");
+        let fog_geometries = build_fog_geometries(&buffered_geometries, fog, output.progress)
+            .map_err(|e| Error::Buffer(e.to_string()))?;
+
+        progress!(output.progress, "Writing fog to {}...", fog_path);
+        write_shapes_to_flatgeobuf(&fog_geometries, &fog_path, output)
+            .map_err(|e| Error::write(&fog_path, e))?;
+        progress!(
+            output.progress,
+            "✓ Successfully wrote {} fog geometries to {}",
+            fog_geometries.len(),
+            fog_path
+        );
+        // the fog covers the whole extent, apart from explored areas
+        let extent = fog.extent.unwrap_or(Rect::new(
+            Coord {
+                x: -HALF_WORLD_M,
+                y: -HALF_WORLD_M,
+            },
+            Coord {
+                x: HALF_WORLD_M,
+                y: HALF_WORLD_M,
+            },
+        ));
+        Ok((
+            pmtiles_filename(&fog_path).map_err(|e| Error::write(&fog_path, e))?,
+            LayerSummary::new(
+                "polygons",
+                fog_geometries.len(),
+                Some(extent),
+                None.into_iter(),
+            ),
+        ))
+    });
+    let (fog, fog_summary) = fog_layer.transpose()?.unzip();
+
+    progress!(output.progress, "\nBuilding buffered 1km geometries...");
+    let buffered_geometries = build_buffered_geometries(
+        &buffer_groups(&radius_groups, BUFFER_1000_RADIUS_M),
+        8,         // quadrant segments
+        1_000,     // chunk size
+        Some(0.5), // simplify tolerance
+        output.progress,
+    )
+    .map_err(|e| Error::Buffer(e.to_string()))?;
+
+    progress!(
+        output.progress,
+        "Writing buffered geometries to {}...",
+        out_path_1000
+    );
+    write_buffered_to_flatgeobuf(
+        &buffered_geometries,
+        &sanitized_cells,
+        &out_path_1000,
+        output,
+    )
+    .map_err(|e| Error::write(&out_path_1000, e))?;
+
+    progress!(
+        output.progress,
+        "✓ Successfully wrote {} buffered geometries to {}",
+        buffered_geometries.len(),
+        out_path_1000
+    );
+
+    let pmtiles = |path: &str| pmtiles_filename(path).map_err(|e| Error::write(path, e));
+    Ok(LayerFiles {
+        points: pmtiles(&out_path)?,
+        buffer_100: pmtiles(&out_path_100)?,
+        buffer_1000: pmtiles(&out_path_1000)?,
+        heatmap: pmtiles(&heatmap_path)?,
+        heatmap_raster,
+        fog,
+        points_summary: LayerSummary::of_points(points.iter()),
+        buffered_summary: LayerSummary::of_points(
+            points
+                .iter()
+                .filter(|p| options.radius_factor(p.kind) > 0.0),
+        ),
+        fog_summary,
+    })
+}
+
+/// Splits points by the radius factor of their activity type and deduplicates each group.
+/// Types with a factor of 0 are dropped.
+pub fn group_by_radius_factor(
+    points: &[TrackPoint],
+    options: &LayerOptions,
+    progress: Progress,
+) -> Vec<(f64, Vec<Point>)> {
+    // f64 isn't Ord, its bits are good enough to group identical factors
+    let mut groups: BTreeMap<u64, Vec<TrackPoint>> = BTreeMap::new();
+    for point in points {
+        let factor = options.radius_factor(point.kind);
+        if factor > 0.0 {
+            groups.entry(factor.to_bits()).or_default().push(*point);
+        }
+    }
+
+    groups
+        .into_iter()
+        .map(|(factor, points)| {
+            let factor = f64::from_bits(factor);
+            progress!(progress, "Points buffered with {}x radius:", factor);
+            let (cells, stats) = sanitize(&points, progress);
+            stats.print(progress);
+            (factor, cells.into_iter().map(|cell| cell.point).collect())
+        })
+        .collect()
+}
+
+fn buffer_groups(radius_groups: &[(f64, Vec<Point>)], radius_m: f64) -> Vec<BufferGroup<'_>> {
+    radius_groups
+        .iter()
+        .map(|(factor, points)| BufferGroup {
+            points,
+            radius_m: radius_m * factor,
+        })
+        .collect()
+}
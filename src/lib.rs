@@ -0,0 +1,82 @@
+//! Converts location history into fog of war map layers.
+//!
+//! The `point-cloud` binary is one consumer of this library. To embed the pipeline,
+//! start with [`pipeline::Pipeline::builder`], which reads, cleans, sanitizes and
+//! buffers activities without printing anything. Where and how outputs are written,
+//! including progress output, is set on the builder and passed to the writers as an
+//! [`io::Output`].
+
+#[macro_use]
+pub mod progress;
+
+pub mod activity;
+pub mod buffer;
+pub mod coverage;
+pub mod crs;
+pub mod export;
+pub mod filter;
+pub mod h3_cells;
+pub mod hashable_point;
+pub mod io;
+pub mod layers;
+pub mod map_match;
+pub mod measure;
+pub mod new_ground;
+pub mod osm;
+pub mod parsers;
+pub mod period;
+pub mod pipeline;
+pub mod raster;
+pub mod region_stats;
+pub mod regions;
+pub mod render;
+pub mod report;
+pub mod resample;
+pub mod street_coverage;
+pub mod tiles;
+pub mod tracks;
+
+/// Where parsers read from by default
+pub const DATA_DIR: &str = "data";
+/// Where outputs are written to by default, see [`io::Output`]
+pub const OUT_DIR: &str = "data";
+/// Where the UI loads the PMTiles and manifests from by default
+pub const UI_PUBLIC_DIR: &str = "ui/public";
+
+// file names of the outputs in the output directory
+pub const OUT_FILE: &str = "out.fgb";
+pub const OUT_FILE_100: &str = "out_buffer_100.fgb";
+pub const OUT_FILE_1000: &str = "out_buffer_1000.fgb";
+pub const HEATMAP_FILE: &str = "heatmap.fgb";
+pub const MATCHED_FILE: &str = "matched.fgb";
+pub const STREET_COVERAGE_FILE: &str = "street_coverage.csv";
+pub const STREET_COVERAGE_AREAS_FILE: &str = "street_coverage_areas.csv";
+pub const UNCOVERED_STREETS_FILE: &str = "uncovered_streets.fgb";
+pub const REGION_STATS_CSV_FILE: &str = "region_stats.csv";
+pub const REGION_STATS_JSON_FILE: &str = "region_stats.json";
+pub const REGIONS_FILE: &str = "regions.fgb";
+pub const TILES_FILE: &str = "tiles.fgb";
+pub const TILES_STATS_FILE: &str = "tiles.json";
+pub const H3_FILE: &str = "h3.fgb";
+pub const HEATMAP_TIFF_FILE: &str = "heatmap.tif";
+pub const HEATMAP_PNG_FILE: &str = "heatmap.png";
+pub const FOG_FILE: &str = "fog.fgb";
+/// `tracks.gpx` etc., or a directory with one file per activity
+pub const TRACKS_FILE: &str = "tracks";
+pub const GPKG_FILE: &str = "layers.gpkg";
+pub const POSTER_FILE: &str = "poster.svg";
+pub const NEW_GROUND_FILE: &str = "new_ground.fgb";
+pub const NEW_GROUND_CSV_FILE: &str = "new_ground.csv";
+pub const REPORT_MD_FILE: &str = "report.md";
+pub const REPORT_HTML_FILE: &str = "report.html";
+pub const REPORT_OVERVIEW_FILE: &str = "report_overview.png";
+pub const REPORT_OVERVIEW_1000_FILE: &str = "report_overview_1000.png";
+pub const REPORT_DENSEST_FILE: &str = "report_densest.png";
+
+// file names of the outputs in the UI directory, next to the PMTiles
+pub const PERIOD_MANIFEST_FILE: &str = "periods.json";
+pub const LAYER_MANIFEST_FILE: &str = "layers.json";
+pub const HEATMAP_RASTER_FILE: &str = "heatmap_raster.pmtiles";
+
+pub const EPSG_WGS84: i32 = 4326;
+pub const EPSG_METERS: i32 = 3857;
@@ -1,138 +1,448 @@
-mod buffer;
-mod hashable_point;
-mod io;
-mod parsers;
+mod cli;
+mod serve;
+mod watch;
 
-use buffer::build_buffered_geometries;
-use hashable_point::{sanitize, sanitize_to_1m_no_dedup};
-use io::{write_buffered_to_flatgeobuf, write_to_flatgeobuf};
-use parsers::{Parser, fit::FitParser, google_timeline::GoogleTimelineParser, gpx::GpxParser};
-use proj::Proj;
-use std::path::Path;
+use chrono::Duration;
+use clap::Parser as _;
+use cli::{
+    Cli, Command, RenderArgs, ServeArgs, ServerArgs, StatsArgs, StreetCoverageArgs, WatchArgs,
+};
+use geo::Rect;
+use point_cloud::buffer::FogOptions;
+use point_cloud::coverage::CoverageIndex;
+use point_cloud::crs::Projector;
+use point_cloud::io::Output;
+use point_cloud::map_match::MatchOptions;
+use point_cloud::osm::load_road_network;
+use point_cloud::parsers::strava_csv::is_activities_csv;
+use point_cloud::parsers::{Activity, Parser, count_points};
+use point_cloud::pipeline::Pipeline;
+use point_cloud::progress::Progress;
+use point_cloud::raster::RasterOptions;
+use point_cloud::region_stats::{region_stats, write_region_stats};
+use point_cloud::regions::load_regions;
+use point_cloud::render::{PosterHeatmap, PosterOptions, render_poster};
+use point_cloud::report::SourceStats;
+use point_cloud::resample::ResampleOptions;
+use point_cloud::street_coverage::{cover_ways, write_statistics, write_uncovered};
+use point_cloud::{HEATMAP_FILE, OUT_FILE_100, POSTER_FILE};
+use serve::{ServeOptions, bind, serve};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::thread;
+use tiny_http::Server;
+use watch::Snapshot;
 
-pub const DATA_DIR: &str = "data";
-pub const OUT_PATH: &str = "data/out.fgb";
-pub const OUT_PATH_100: &str = "data/out_buffer_100.fgb";
-pub const OUT_PATH_1000: &str = "data/out_buffer_1000.fgb";
-pub const HEATMAP_PATH: &str = "data/heatmap.fgb";
-
-pub const EPSG_WGS84: i32 = 4326;
-pub const EPSG_METERS: i32 = 3857;
-
-thread_local! {
-    // project WGS84 to proper EPSG
-    pub static PROJ_METER: Proj = Proj::new_known_crs(format!("EPSG:{}", EPSG_WGS84).as_str(), format!("EPSG:{}", EPSG_METERS).as_str(), None).unwrap();
-}
+/// The UI built with `bun run build`
+const UI_DIST_DIR: &str = "ui/dist";
 
 fn main() -> Result<(), ()> {
-    let data_dir = Path::new(DATA_DIR);
-
-    let parsers: Vec<Box<dyn Parser>> = vec![
-        Box::new(GpxParser),
-        Box::new(GoogleTimelineParser),
-        Box::new(FitParser),
-    ];
-
-    let mut all_points = Vec::new();
-
-    for parser in &parsers {
-        println!("\n--- Running {} ---", parser.name());
-        match parser.parse(data_dir) {
-            Ok(mut points) => {
-                println!("✓ {} extracted {} points", parser.name(), points.len());
-                all_points.append(&mut points);
-            }
-            Err(e) => {
-                println!("✗ {} failed: {}", parser.name(), e);
+    let cli = Cli::parse();
+    let output = output(&cli);
+
+    match &cli.command {
+        Some(Command::StreetCoverage(args)) => street_coverage(args, &output),
+        Some(Command::Stats(args)) => stats(args, &output),
+        Some(Command::Render(args)) => render(args, &output),
+        Some(Command::Serve(args)) => {
+            if args.build {
+                build(&cli)?;
             }
+            serve_map(args, &output)
         }
+        Some(Command::Watch(args)) => watch(&cli, args),
+        None => build(&cli),
     }
+}
 
-    println!("\n--- Summary ---");
-    println!(
-        "Collected {} total points from all parsers",
-        all_points.len()
-    );
-
-    if all_points.is_empty() {
-        println!("No points to process.");
-        return Ok(());
+/// Where and how the layers are written, printing progress.
+fn output(cli: &Cli) -> Output {
+    Output {
+        dir: cli.out_dir.clone(),
+        ui_dir: cli.ui_dir.clone(),
+        crs: cli.crs,
+        export: cli.export.clone(),
+        progress: Progress::new(true),
+        projector: Projector,
     }
+}
 
-    println!("Transforming coordinates...");
-
-    PROJ_METER.with(|proj| {
-        proj.project_array(&mut all_points, false)
-            .expect("transformation to proper EPSG should work")
-    });
+/// Prints why `step` failed, so the subcommands can return early with `?`.
+fn report<T>(result: Result<T, impl fmt::Display>, step: &str) -> Result<T, ()> {
+    result.map_err(|e| println!("✗ {} failed: {}", step, e))
+}
 
-    println!("Successfully transformed {} points", all_points.len());
+/// The buffer layer given on the command line, else the one of the last run.
+fn coverage_path(coverage: &Option<PathBuf>, output: &Output) -> PathBuf {
+    coverage
+        .clone()
+        .unwrap_or_else(|| output.dir.join(OUT_FILE_100))
+}
 
-    // Process heatmap points (1m accuracy, no deduplication)
-    println!("\nProcessing heatmap points...");
+/// Compares the buffered coverage of a previous run with the streets of an OSM extract.
+fn street_coverage(args: &StreetCoverageArgs, output: &Output) -> Result<(), ()> {
+    println!("\n--- Street coverage ---");
+    let network = report(
+        load_road_network(&args.osm, output.progress, output.projector),
+        "Reading the OSM extract",
+    )?;
 
-    // Sanitize to 1m accuracy without deduplication
-    let heatmap_sanitized = sanitize_to_1m_no_dedup(all_points);
+    let coverage_path = coverage_path(&args.coverage, output);
+    println!("Reading coverage from {}...", coverage_path.display());
+    let coverage = report(
+        CoverageIndex::from_flatgeobuf(&coverage_path, output.projector),
+        "Reading the coverage",
+    )?;
 
-    println!("Writing heatmap points to {}...", HEATMAP_PATH);
-    write_to_flatgeobuf(&heatmap_sanitized, HEATMAP_PATH).expect("writing heatmap to FGB to work");
+    let regions = match &args.areas {
+        Some(path) => report(
+            load_regions(path, &args.area_name, output.progress, output.projector),
+            "Reading the areas",
+        )?,
+        None => Vec::new(),
+    };
 
     println!(
-        "✓ Successfully wrote {} heatmap points to {}",
-        heatmap_sanitized.len(),
-        HEATMAP_PATH
+        "Checking {} highways against the coverage...",
+        network.ways.len()
     );
+    let ways = cover_ways(&network, &coverage, &args.exclude_highway);
 
-    let (sanitized_points, stats) = sanitize(heatmap_sanitized);
-    stats.print();
+    report(
+        write_statistics(&ways, &regions, output),
+        "Writing the street coverage",
+    )?;
+    report(
+        write_uncovered(&ways, output),
+        "Writing the uncovered streets",
+    )?;
 
-    println!("\nWriting points to {}...", OUT_PATH);
+    Ok(())
+}
+
+/// Reports the explored area per region from the buffered coverage of a previous run.
+fn stats(args: &StatsArgs, output: &Output) -> Result<(), ()> {
+    println!("\n--- Region stats ---");
+    let mut regions = report(
+        load_regions(
+            &args.regions,
+            &args.region_name,
+            output.progress,
+            output.projector,
+        ),
+        "Reading the regions",
+    )?;
+    if !args.admin_level.is_empty() {
+        regions.retain(|region| {
+            region
+                .admin_level
+                .as_ref()
+                .is_some_and(|level| args.admin_level.contains(level))
+        });
+        println!(
+            "Kept {} regions of admin levels {:?}",
+            regions.len(),
+            args.admin_level
+        );
+    }
 
-    write_to_flatgeobuf(&sanitized_points, OUT_PATH).expect("writing to FGB to work");
+    let coverage_path = coverage_path(&args.coverage, output);
+    println!("Reading coverage from {}...", coverage_path.display());
+    let coverage = report(
+        CoverageIndex::from_flatgeobuf(&coverage_path, output.projector),
+        "Reading the coverage",
+    )?;
 
     println!(
-        "✓ Successfully wrote {} points to {}",
-        sanitized_points.len(),
-        OUT_PATH
+        "Intersecting {} regions with the coverage...",
+        regions.len()
     );
+    let stats = region_stats(&regions, &coverage);
+    for row in &stats {
+        println!(
+            "{}: {} of {} km² ({}%)",
+            row.name, row.explored_km2, row.area_km2, row.explored_percent
+        );
+    }
 
-    println!("\nBuilding buffered 100m geometries...");
-    let buffered_geometries = build_buffered_geometries(
-        &sanitized_points,
-        50.0,      // 50m radius
-        8,         // quadrant segments
-        1_000,     // chunk size
-        Some(0.5), // simplify tolerance
-    );
+    report(
+        write_region_stats(&stats, &regions, output),
+        "Writing the region stats",
+    )?;
 
-    println!("Writing buffered geometries to {}...", OUT_PATH_100);
-    write_buffered_to_flatgeobuf(&buffered_geometries, OUT_PATH_100)
-        .expect("writing buffered geometries to FGB to work");
+    Ok(())
+}
 
-    println!(
-        "✓ Successfully wrote {} buffered geometries to {}",
-        buffered_geometries.len(),
-        OUT_PATH_100
-    );
+/// Projects a WGS84 bounding box given on the command line to EPSG_METERS.
+fn project_rect(rect: Rect, projector: Projector) -> Result<Rect, ()> {
+    let corners = projector
+        .to_meters(rect.min())
+        .and_then(|min| Ok(Rect::new(min, projector.to_meters(rect.max())?)));
+    report(corners, "Projecting the bounding box")
+}
 
-    println!("\nBuilding buffered 1km geometries...");
-    let buffered_geometries = build_buffered_geometries(
-        &sanitized_points,
-        500.0,     // 500m radius
-        8,         // quadrant segments
-        1_000,     // chunk size
-        Some(0.5), // simplify tolerance
-    );
+/// Renders the fog of a previous run to a poster.
+fn render(args: &RenderArgs, output: &Output) -> Result<(), ()> {
+    println!("\n--- Poster ---");
+    let coverage_path = coverage_path(&args.coverage, output);
+    println!("Reading coverage from {}...", coverage_path.display());
+    let coverage = report(
+        CoverageIndex::from_flatgeobuf(&coverage_path, output.projector),
+        "Reading the coverage",
+    )?;
 
-    println!("Writing buffered geometries to {}...", OUT_PATH_1000);
-    write_buffered_to_flatgeobuf(&buffered_geometries, OUT_PATH_1000)
-        .expect("writing buffered geometries to FGB to work");
+    let options = PosterOptions {
+        bbox: args
+            .bbox
+            .map(|bbox| project_rect(bbox, output.projector))
+            .transpose()?,
+        paper: if args.landscape {
+            args.paper.landscape()
+        } else {
+            args.paper
+        },
+        dpi: args.dpi,
+        background: args.background,
+        fog_color: args.fog_color,
+        heatmap: args.heatmap.clone().map(|path| PosterHeatmap {
+            path: path.unwrap_or_else(|| output.path(HEATMAP_FILE)),
+            radius_mm: args.heatmap_radius,
+            ramp: args.heatmap_ramp.clone(),
+            log_scale: args.heatmap_log,
+        }),
+    };
 
-    println!(
-        "✓ Successfully wrote {} buffered geometries to {}",
-        buffered_geometries.len(),
-        OUT_PATH_1000
-    );
+    let poster_path = args
+        .output
+        .clone()
+        .unwrap_or_else(|| output.dir.join(POSTER_FILE));
+    report(
+        render_poster(
+            &coverage,
+            &options,
+            &poster_path,
+            output.progress,
+            output.projector,
+        ),
+        "Rendering the poster",
+    )?;
 
     Ok(())
 }
+
+/// Where to serve the UI and layers from, fails if the UI isn't built.
+fn serve_options(args: &ServerArgs, output: &Output) -> Result<ServeOptions, ()> {
+    if !Path::new(UI_DIST_DIR).join("index.html").is_file() {
+        println!(
+            "✗ No UI found in {}, run `bun install && bun run build` in ui first",
+            UI_DIST_DIR
+        );
+        return Err(());
+    }
+
+    Ok(ServeOptions {
+        address: format!("{}:{}", args.host, args.port),
+        // the layers of the last run take precedence over those copied into the build
+        roots: vec![output.ui_dir.clone(), PathBuf::from(UI_DIST_DIR)],
+        threads: args.threads,
+    })
+}
+
+/// Listens on the address of `options`, telling where the map is served.
+fn bind_server(options: &ServeOptions) -> Result<Server, ()> {
+    let server = report(bind(options), "Serving the map")?;
+    println!("✓ Serving the map on http://{}", options.address);
+    Ok(server)
+}
+
+/// Serves the built UI along with the layers of the last run.
+fn serve_map(args: &ServeArgs, output: &Output) -> Result<(), ()> {
+    println!("\n--- Serve ---");
+    let options = serve_options(&args.server, output)?;
+    let server = bind_server(&options)?;
+    report(serve(server, &options), "Serving the map")
+}
+
+/// Runs a parser on the data directory of the pipeline, nothing if it fails.
+fn run_parser(parser: &dyn Parser, pipeline: &Pipeline) -> Vec<Activity> {
+    println!("\n--- Running {} ---", parser.name());
+    match parser.parse(pipeline.data_dir(), pipeline.output().progress) {
+        Ok(activities) => {
+            println!(
+                "✓ {} extracted {} points",
+                parser.name(),
+                count_points(&activities)
+            );
+            activities
+        }
+        Err(e) => {
+            println!("✗ {} failed: {}", parser.name(), e);
+            Vec::new()
+        }
+    }
+}
+
+/// Runs every parser of the pipeline, the activities of every parser in their order.
+fn run_parsers(pipeline: &Pipeline) -> Vec<Vec<Activity>> {
+    pipeline
+        .parsers()
+        .iter()
+        .map(|parser| run_parser(parser.as_ref(), pipeline))
+        .collect()
+}
+
+/// Builds all layers from the activities of every parser.
+fn build_layers(pipeline: &Pipeline, activities: &[Vec<Activity>]) -> Result<(), ()> {
+    let sources = pipeline
+        .parsers()
+        .iter()
+        .zip(activities)
+        .map(|(parser, activities)| SourceStats::new(parser.name(), activities))
+        .collect();
+    match pipeline.build_layers(sources, activities.concat()) {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            println!("✗ Building the layers failed: {}", e);
+            Err(())
+        }
+    }
+}
+
+/// Reads all activities and builds the map layers from them.
+fn build(cli: &Cli) -> Result<(), ()> {
+    let pipeline = pipeline(cli)?;
+    build_layers(&pipeline, &run_parsers(&pipeline))
+}
+
+/// Rebuilds the layers whenever files read by a parser change the parsed activities,
+/// re-running only the parsers whose files changed.
+fn watch(cli: &Cli, args: &WatchArgs) -> Result<(), ()> {
+    if args.serve {
+        let options = serve_options(&args.server, &output(cli))?;
+        // bound up front, so a taken port stops the watcher as well
+        let server = bind_server(&options)?;
+        thread::spawn(move || {
+            if report(serve(server, &options), "Serving the map").is_err() {
+                process::exit(1);
+            }
+        });
+    }
+
+    let interval = std::time::Duration::from_secs(args.interval);
+    let mut pipeline = pipeline(cli)?;
+    // layers written below the data directory aren't inputs
+    let output = pipeline.output();
+    let mut snapshot = Snapshot::new(pipeline.data_dir(), &[&output.dir, &output.ui_dir]);
+    let mut activities = run_parsers(&pipeline);
+    build_layers(&pipeline, &activities)?;
+
+    loop {
+        println!(
+            "\n--- Watching {} for changes ---",
+            pipeline.data_dir().display()
+        );
+        let changed = snapshot.wait_for_changes(interval);
+        // outputs written to the data directory aren't read by any parser
+        let parsers = pipeline.parsers();
+        let affected: Vec<usize> = (0..parsers.len())
+            .filter(|&i| changed.iter().any(|path| parsers[i].handles(path)))
+            .collect();
+        if affected.is_empty() {
+            continue;
+        }
+
+        println!(
+            "{} files changed, re-running their parsers...",
+            changed.len()
+        );
+        if changed.iter().any(|path| is_activities_csv(path)) {
+            // the parsers read the activity types once
+            pipeline = self::pipeline(cli)?;
+        }
+        let mut modified = false;
+        for i in affected {
+            let parsed = run_parser(pipeline.parsers()[i].as_ref(), &pipeline);
+            modified |= parsed != activities[i];
+            activities[i] = parsed;
+        }
+        // every layer depends on all activities, so any change rebuilds all of them
+        if !modified {
+            println!("✓ Activities unchanged, nothing to rebuild");
+            continue;
+        }
+        build_layers(&pipeline, &activities)?;
+    }
+}
+
+/// The pipeline with the steps and layers selected on the command line.
+fn pipeline(cli: &Cli) -> Result<Pipeline, ()> {
+    let mut builder = Pipeline::builder()
+        .data_dir(&cli.data_dir)
+        .output(output(cli));
+    let cli = &cli.build;
+    builder = builder
+        .activities(cli.activity.iter().copied())
+        .exclude_activities(cli.exclude_activity.iter().copied())
+        .max_gap(cli.max_gap_m)
+        .export_tracks(cli.export_tracks.iter().copied(), cli.split_tracks)
+        .tiles(cli.tiles.iter().copied())
+        .h3(cli.h3.iter().copied());
+    if let Some(max_speed) = cli.max_speed {
+        builder = builder.max_speed(max_speed);
+    }
+    if let Some(spacing_m) = cli.resample {
+        builder = builder.resample(ResampleOptions {
+            spacing_m,
+            max_gap_m: cli.max_gap_m,
+            max_gap_time: Duration::seconds(cli.max_gap_s),
+        });
+    }
+    for &(kind, factor) in &cli.radius_factor {
+        builder = builder.radius_factor(kind, factor);
+    }
+    if let Some(osm_path) = &cli.map_match {
+        builder = builder.map_match(
+            osm_path,
+            MatchOptions {
+                search_radius_m: cli.match_radius,
+                gps_sigma_m: cli.match_gps_sigma,
+                beta_m: cli.match_beta,
+                max_candidates: cli.match_candidates,
+                spacing_m: 5.0,
+            },
+        );
+    }
+    if cli.raster {
+        builder = builder.raster(RasterOptions {
+            radius_px: cli.raster_radius,
+            ramp: cli.raster_ramp.clone(),
+            log_scale: cli.raster_log,
+            max_zoom: cli.raster_max_zoom,
+            pixel_size_m: cli.raster_pixel_m,
+        });
+    }
+    if cli.fog {
+        builder = builder.fog(FogOptions {
+            extent: cli
+                .fog_extent
+                .map(|extent| project_rect(extent, Projector))
+                .transpose()?,
+            tile_size_m: cli.fog_tile_size,
+        });
+    }
+    if cli.new_ground {
+        builder = builder.new_ground();
+    }
+    if let Some(period) = cli.period {
+        builder = builder.period(period);
+    }
+    if cli.report {
+        builder = builder.report(
+            cli.report_regions
+                .clone()
+                .map(|path| (path, cli.report_region_name.clone())),
+        );
+    }
+    Ok(builder.build())
+}
@@ -0,0 +1,544 @@
+use geo::{Coord, Distance, Euclidean, Line, LineString, Point};
+use rstar::RTree;
+use rstar::primitives::GeomWithData;
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap};
+
+use crate::measure::mercator_scale;
+use crate::osm::RoadNetwork;
+use crate::parsers::{Activity, TrackPoint};
+
+/// Distances are meters on the ground, stretched like EPSG_METERS at every point.
+#[derive(Clone, Debug)]
+pub struct MatchOptions {
+    /// Only segments this close to a point are candidates, e.g. 50.0
+    pub search_radius_m: f64,
+    /// Standard deviation of the GPS noise, e.g. 10.0
+    pub gps_sigma_m: f64,
+    /// How much a route may typically differ from the straight line between fixes, e.g. 30.0
+    pub beta_m: f64,
+    /// Candidates considered per point, e.g. 8
+    pub max_candidates: usize,
+    /// Distance between the points emitted along matched routes, e.g. 5.0
+    pub spacing_m: f64,
+}
+
+/// Hidden Markov map matching (Newson & Krumm, 2009) on a road network in EPSG_METERS.
+pub struct MapMatcher<'a> {
+    network: &'a RoadNetwork,
+    /// (from node, to node) of every segment
+    segments: Vec<(usize, usize)>,
+    /// neighbouring nodes of every node with the distance to them
+    adjacency: Vec<Vec<(usize, f64)>>,
+    tree: RTree<GeomWithData<Line, usize>>,
+    options: MatchOptions,
+}
+
+/// Possible position of a point on a segment.
+#[derive(Clone, Copy)]
+struct Candidate {
+    segment: usize,
+    point: Point,
+    /// distance along the segment from its from node
+    offset: f64,
+    /// distance to the recorded point
+    distance: f64,
+}
+
+/// Viterbi state of one point of a matched section.
+struct Step {
+    point_index: usize,
+    candidates: Vec<Candidate>,
+    /// best previous candidate for every candidate
+    back: Vec<usize>,
+}
+
+impl<'a> MapMatcher<'a> {
+    pub fn new(network: &'a RoadNetwork, options: MatchOptions) -> Self {
+        let segments: Vec<(usize, usize)> = network.segments().collect();
+
+        let mut adjacency = vec![Vec::new(); network.nodes.len()];
+        for &(from, to) in &segments {
+            let length = Euclidean.distance(network.nodes[from], network.nodes[to]);
+            adjacency[from].push((to, length));
+            adjacency[to].push((from, length));
+        }
+
+        let tree = RTree::bulk_load(
+            segments
+                .iter()
+                .enumerate()
+                .map(|(i, &(from, to))| {
+                    GeomWithData::new(Line::new(network.nodes[from], network.nodes[to]), i)
+                })
+                .collect(),
+        );
+
+        MapMatcher {
+            network,
+            segments,
+            adjacency,
+            tree,
+            options,
+        }
+    }
+
+    /// Snaps the points of an activity onto the network. Matched sections are replaced by points
+    /// every `spacing_m` along the matched route, points without nearby segments are kept.
+    /// Returns the matched routes.
+    pub fn match_activity(&self, activity: &mut Activity) -> Vec<LineString> {
+        let points = std::mem::take(&mut activity.points);
+        let mut output = Vec::with_capacity(points.len());
+        let mut lines = Vec::new();
+
+        let mut section: Vec<Step> = Vec::new();
+        let mut scores: Vec<f64> = Vec::new();
+
+        for (point_index, track_point) in points.iter().enumerate() {
+            let candidates = self.candidates(track_point.point);
+
+            if candidates.is_empty() {
+                self.finish_section(&points, &mut section, &scores, &mut output, &mut lines);
+                output.push(*track_point);
+                continue;
+            }
+
+            let sigma = self.options.gps_sigma_m * mercator_scale(track_point.point.y());
+            let emissions: Vec<f64> = candidates
+                .iter()
+                .map(|c| -0.5 * (c.distance / sigma).powi(2))
+                .collect();
+
+            if let Some(previous) = section.last() {
+                let (next_scores, back) = self.transition(
+                    &points[previous.point_index],
+                    track_point,
+                    &previous.candidates,
+                    &scores,
+                    &candidates,
+                );
+
+                // the route can't be followed, e.g. the extract ends, start over here
+                if next_scores.iter().all(|s| s.is_infinite()) {
+                    self.finish_section(&points, &mut section, &scores, &mut output, &mut lines);
+                    scores = emissions;
+                    section.push(Step {
+                        point_index,
+                        candidates,
+                        back: Vec::new(),
+                    });
+                } else {
+                    scores = next_scores
+                        .iter()
+                        .zip(&emissions)
+                        .map(|(score, emission)| score + emission)
+                        .collect();
+                    section.push(Step {
+                        point_index,
+                        candidates,
+                        back,
+                    });
+                }
+            } else {
+                scores = emissions;
+                section.push(Step {
+                    point_index,
+                    candidates,
+                    back: Vec::new(),
+                });
+            }
+        }
+
+        self.finish_section(&points, &mut section, &scores, &mut output, &mut lines);
+
+        activity.points = output;
+        lines
+    }
+
+    fn candidates(&self, point: Point) -> Vec<Candidate> {
+        let radius = self.options.search_radius_m * mercator_scale(point.y());
+        let mut candidates: Vec<Candidate> = self
+            .tree
+            .locate_within_distance(point, radius * radius)
+            .map(|segment| {
+                let (projected, offset) = project_onto(segment.geom(), point);
+                Candidate {
+                    segment: segment.data,
+                    point: projected,
+                    offset,
+                    distance: Euclidean.distance(point, projected),
+                }
+            })
+            .collect();
+
+        candidates.sort_by(|a, b| a.distance.total_cmp(&b.distance));
+        candidates.truncate(self.options.max_candidates);
+        candidates
+    }
+
+    /// Best score and predecessor for every candidate of the next point.
+    fn transition(
+        &self,
+        from: &TrackPoint,
+        to: &TrackPoint,
+        previous: &[Candidate],
+        scores: &[f64],
+        candidates: &[Candidate],
+    ) -> (Vec<f64>, Vec<usize>) {
+        let straight = Euclidean.distance(from.point, to.point);
+        let scale = mercator_scale(from.point.y());
+        let limit = self.route_limit(straight, scale);
+        let beta = self.options.beta_m * scale;
+
+        let mut best = vec![f64::NEG_INFINITY; candidates.len()];
+        let mut back = vec![0; candidates.len()];
+
+        for (k, (source, score)) in previous.iter().zip(scores).enumerate() {
+            if score.is_infinite() {
+                continue;
+            }
+            let paths = self.shortest_paths(source, limit);
+
+            for (j, target) in candidates.iter().enumerate() {
+                let Some((route, _)) = self.route_distance(source, target, &paths) else {
+                    continue;
+                };
+                let candidate_score = score - (route - straight).abs() / beta;
+                if candidate_score > best[j] {
+                    best[j] = candidate_score;
+                    back[j] = k;
+                }
+            }
+        }
+
+        (best, back)
+    }
+
+    /// Backtracks the most likely candidates of a section and emits its route.
+    fn finish_section(
+        &self,
+        points: &[TrackPoint],
+        section: &mut Vec<Step>,
+        scores: &[f64],
+        output: &mut Vec<TrackPoint>,
+        lines: &mut Vec<LineString>,
+    ) {
+        let steps = std::mem::take(section);
+
+        // a single point has no trace to follow, keep it where it was recorded
+        if steps.len() < 2 {
+            output.extend(steps.iter().map(|step| points[step.point_index]));
+            return;
+        }
+
+        let mut chosen = vec![0; steps.len()];
+        chosen[steps.len() - 1] = (0..scores.len())
+            .max_by(|&a, &b| scores[a].total_cmp(&scores[b]))
+            .unwrap_or(0);
+        for i in (1..steps.len()).rev() {
+            chosen[i - 1] = steps[i].back[chosen[i]];
+        }
+
+        let mut line: Vec<Coord> = Vec::new();
+
+        for i in 1..steps.len() {
+            let from = &points[steps[i - 1].point_index];
+            let to = &points[steps[i].point_index];
+            let source = &steps[i - 1].candidates[chosen[i - 1]];
+            let target = &steps[i].candidates[chosen[i]];
+
+            let scale = mercator_scale(from.point.y());
+            let route = self.route(
+                source,
+                target,
+                Euclidean.distance(from.point, to.point),
+                scale,
+            );
+            output.extend(
+                densify(&route, self.options.spacing_m * scale)
+                    .into_iter()
+                    .map(|(coord, ratio)| {
+                        TrackPoint::new(coord.into(), from.time_towards(to, ratio), from.kind)
+                    }),
+            );
+
+            // consecutive routes share their end and start
+            let skip = usize::from(!line.is_empty());
+            line.extend(route.into_iter().skip(skip));
+        }
+
+        let last_step = &steps[steps.len() - 1];
+        let last = &points[last_step.point_index];
+        output.push(TrackPoint::new(
+            last_step.candidates[chosen[steps.len() - 1]].point,
+            last.time,
+            last.kind,
+        ));
+
+        lines.push(LineString::new(line));
+    }
+
+    /// Coordinates of the shortest route between two candidates.
+    fn route(
+        &self,
+        source: &Candidate,
+        target: &Candidate,
+        straight: f64,
+        scale: f64,
+    ) -> Vec<Coord> {
+        let paths = self.shortest_paths(source, self.route_limit(straight, scale));
+        let Some((_, via)) = self.route_distance(source, target, &paths) else {
+            // unreachable can't happen for chosen candidates, fall back to a straight line
+            return vec![source.point.0, target.point.0];
+        };
+
+        let mut coords = vec![target.point.0];
+        let mut node = via;
+        while let Some(current) = node {
+            coords.push(self.network.nodes[current].0);
+            node = paths.get(&current).and_then(|&(_, previous)| previous);
+        }
+        coords.push(source.point.0);
+        coords.reverse();
+        coords
+    }
+
+    /// Length of the shortest route between two candidates and the node it enters the
+    /// target segment from, None if they are on the same segment.
+    fn route_distance(
+        &self,
+        source: &Candidate,
+        target: &Candidate,
+        paths: &HashMap<usize, (f64, Option<usize>)>,
+    ) -> Option<(f64, Option<usize>)> {
+        let mut best = None;
+
+        if source.segment == target.segment {
+            best = Some(((target.offset - source.offset).abs(), None));
+        }
+
+        let (from, to) = self.segments[target.segment];
+        let length = self.segment_length(target.segment);
+        for (node, rest) in [(from, target.offset), (to, length - target.offset)] {
+            if let Some(&(distance, _)) = paths.get(&node) {
+                let total = distance + rest;
+                if best.is_none_or(|(b, _)| total < b) {
+                    best = Some((total, Some(node)));
+                }
+            }
+        }
+
+        best
+    }
+
+    /// Dijkstra from both ends of a candidate's segment, up to `limit` meters.
+    /// Returns the distance and predecessor of every reached node.
+    fn shortest_paths(
+        &self,
+        source: &Candidate,
+        limit: f64,
+    ) -> HashMap<usize, (f64, Option<usize>)> {
+        let (from, to) = self.segments[source.segment];
+        let length = self.segment_length(source.segment);
+
+        let mut reached: HashMap<usize, (f64, Option<usize>)> = HashMap::new();
+        let mut queue = BinaryHeap::new();
+        for (node, cost) in [(from, source.offset), (to, length - source.offset)] {
+            queue.push(QueueEntry {
+                cost,
+                node,
+                previous: None,
+            });
+        }
+
+        while let Some(QueueEntry {
+            cost,
+            node,
+            previous,
+        }) = queue.pop()
+        {
+            if cost > limit || reached.contains_key(&node) {
+                continue;
+            }
+            reached.insert(node, (cost, previous));
+
+            for &(next, length) in &self.adjacency[node] {
+                if !reached.contains_key(&next) {
+                    queue.push(QueueEntry {
+                        cost: cost + length,
+                        node: next,
+                        previous: Some(node),
+                    });
+                }
+            }
+        }
+
+        reached
+    }
+
+    /// Routes much longer than the straight line are implausible and not searched.
+    /// `scale` is the mercator scale where the route starts.
+    fn route_limit(&self, straight: f64, scale: f64) -> f64 {
+        2.0 * straight + 4.0 * self.options.search_radius_m * scale
+    }
+
+    fn segment_length(&self, segment: usize) -> f64 {
+        let (from, to) = self.segments[segment];
+        Euclidean.distance(self.network.nodes[from], self.network.nodes[to])
+    }
+}
+
+/// Min-heap entry for Dijkstra.
+struct QueueEntry {
+    cost: f64,
+    node: usize,
+    previous: Option<usize>,
+}
+
+impl PartialEq for QueueEntry {
+    fn eq(&self, other: &Self) -> bool {
+        self.cost == other.cost
+    }
+}
+
+impl Eq for QueueEntry {}
+
+impl PartialOrd for QueueEntry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for QueueEntry {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // reversed, BinaryHeap is a max-heap
+        other.cost.total_cmp(&self.cost)
+    }
+}
+
+/// Closest point on a line and its distance from the line's start.
+fn project_onto(line: &Line, point: Point) -> (Point, f64) {
+    let delta = line.delta();
+    let length_squared = delta.x * delta.x + delta.y * delta.y;
+    if length_squared == 0.0 {
+        return (line.start.into(), 0.0);
+    }
+
+    let relative = point.0 - line.start;
+    let t = ((relative.x * delta.x + relative.y * delta.y) / length_squared).clamp(0.0, 1.0);
+    ((line.start + delta * t).into(), t * length_squared.sqrt())
+}
+
+/// Points every `spacing` along a polyline with their position as ratio of its length.
+/// The polyline's end is not included.
+fn densify(coords: &[Coord], spacing: f64) -> Vec<(Coord, f64)> {
+    let lengths: Vec<f64> = coords
+        .windows(2)
+        .map(|pair| Euclidean.distance(Point(pair[0]), Point(pair[1])))
+        .collect();
+    let total: f64 = lengths.iter().sum();
+    if total == 0.0 {
+        return vec![(coords[0], 0.0)];
+    }
+
+    let mut output = Vec::new();
+    let mut travelled = 0.0;
+    let mut next = 0.0;
+
+    for (pair, length) in coords.windows(2).zip(lengths) {
+        while next < travelled + length {
+            let t = (next - travelled) / length;
+            output.push((pair[0] + (pair[1] - pair[0]) * t, next / total));
+            next += spacing;
+        }
+        travelled += length;
+    }
+
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::activity::ActivityKind;
+    use crate::osm::Way;
+
+    fn options() -> MatchOptions {
+        MatchOptions {
+            search_radius_m: 50.0,
+            gps_sigma_m: 10.0,
+            beta_m: 30.0,
+            max_candidates: 8,
+            spacing_m: 5.0,
+        }
+    }
+
+    fn way(id: i64, nodes: Vec<usize>) -> Way {
+        Way {
+            id,
+            name: String::new(),
+            highway: "residential".to_string(),
+            nodes,
+        }
+    }
+
+    fn activity(coords: &[(f64, f64)]) -> Activity {
+        Activity {
+            name: None,
+            points: coords
+                .iter()
+                .map(|&(x, y)| TrackPoint::new(Point::new(x, y), None, ActivityKind::Walk))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn snaps_noisy_points_onto_a_straight_road() {
+        let network = RoadNetwork {
+            nodes: vec![Point::new(0.0, 0.0), Point::new(1000.0, 0.0)],
+            ways: vec![way(1, vec![0, 1])],
+        };
+        let matcher = MapMatcher::new(&network, options());
+        let mut activity = activity(&[(100.0, 8.0), (200.0, -6.0), (300.0, 12.0), (400.0, 3.0)]);
+
+        let lines = matcher.match_activity(&mut activity);
+
+        assert_eq!(lines.len(), 1);
+        assert!(activity.points.iter().all(|p| p.point.y().abs() < 1e-9));
+        let first = activity.points.first().unwrap().point.x();
+        let last = activity.points.last().unwrap().point.x();
+        assert!((first - 100.0).abs() < 1e-9 && (last - 400.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn stays_on_the_road_of_the_trace_next_to_a_parallel_one() {
+        // two roads 40m apart, only connected at their ends
+        let network = RoadNetwork {
+            nodes: vec![
+                Point::new(0.0, 0.0),
+                Point::new(1000.0, 0.0),
+                Point::new(0.0, 40.0),
+                Point::new(1000.0, 40.0),
+            ],
+            ways: vec![
+                way(1, vec![0, 1]),
+                way(2, vec![2, 3]),
+                way(3, vec![0, 2]),
+                way(4, vec![1, 3]),
+            ],
+        };
+        let matcher = MapMatcher::new(&network, options());
+        // the third fix is closer to the upper road
+        let mut activity = activity(&[
+            (300.0, 5.0),
+            (400.0, 8.0),
+            (500.0, 25.0),
+            (600.0, 6.0),
+            (700.0, 4.0),
+        ]);
+
+        matcher.match_activity(&mut activity);
+
+        assert!(activity.points.iter().all(|p| p.point.y().abs() < 1e-9));
+    }
+}
@@ -0,0 +1,59 @@
+use geo::{Coord, Euclidean, GeodesicArea, Length, LineString, MapCoords, MultiPolygon, Polygon};
+
+/// Semi-major axis of the spheroid EPSG_METERS projects onto.
+pub const EARTH_RADIUS_M: f64 = 6_378_137.0;
+
+/// Half the width of the world in EPSG_METERS.
+pub const HALF_WORLD_M: f64 = std::f64::consts::PI * EARTH_RADIUS_M;
+
+/// How many times a length at projected `y` is stretched by EPSG_METERS,
+/// i.e. 1 / cos(latitude).
+pub fn mercator_scale(y: f64) -> f64 {
+    (y / EARTH_RADIUS_M).cosh()
+}
+
+/// Length on the ground in meters of a line in EPSG_METERS.
+pub fn length_m(line: &LineString) -> f64 {
+    line.lines()
+        .map(|segment| {
+            let mid_y = (segment.start.y + segment.end.y) / 2.0;
+            Euclidean.length(&segment) / mercator_scale(mid_y)
+        })
+        .sum()
+}
+
+/// Inverse of EPSG_METERS, back to WGS84 degrees.
+pub fn to_wgs84(coord: Coord) -> Coord {
+    Coord {
+        x: (coord.x / EARTH_RADIUS_M).to_degrees(),
+        y: (coord.y / EARTH_RADIUS_M).sinh().atan().to_degrees(),
+    }
+}
+
+/// Length on the ground in meters of the outline of a polygon in EPSG_METERS,
+/// including its holes.
+pub fn perimeter_m(polygon: &Polygon) -> f64 {
+    std::iter::once(polygon.exterior())
+        .chain(polygon.interiors())
+        .map(length_m)
+        .sum()
+}
+
+/// Area on the ground in square meters of polygons in EPSG_METERS.
+pub fn area_m2(geometry: &MultiPolygon) -> f64 {
+    geometry.map_coords(to_wgs84).geodesic_area_unsigned()
+}
+
+/// km² rounded to three decimals
+pub fn round_km2(area_m2: f64) -> f64 {
+    (area_m2 / 1_000.0).round() / 1_000.0
+}
+
+/// Percentage rounded to two decimals, 0 for nothing
+pub fn percent(part: f64, total: f64) -> f64 {
+    if total > 0.0 {
+        (part / total * 10_000.0).round() / 100.0
+    } else {
+        0.0
+    }
+}
@@ -0,0 +1,301 @@
+use chrono::{DateTime, Utc};
+use geo::{Area, BoundingRect, MultiPolygon, Polygon};
+use geos::{GResult, Geom, Geometry};
+use indicatif::ParallelProgressIterator;
+use rayon::prelude::*;
+use rstar::RTree;
+use rstar::primitives::{GeomWithData, Rectangle};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashSet};
+
+use crate::buffer::{BufferGroup, buffer_points, explode_polygons};
+use crate::hashable_point::dedup_consecutive;
+use crate::io::{Output, pmtiles_filename, suffixed_path, write_features_to_flatgeobuf};
+use crate::layers::{
+    BUFFER_100_RADIUS_M, GeometryType, LayerEntry, LayerOptions, LayerSummary, union_rect,
+};
+use crate::measure::{area_m2, round_km2};
+use crate::parsers::Activity;
+use crate::pipeline::Error;
+use crate::tiles::visited_tiles;
+use crate::{NEW_GROUND_CSV_FILE, NEW_GROUND_FILE};
+
+/// Zoom level of the tiles counted as new, explorer tiles.
+const NEW_GROUND_TILE_ZOOM: u8 = 14;
+
+/// Pieces of new ground smaller than this many square meters in EPSG_METERS are
+/// slivers left between simplified buffers. They count as explored, but aren't reported.
+const MIN_PART_AREA_M2: f64 = 10.0;
+
+type IndexedRect = GeomWithData<Rectangle<[f64; 2]>, usize>;
+
+/// Ground an activity explored first, compared with all activities before it.
+#[derive(Serialize)]
+pub struct NewGroundRow {
+    /// Day the activity started, e.g. `2023-04-01`
+    pub date: String,
+    pub name: String,
+    pub kind: &'static str,
+    /// Area of its 100m buffer not covered by any earlier activity
+    pub new_km2: f64,
+    /// Explorer tiles no earlier activity passed through
+    pub new_tiles: usize,
+    /// Area explored by this and all earlier activities
+    pub explored_km2: f64,
+}
+
+/// A piece of new ground, with the time of the activity that explored it first so the UI
+/// can colour by date.
+#[derive(Serialize)]
+struct NewGroundFeature<'a> {
+    geo: Polygon,
+    name: &'a str,
+    date: &'a str,
+    /// Unix seconds the activity started at
+    time: i64,
+    area_m2: f64,
+}
+
+/// An activity with a timestamp, buffered and reduced to its tiles.
+struct BufferedActivity<'a> {
+    start: DateTime<Utc>,
+    name: String,
+    activity: &'a Activity,
+    buffer: Option<Geometry>,
+    tiles: HashSet<(u32, u32)>,
+}
+
+/// Goes through the timestamped activities in chronological order and cuts the 100m
+/// buffers of all earlier ones out of the buffer of every activity. Writes the table to
+/// `new_ground.csv` and the new pieces to `new_ground.fgb` in the output directory,
+/// suffixed with `suffix`. Returns the `layers.json` entry of the layer.
+pub fn build_new_ground(
+    activities: &[Activity],
+    options: &LayerOptions,
+    max_gap_m: f64,
+    suffix: &str,
+    output: &Output,
+) -> Result<LayerEntry, Error> {
+    let mut timed: Vec<(DateTime<Utc>, usize, &Activity)> = activities
+        .iter()
+        .enumerate()
+        .filter_map(|(index, activity)| {
+            let start = activity.points.iter().filter_map(|p| p.time).min()?;
+            Some((start, index, activity))
+        })
+        .collect();
+    if timed.len() < activities.len() {
+        progress!(
+            output.progress,
+            "Skipping {} activities without timestamps",
+            activities.len() - timed.len()
+        );
+    }
+    timed.sort_by_key(|&(start, index, _)| (start, index));
+
+    progress!(output.progress, "Buffering {} activities...", timed.len());
+    let bar = output.progress.bar(timed.len());
+    let buffered: Vec<BufferedActivity> = timed
+        .into_par_iter()
+        .progress_with(bar)
+        .map(|(start, index, activity)| {
+            Ok(BufferedActivity {
+                start,
+                name: activity
+                    .name
+                    .clone()
+                    .unwrap_or_else(|| format!("activity {}", index + 1)),
+                activity,
+                buffer: buffer_activity(activity, options)?,
+                tiles: visited_tiles(
+                    std::slice::from_ref(activity),
+                    NEW_GROUND_TILE_ZOOM,
+                    max_gap_m,
+                )
+                .tiles,
+            })
+        })
+        .collect::<GResult<_>>()
+        .map_err(|e| Error::Buffer(e.to_string()))?;
+
+    progress!(
+        output.progress,
+        "Comparing every activity with the ones before it..."
+    );
+    // every piece explored so far, including slivers
+    let mut explored: Vec<Polygon> = Vec::new();
+    let mut tree: RTree<IndexedRect> = RTree::new();
+    let mut seen_tiles = HashSet::new();
+    let mut explored_m2 = 0.0;
+    // the reported pieces and the index of the activity that explored them first
+    let mut pieces: Vec<Polygon> = Vec::new();
+    let mut pieces_by: Vec<usize> = Vec::new();
+    let mut rows = Vec::new();
+
+    for (row_index, activity) in buffered.iter().enumerate() {
+        let new_parts = match &activity.buffer {
+            Some(buffer) => {
+                new_parts(buffer, &explored, &tree).map_err(|e| Error::Buffer(e.to_string()))?
+            }
+            None => Vec::new(),
+        };
+        let new_pieces: Vec<Polygon> = new_parts
+            .iter()
+            .filter(|part| part.unsigned_area() >= MIN_PART_AREA_M2)
+            .cloned()
+            .collect();
+        let new_m2 = area_m2(&MultiPolygon::new(new_pieces.clone()));
+        explored_m2 += new_m2;
+        pieces_by.extend(std::iter::repeat_n(row_index, new_pieces.len()));
+        pieces.extend(new_pieces);
+        for part in new_parts {
+            if let Some(rect) = part.bounding_rect() {
+                let corners = Rectangle::from_corners(rect.min().into(), rect.max().into());
+                tree.insert(GeomWithData::new(corners, explored.len()));
+            }
+            explored.push(part);
+        }
+
+        let new_tiles = activity
+            .tiles
+            .iter()
+            .filter(|&&tile| seen_tiles.insert(tile))
+            .count();
+
+        rows.push(NewGroundRow {
+            date: activity.start.format("%Y-%m-%d").to_string(),
+            name: activity.name.clone(),
+            kind: activity
+                .activity
+                .points
+                .first()
+                .map_or("unknown", |p| p.kind.name()),
+            new_km2: round_km2(new_m2),
+            new_tiles,
+            explored_km2: round_km2(explored_m2),
+        });
+    }
+
+    let filename = write_new_ground(&rows, &buffered, &pieces, &pieces_by, suffix, output)?;
+    let summary = LayerSummary::new(
+        "pieces",
+        pieces.len(),
+        union_rect(pieces.iter().filter_map(|polygon| polygon.bounding_rect())),
+        pieces_by.iter().map(|&index| buffered[index].start),
+    );
+    Ok(LayerEntry::new(
+        "new_ground",
+        "New ground",
+        &filename,
+        GeometryType::Polygons,
+        &summary,
+    ))
+}
+
+/// The points of an activity buffered like the 100m buffer layer.
+fn buffer_activity(activity: &Activity, options: &LayerOptions) -> GResult<Option<Geometry>> {
+    // f64 isn't Ord, its bits are good enough to group identical factors
+    let mut by_factor: BTreeMap<u64, Vec<geo::Point>> = BTreeMap::new();
+    for track_point in dedup_consecutive(&activity.points) {
+        let factor = options.radius_factor(track_point.kind);
+        if factor > 0.0 {
+            by_factor
+                .entry(factor.to_bits())
+                .or_default()
+                .push(track_point.point);
+        }
+    }
+    let groups: Vec<BufferGroup> = by_factor
+        .iter()
+        .map(|(factor, points)| BufferGroup {
+            points,
+            radius_m: BUFFER_100_RADIUS_M * f64::from_bits(*factor),
+        })
+        .collect();
+    buffer_points(&groups, 8, Some(0.5))
+}
+
+/// Parts of `buffer` outside of the `explored` polygons, which don't overlap.
+fn new_parts(
+    buffer: &Geometry,
+    explored: &[Polygon],
+    tree: &RTree<IndexedRect>,
+) -> GResult<Vec<Polygon>> {
+    let Some(rect) = geo::Geometry::try_from(buffer)?.bounding_rect() else {
+        return Ok(Vec::new());
+    };
+    let envelope = rstar::AABB::from_corners(rect.min().into(), rect.max().into());
+    let covered: Vec<Geometry> = tree
+        .locate_in_envelope_intersecting(&envelope)
+        .map(|entry| Geometry::try_from(&explored[entry.data]))
+        .collect::<GResult<_>>()?;
+
+    let new = if covered.is_empty() {
+        Clone::clone(buffer)
+    } else {
+        // earlier pieces can share edges, dissolve them into a valid geometry
+        let covered = Geometry::create_geometry_collection(covered)?.unary_union()?;
+        buffer.difference(&covered)?
+    };
+
+    Ok(explode_polygons(new)?
+        .into_iter()
+        .filter_map(|part| match geo::Geometry::try_from(&part).ok()? {
+            geo::Geometry::Polygon(polygon) => Some(polygon),
+            _ => None,
+        })
+        .collect())
+}
+
+fn write_new_ground(
+    rows: &[NewGroundRow],
+    activities: &[BufferedActivity],
+    polygons: &[Polygon],
+    explored_by: &[usize],
+    suffix: &str,
+    output: &Output,
+) -> Result<String, Error> {
+    let csv_path = output.path(&suffixed_path(NEW_GROUND_CSV_FILE, suffix));
+    let write_rows = || -> csv::Result<()> {
+        let mut writer = csv::Writer::from_path(&csv_path)?;
+        for row in rows {
+            writer.serialize(row)?;
+        }
+        writer.flush()?;
+        Ok(())
+    };
+    write_rows().map_err(|e| Error::write(&csv_path, e))?;
+    progress!(
+        output.progress,
+        "✓ Wrote new ground of {} activities to {}",
+        rows.len(),
+        csv_path
+    );
+
+    let features: Vec<NewGroundFeature> = polygons
+        .par_iter()
+        .zip(explored_by)
+        .map(|(polygon, &index)| {
+            Ok(NewGroundFeature {
+                geo: output.project(polygon)?,
+                name: &rows[index].name,
+                date: &rows[index].date,
+                time: activities[index].start.timestamp(),
+                area_m2: area_m2(&MultiPolygon::new(vec![polygon.clone()])),
+            })
+        })
+        .collect::<Result<_, String>>()
+        .map_err(Error::Projection)?;
+
+    let path = output.path(&suffixed_path(NEW_GROUND_FILE, suffix));
+    progress!(output.progress, "Writing new ground to {}...", path);
+    write_features_to_flatgeobuf(&features, &path, output).map_err(|e| Error::write(&path, e))?;
+    progress!(
+        output.progress,
+        "✓ Successfully wrote {} pieces of new ground to {}",
+        features.len(),
+        path
+    );
+
+    pmtiles_filename(&path).map_err(|e| Error::write(&path, e))
+}
@@ -0,0 +1,316 @@
+use geo::{Contains, LineString, MultiPolygon, Point, Polygon};
+use osmpbf::{Element, ElementReader, RelMemberType};
+use rayon::prelude::*;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+use crate::crs::Projector;
+use crate::progress::Progress;
+
+/// `highway` values that aren't streets or paths one can travel along.
+const IGNORED_HIGHWAYS: &[&str] = &[
+    "proposed",
+    "construction",
+    "abandoned",
+    "disused",
+    "platform",
+    "raceway",
+    "bus_stop",
+    "elevator",
+    "rest_area",
+    "services",
+];
+
+/// Streets and paths of an OSM extract, projected to EPSG_METERS.
+pub struct RoadNetwork {
+    pub nodes: Vec<Point>,
+    pub ways: Vec<Way>,
+}
+
+pub struct Way {
+    pub id: i64,
+    /// `name` tag, empty if the way has none
+    pub name: String,
+    /// `highway` tag, e.g. `residential`
+    pub highway: String,
+    /// Indices into `RoadNetwork::nodes`
+    pub nodes: Vec<usize>,
+}
+
+/// A way as read in the first pass, before its nodes are resolved.
+struct RawWay {
+    id: i64,
+    name: String,
+    highway: String,
+    refs: Vec<i64>,
+}
+
+impl RoadNetwork {
+    /// Every segment between two consecutive nodes of a way, as (from node, to node).
+    pub fn segments(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        self.ways
+            .iter()
+            .flat_map(|way| way.nodes.windows(2).map(|pair| (pair[0], pair[1])))
+    }
+}
+
+/// Reads all `highway` ways from a `.osm.pbf` extract. Needs two passes over the file:
+/// one for the ways and one for the coordinates of their nodes.
+pub fn load_road_network(
+    path: &Path,
+    progress: Progress,
+    projector: Projector,
+) -> Result<RoadNetwork, Box<dyn std::error::Error>> {
+    progress!(progress, "Reading highways from {}...", path.display());
+
+    let mut raw_ways: Vec<RawWay> = Vec::new();
+    ElementReader::from_path(path)?.for_each(|element| {
+        if let Element::Way(way) = element {
+            let tag = |name: &str| {
+                way.tags()
+                    .find(|(key, _)| *key == name)
+                    .map(|(_, value)| value.to_string())
+            };
+            let Some(highway) = tag("highway") else {
+                return;
+            };
+            if IGNORED_HIGHWAYS.contains(&highway.as_str()) {
+                return;
+            }
+            raw_ways.push(RawWay {
+                id: way.id(),
+                name: tag("name").unwrap_or_default(),
+                highway,
+                refs: way.refs().collect(),
+            });
+        }
+    })?;
+
+    let needed: HashSet<i64> = raw_ways
+        .iter()
+        .flat_map(|way| way.refs.iter())
+        .copied()
+        .collect();
+    progress!(
+        progress,
+        "Found {} highways, reading {} nodes...",
+        raw_ways.len(),
+        needed.len()
+    );
+
+    let coordinates = read_node_coordinates(path, &needed)?;
+
+    // assign dense indices and project, ways of clipped extracts may miss nodes
+    let ids: Vec<i64> = coordinates.keys().copied().collect();
+    let index_of: HashMap<i64, usize> = ids.iter().enumerate().map(|(i, id)| (*id, i)).collect();
+    let nodes: Vec<Point> = ids
+        .par_iter()
+        .map(|id| projector.to_meters(coordinates[id]))
+        .collect::<Result<_, _>>()?;
+
+    let ways: Vec<Way> = raw_ways
+        .into_iter()
+        .map(|raw| Way {
+            id: raw.id,
+            name: raw.name,
+            highway: raw.highway,
+            nodes: raw
+                .refs
+                .iter()
+                .filter_map(|node_id| index_of.get(node_id).copied())
+                .collect(),
+        })
+        .filter(|way| way.nodes.len() >= 2)
+        .collect();
+
+    progress!(
+        progress,
+        "✓ Loaded {} highways with {} nodes",
+        ways.len(),
+        nodes.len()
+    );
+
+    Ok(RoadNetwork { nodes, ways })
+}
+
+/// An administrative boundary relation, in WGS84.
+pub struct AdminBoundary {
+    pub name: String,
+    pub admin_level: Option<String>,
+    pub geometry: MultiPolygon,
+}
+
+/// Outer and inner member ways of a boundary relation.
+struct RawBoundary {
+    name: String,
+    admin_level: Option<String>,
+    outer: Vec<i64>,
+    inner: Vec<i64>,
+}
+
+/// Reads all `boundary=administrative` relations from a `.osm.pbf` extract and assembles
+/// their member ways into polygons. Needs three passes over the file: relations, their
+/// ways and the coordinates of the nodes. Boundaries cut off by the extract are skipped.
+pub fn load_admin_boundaries(
+    path: &Path,
+    progress: Progress,
+) -> Result<Vec<AdminBoundary>, Box<dyn std::error::Error>> {
+    progress!(
+        progress,
+        "Reading admin boundaries from {}...",
+        path.display()
+    );
+
+    let mut raw_boundaries: Vec<RawBoundary> = Vec::new();
+    ElementReader::from_path(path)?.for_each(|element| {
+        if let Element::Relation(relation) = element {
+            let tag = |name: &str| {
+                relation
+                    .tags()
+                    .find(|(key, _)| *key == name)
+                    .map(|(_, value)| value.to_string())
+            };
+            if tag("boundary").as_deref() != Some("administrative") {
+                return;
+            }
+
+            let mut boundary = RawBoundary {
+                name: tag("name").unwrap_or_default(),
+                admin_level: tag("admin_level"),
+                outer: Vec::new(),
+                inner: Vec::new(),
+            };
+            for member in relation.members() {
+                if member.member_type != RelMemberType::Way {
+                    continue;
+                }
+                match member.role() {
+                    Ok("inner") => boundary.inner.push(member.member_id),
+                    // the role is optional for outer ways
+                    Ok("outer" | "") => boundary.outer.push(member.member_id),
+                    _ => {}
+                }
+            }
+            raw_boundaries.push(boundary);
+        }
+    })?;
+
+    let needed_ways: HashSet<i64> = raw_boundaries
+        .iter()
+        .flat_map(|boundary| boundary.outer.iter().chain(&boundary.inner))
+        .copied()
+        .collect();
+    progress!(
+        progress,
+        "Found {} boundaries, reading {} ways...",
+        raw_boundaries.len(),
+        needed_ways.len()
+    );
+
+    let mut way_refs: HashMap<i64, Vec<i64>> = HashMap::with_capacity(needed_ways.len());
+    ElementReader::from_path(path)?.for_each(|element| {
+        if let Element::Way(way) = element
+            && needed_ways.contains(&way.id())
+        {
+            way_refs.insert(way.id(), way.refs().collect());
+        }
+    })?;
+
+    let needed_nodes: HashSet<i64> = way_refs.values().flatten().copied().collect();
+    let coordinates = read_node_coordinates(path, &needed_nodes)?;
+
+    let boundaries: Vec<AdminBoundary> = raw_boundaries
+        .into_iter()
+        .filter_map(|boundary| {
+            let rings = |way_ids: &[i64]| -> Option<Vec<LineString>> {
+                let ways = way_ids
+                    .iter()
+                    .map(|id| way_refs.get(id).cloned())
+                    .collect::<Option<Vec<_>>>()?;
+                assemble_rings(ways)
+                    .into_iter()
+                    .map(|ring| {
+                        ring.iter()
+                            .map(|id| coordinates.get(id).copied())
+                            .collect::<Option<LineString>>()
+                    })
+                    .collect()
+            };
+            let outer = rings(&boundary.outer)?;
+            let mut inner = rings(&boundary.inner)?;
+
+            // every hole belongs to the outer ring that contains it
+            let polygons: Vec<Polygon> = outer
+                .into_iter()
+                .map(|exterior| {
+                    let shell = Polygon::new(exterior.clone(), Vec::new());
+                    let (holes, rest) =
+                        inner.drain(..).partition(|hole| shell.contains(&hole.0[0]));
+                    inner = rest;
+                    Polygon::new(exterior, holes)
+                })
+                .collect();
+            if polygons.is_empty() {
+                return None;
+            }
+
+            Some(AdminBoundary {
+                name: boundary.name,
+                admin_level: boundary.admin_level,
+                geometry: MultiPolygon::new(polygons),
+            })
+        })
+        .collect();
+
+    progress!(progress, "✓ Loaded {} admin boundaries", boundaries.len());
+    Ok(boundaries)
+}
+
+/// Coordinates of the `needed` nodes in WGS84.
+fn read_node_coordinates(
+    path: &Path,
+    needed: &HashSet<i64>,
+) -> Result<HashMap<i64, Point>, Box<dyn std::error::Error>> {
+    let mut coordinates: HashMap<i64, Point> = HashMap::with_capacity(needed.len());
+    ElementReader::from_path(path)?.for_each(|element| {
+        let (id, lon, lat) = match element {
+            Element::Node(node) => (node.id(), node.lon(), node.lat()),
+            Element::DenseNode(node) => (node.id(), node.lon(), node.lat()),
+            _ => return,
+        };
+        if needed.contains(&id) {
+            coordinates.insert(id, Point::new(lon, lat));
+        }
+    })?;
+    Ok(coordinates)
+}
+
+/// Joins ways that share end nodes into closed rings of node ids. Ways that
+/// can't be closed, e.g. because the extract cuts them off, are dropped.
+fn assemble_rings(mut ways: Vec<Vec<i64>>) -> Vec<Vec<i64>> {
+    let mut rings = Vec::new();
+
+    while let Some(mut ring) = ways.pop() {
+        while ring.len() > 1 && ring.first() != ring.last() {
+            let end = ring[ring.len() - 1];
+            let Some(next) = ways
+                .iter()
+                .position(|way| way.first() == Some(&end) || way.last() == Some(&end))
+            else {
+                break;
+            };
+
+            let mut way = ways.swap_remove(next);
+            if way.first() != Some(&end) {
+                way.reverse();
+            }
+            ring.extend_from_slice(&way[1..]);
+        }
+
+        if ring.len() >= 4 && ring.first() == ring.last() {
+            rings.push(ring);
+        }
+    }
+
+    rings
+}
@@ -1,4 +1,8 @@
-use super::Parser;
+use super::{Activity, Parser, TrackPoint, count_points, strava_csv};
+use crate::activity::ActivityKind;
+use crate::progress::Progress;
+use chrono::{DateTime, Utc};
+use fitparser::profile::MesgNum;
 use fitparser::{FitDataRecord, Value};
 use flate2::read::GzDecoder;
 use geo::Point;
@@ -7,16 +11,36 @@ use rayon::prelude::*;
 use std::fs::File;
 use std::io::Read;
 use std::path::Path;
+use std::sync::Arc;
 use walkdir::WalkDir;
 
 /**
  * Parse .fit.gz files, which I get from Strava for newer activities.
  */
-pub struct FitParser;
+pub struct FitParser {
+    strava_kinds: Arc<strava_csv::ActivityKinds>,
+}
+
+impl FitParser {
+    pub fn new(strava_kinds: Arc<strava_csv::ActivityKinds>) -> Self {
+        FitParser { strava_kinds }
+    }
+}
+
+fn is_fit_file(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| name.ends_with(".fit.gz"))
+}
 
 impl Parser for FitParser {
-    fn parse(&self, data_dir: &Path) -> Result<Vec<Point>, Box<dyn std::error::Error>> {
-        println!(
+    fn parse(
+        &self,
+        data_dir: &Path,
+        progress: Progress,
+    ) -> Result<Vec<Activity>, Box<dyn std::error::Error>> {
+        progress!(
+            progress,
             "Searching for .fit.gz files in {} directory...",
             data_dir.display()
         );
@@ -24,54 +48,60 @@ impl Parser for FitParser {
         let fit_files: Vec<_> = WalkDir::new(data_dir)
             .into_iter()
             .filter_map(|e| e.ok())
-            .filter(|entry| {
-                if !entry.file_type().is_file() {
-                    return false;
-                }
-
-                let path = entry.path();
-                let file_name = path
-                    .file_name()
-                    .and_then(|name| name.to_str())
-                    .unwrap_or("");
-
-                file_name.ends_with(".fit.gz")
-            })
+            .filter(|entry| entry.file_type().is_file() && is_fit_file(entry.path()))
             .collect();
 
-        println!("Found {} .fit.gz files", fit_files.len());
+        progress!(progress, "Found {} .fit.gz files", fit_files.len());
 
         if fit_files.is_empty() {
             return Ok(Vec::new());
         }
 
-        println!(
+        let strava_kinds = self.strava_kinds.load(data_dir, progress);
+
+        progress!(
+            progress,
             "Processing {} .fit.gz files in parallel...",
             fit_files.len()
         );
 
-        let all_points: Vec<Point> = fit_files
+        let bar = progress.bar(fit_files.len());
+        let activities: Vec<Activity> = fit_files
             .into_par_iter()
-            .progress()
+            .progress_with(bar)
             .filter_map(|entry| {
                 let file_path = entry.path();
+                let strava_kind = entry
+                    .file_name()
+                    .to_str()
+                    .and_then(|name| strava_kinds.get(name))
+                    .copied();
 
-                match extract_points_from_fit_gz(file_path) {
-                    Ok(points) => Some(points),
+                match extract_activity_from_fit_gz(file_path, strava_kind) {
+                    Ok(activity) => Some(activity),
                     Err(e) => {
-                        println!("✗ Error processing {}: {}", file_path.display(), e);
+                        progress!(
+                            progress,
+                            "✗ Error processing {}: {}",
+                            file_path.display(),
+                            e
+                        );
                         None
                     }
                 }
             })
-            .flatten()
             .collect();
 
-        println!(
+        progress!(
+            progress,
             "✓ Extracted {} total points from .fit.gz files",
-            all_points.len()
+            count_points(&activities)
         );
-        Ok(all_points)
+        Ok(activities)
+    }
+
+    fn handles(&self, path: &Path) -> bool {
+        is_fit_file(path) || strava_csv::is_activities_csv(path)
     }
 
     fn name(&self) -> &'static str {
@@ -79,7 +109,11 @@ impl Parser for FitParser {
     }
 }
 
-fn extract_points_from_fit_gz(file_path: &Path) -> Result<Vec<Point>, Box<dyn std::error::Error>> {
+/// `strava_kind` takes precedence over the sport recorded in the file.
+fn extract_activity_from_fit_gz(
+    file_path: &Path,
+    strava_kind: Option<ActivityKind>,
+) -> Result<Activity, Box<dyn std::error::Error>> {
     let file = File::open(file_path)?;
     let mut decoder = GzDecoder::new(file);
     let mut decompressed_data = Vec::new();
@@ -87,20 +121,50 @@ fn extract_points_from_fit_gz(file_path: &Path) -> Result<Vec<Point>, Box<dyn st
 
     let fit_file = fitparser::from_bytes(&decompressed_data)?;
 
+    // the sport is only known from session/sport messages, usually at the end of the file
+    let kind = strava_kind
+        .or_else(|| fit_file.iter().find_map(extract_sport_from_record))
+        .unwrap_or_default();
+
     let mut points = Vec::new();
 
     for record in fit_file.iter() {
-        if let Some(point) = extract_coordinates_from_record(record) {
+        if let Some(point) = extract_coordinates_from_record(record, kind) {
             points.push(point);
         }
     }
 
-    Ok(points)
+    Ok(Activity {
+        name: file_path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .map(|name| name.trim_end_matches(".fit.gz").to_string()),
+        points,
+    })
 }
 
-fn extract_coordinates_from_record(record: &FitDataRecord) -> Option<Point> {
+fn extract_sport_from_record(record: &FitDataRecord) -> Option<ActivityKind> {
+    if !matches!(record.kind(), MesgNum::Session | MesgNum::Sport) {
+        return None;
+    }
+
+    record
+        .fields()
+        .iter()
+        .find(|field| field.name() == "sport")
+        .and_then(|field| match field.value() {
+            Value::String(sport) => Some(ActivityKind::from_label(sport)),
+            _ => None,
+        })
+}
+
+fn extract_coordinates_from_record(
+    record: &FitDataRecord,
+    kind: ActivityKind,
+) -> Option<TrackPoint> {
     let mut latitude: Option<f64> = None;
     let mut longitude: Option<f64> = None;
+    let mut time: Option<DateTime<Utc>> = None;
 
     for field in record.fields() {
         match field.name() {
@@ -114,6 +178,11 @@ fn extract_coordinates_from_record(record: &FitDataRecord) -> Option<Point> {
                     longitude = Some(lon_value);
                 }
             }
+            "timestamp" => {
+                if let Value::Timestamp(timestamp) = field.value() {
+                    time = Some(timestamp.with_timezone(&Utc));
+                }
+            }
             _ => {} // ignore other fields
         }
     }
@@ -123,7 +192,11 @@ fn extract_coordinates_from_record(record: &FitDataRecord) -> Option<Point> {
         let lat_degrees = lat * (180.0 / 2_147_483_648.0);
         let lon_degrees = lon * (180.0 / 2_147_483_648.0);
 
-        Some(Point::new(lon_degrees, lat_degrees))
+        Some(TrackPoint::new(
+            Point::new(lon_degrees, lat_degrees),
+            time,
+            kind,
+        ))
     } else {
         None
     }
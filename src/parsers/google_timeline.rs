@@ -1,4 +1,7 @@
-use super::Parser;
+use super::{Activity, Parser, TrackPoint, count_points};
+use crate::activity::ActivityKind;
+use crate::progress::Progress;
+use chrono::{DateTime, Duration, Utc};
 use geo::Point;
 use rayon::prelude::*;
 use serde_json::Value;
@@ -6,18 +9,29 @@ use std::fs::File;
 use std::io::BufReader;
 use std::path::Path;
 
+const TIMELINE_FILE_NAME: &str = "location-history.json";
+
 pub struct GoogleTimelineParser;
 
 impl Parser for GoogleTimelineParser {
-    fn parse(&self, data_dir: &Path) -> Result<Vec<Point>, Box<dyn std::error::Error>> {
-        let timeline_path = data_dir.join("location-history.json");
+    fn parse(
+        &self,
+        data_dir: &Path,
+        progress: Progress,
+    ) -> Result<Vec<Activity>, Box<dyn std::error::Error>> {
+        let timeline_path = data_dir.join(TIMELINE_FILE_NAME);
 
         if !timeline_path.exists() {
-            println!("No location-history.json found in {}", data_dir.display());
+            progress!(
+                progress,
+                "No location-history.json found in {}",
+                data_dir.display()
+            );
             return Ok(Vec::new());
         }
 
-        println!(
+        progress!(
+            progress,
             "Parsing Google Timeline data from {}...",
             timeline_path.display()
         );
@@ -28,22 +42,25 @@ impl Parser for GoogleTimelineParser {
         let timeline_entries: Vec<Value> = serde_json::from_reader(reader)
             .map_err(|e| format!("Failed to parse Google Timeline JSON: {}", e))?;
 
-        let points: Result<Vec<Point>, String> = timeline_entries
+        let activities: Result<Vec<Activity>, String> = timeline_entries
             .into_par_iter()
-            .flat_map(|entry| extract_geo_strings_vec(&entry))
-            .map(|geo_str| {
-                parse_geo_string(&geo_str)
-                    .ok_or_else(|| format!("Failed to parse geo string '{}'", geo_str))
-            })
+            .map(|entry| extract_activity(&entry))
             .collect();
 
-        let points = points.map_err(|e| -> Box<dyn std::error::Error> { e.into() })?;
+        let mut activities = activities.map_err(|e| -> Box<dyn std::error::Error> { e.into() })?;
+        activities.retain(|activity| !activity.points.is_empty());
 
-        println!(
-            "✓ Extracted {} location points from Google Timeline",
-            points.len()
+        progress!(
+            progress,
+            "✓ Extracted {} location points in {} entries from Google Timeline",
+            count_points(&activities),
+            activities.len()
         );
-        Ok(points)
+        Ok(activities)
+    }
+
+    fn handles(&self, path: &Path) -> bool {
+        path.file_name() == Some(TIMELINE_FILE_NAME.as_ref())
     }
 
     fn name(&self) -> &'static str {
@@ -51,33 +68,123 @@ impl Parser for GoogleTimelineParser {
     }
 }
 
-fn extract_geo_strings_vec(value: &Value) -> Vec<String> {
+// every timeline entry (visit, activity or path) becomes one activity
+fn extract_activity(entry: &Value) -> Result<Activity, String> {
+    let mut points = extract_geo_strings_vec(entry)
+        .into_iter()
+        .map(|(geo_str, time, kind)| {
+            parse_geo_string(&geo_str)
+                .map(|point| TrackPoint::new(point, time, kind))
+                .ok_or_else(|| format!("Failed to parse geo string '{}'", geo_str))
+        })
+        .collect::<Result<Vec<TrackPoint>, String>>()?;
+
+    // object keys aren't in recording order, e.g. an activity's "end" comes before "start"
+    points.sort_by_key(|p| p.time);
+
+    Ok(Activity { name: None, points })
+}
+
+// raw "geo:" string with the time and activity of its timeline entry
+type TimedGeoString = (String, Option<DateTime<Utc>>, ActivityKind);
+
+fn extract_geo_strings_vec(value: &Value) -> Vec<TimedGeoString> {
     let mut geo_strings = Vec::new();
-    extract_geo_strings_recursive(value, &mut geo_strings);
+    extract_geo_strings_recursive(value, &EntryContext::default(), &mut geo_strings);
     geo_strings
 }
 
-fn extract_geo_strings_recursive(value: &Value, geo_strings: &mut Vec<String>) {
+// times and activity inherited from the enclosing timeline entry
+#[derive(Clone, Default)]
+struct EntryContext {
+    start: Option<DateTime<Utc>>,
+    end: Option<DateTime<Utc>>,
+    current: Option<DateTime<Utc>>,
+    kind: ActivityKind,
+}
+
+fn extract_geo_strings_recursive(
+    value: &Value,
+    context: &EntryContext,
+    geo_strings: &mut Vec<TimedGeoString>,
+) {
     match value {
-        Value::String(s) => {
-            if s.starts_with("geo:") {
-                geo_strings.push(s.clone());
-            }
+        Value::String(s) if s.starts_with("geo:") => {
+            geo_strings.push((s.clone(), context.current, context.kind));
         }
         Value::Object(map) => {
-            for (_, v) in map {
-                extract_geo_strings_recursive(v, geo_strings);
+            let context = object_entry_context(map, context);
+            for (k, v) in map {
+                // activities store their end location under "end"
+                if k == "end" && context.end.is_some() {
+                    let end_context = EntryContext {
+                        current: context.end,
+                        ..context.clone()
+                    };
+                    extract_geo_strings_recursive(v, &end_context, geo_strings);
+                } else {
+                    extract_geo_strings_recursive(v, &context, geo_strings);
+                }
             }
         }
         Value::Array(arr) => {
             for v in arr {
-                extract_geo_strings_recursive(v, geo_strings);
+                extract_geo_strings_recursive(v, context, geo_strings);
             }
         }
         _ => {} // ignore other value types
     }
 }
 
+fn object_entry_context(
+    map: &serde_json::Map<String, Value>,
+    parent: &EntryContext,
+) -> EntryContext {
+    let start = map.get("startTime").and_then(parse_time);
+    let end = map.get("endTime").and_then(parse_time);
+
+    // activity segments carry their most likely mode of transport
+    let kind = map
+        .get("activity")
+        .and_then(|activity| activity.pointer("/topCandidate/type"))
+        .and_then(|v| v.as_str())
+        .map(ActivityKind::from_label)
+        .unwrap_or(parent.kind);
+
+    if start.is_some() {
+        return EntryContext {
+            start,
+            end: end.or(parent.end),
+            current: start,
+            kind,
+        };
+    }
+
+    // timelinePath points are relative to the start of their entry
+    let offset_minutes = map
+        .get("durationMinutesOffsetFromStartTime")
+        .and_then(|v| v.as_str())
+        .and_then(|v| v.parse::<i64>().ok());
+
+    match (parent.start, offset_minutes) {
+        (Some(start), Some(minutes)) => EntryContext {
+            current: Some(start + Duration::minutes(minutes)),
+            kind,
+            ..parent.clone()
+        },
+        _ => EntryContext {
+            kind,
+            ..parent.clone()
+        },
+    }
+}
+
+fn parse_time(value: &Value) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(value.as_str()?)
+        .ok()
+        .map(|time| time.with_timezone(&Utc))
+}
+
 // geo:[lat],[lon]
 fn parse_geo_string(geo_str: &str) -> Option<Point> {
     if !geo_str.starts_with("geo:") {
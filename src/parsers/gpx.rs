@@ -1,19 +1,45 @@
-use super::Parser;
+use super::{Activity, Parser, TrackPoint, count_points, strava_csv};
+use crate::activity::ActivityKind;
+use crate::progress::Progress;
+use crate::tracks::is_track_export;
+use chrono::{DateTime, Utc};
 use flate2::read::GzDecoder;
-use geo::Point;
-use gpx::Gpx;
+use gpx::{Gpx, Waypoint};
 use indicatif::ParallelProgressIterator;
 use rayon::prelude::*;
 use std::fs::File;
 use std::io::BufReader;
 use std::path::Path;
+use std::sync::Arc;
 use walkdir::WalkDir;
 
-pub struct GpxParser;
+pub struct GpxParser {
+    strava_kinds: Arc<strava_csv::ActivityKinds>,
+}
+
+impl GpxParser {
+    pub fn new(strava_kinds: Arc<strava_csv::ActivityKinds>) -> Self {
+        GpxParser { strava_kinds }
+    }
+}
+
+fn is_gpx_file(path: &Path) -> bool {
+    let file_name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or("");
+
+    file_name.ends_with(".gpx") || file_name.ends_with(".gpx.gz")
+}
 
 impl Parser for GpxParser {
-    fn parse(&self, data_dir: &Path) -> Result<Vec<Point>, Box<dyn std::error::Error>> {
-        println!(
+    fn parse(
+        &self,
+        data_dir: &Path,
+        progress: Progress,
+    ) -> Result<Vec<Activity>, Box<dyn std::error::Error>> {
+        progress!(
+            progress,
             "Searching for .gpx and .gpx.gz files in {} directory...",
             data_dir.display()
         );
@@ -21,39 +47,48 @@ impl Parser for GpxParser {
         let gpx_files: Vec<_> = WalkDir::new(data_dir)
             .into_iter()
             .filter_map(|e| e.ok())
-            .filter(|entry| {
-                if !entry.file_type().is_file() {
-                    return false;
-                }
-
-                let path = entry.path();
-                let file_name = path
-                    .file_name()
-                    .and_then(|name| name.to_str())
-                    .unwrap_or("");
-
-                file_name.ends_with(".gpx") || file_name.ends_with(".gpx.gz")
-            })
+            .filter(|entry| entry.file_type().is_file() && is_gpx_file(entry.path()))
             .collect();
 
-        println!("Found {} GPX files (.gpx and .gpx.gz)", gpx_files.len());
+        progress!(
+            progress,
+            "Found {} GPX files (.gpx and .gpx.gz)",
+            gpx_files.len()
+        );
 
         if gpx_files.is_empty() {
             return Ok(Vec::new());
         }
 
-        println!("Processing {} GPX files in parallel...", gpx_files.len());
+        let strava_kinds = self.strava_kinds.load(data_dir, progress);
+
+        progress!(
+            progress,
+            "Processing {} GPX files in parallel...",
+            gpx_files.len()
+        );
 
-        let all_points: Vec<Point> = gpx_files
+        let bar = progress.bar(gpx_files.len());
+        let activities: Vec<Activity> = gpx_files
             .into_par_iter()
-            .progress()
+            .progress_with(bar)
             .filter_map(|entry| {
                 let file_path = entry.path();
+                let strava_kind = entry
+                    .file_name()
+                    .to_str()
+                    .and_then(|name| strava_kinds.get(name))
+                    .copied();
 
-                match extract_points_from_gpx(file_path) {
-                    Ok(points) => Some(points),
+                match extract_activities_from_gpx(file_path, strava_kind) {
+                    Ok(activities) => Some(activities),
                     Err(e) => {
-                        println!("✗ Error processing {}: {}", file_path.display(), e);
+                        progress!(
+                            progress,
+                            "✗ Error processing {}: {}",
+                            file_path.display(),
+                            e
+                        );
                         None
                     }
                 }
@@ -61,11 +96,17 @@ impl Parser for GpxParser {
             .flatten()
             .collect();
 
-        println!(
-            "✓ Extracted {} total points from GPX files",
-            all_points.len()
+        progress!(
+            progress,
+            "✓ Extracted {} total points in {} tracks from GPX files",
+            count_points(&activities),
+            activities.len()
         );
-        Ok(all_points)
+        Ok(activities)
+    }
+
+    fn handles(&self, path: &Path) -> bool {
+        is_gpx_file(path) || strava_csv::is_activities_csv(path)
     }
 
     fn name(&self) -> &'static str {
@@ -73,7 +114,12 @@ impl Parser for GpxParser {
     }
 }
 
-fn extract_points_from_gpx(file_path: &Path) -> Result<Vec<Point>, Box<dyn std::error::Error>> {
+/// Every track and route becomes an activity, loose waypoints are collected in another one.
+/// `strava_kind` takes precedence over the `<type>` of tracks and routes.
+fn extract_activities_from_gpx(
+    file_path: &Path,
+    strava_kind: Option<ActivityKind>,
+) -> Result<Vec<Activity>, Box<dyn std::error::Error>> {
     let file = File::open(file_path)?;
 
     let is_gzipped = file_path
@@ -91,25 +137,80 @@ fn extract_points_from_gpx(file_path: &Path) -> Result<Vec<Point>, Box<dyn std::
         gpx::read(reader)?
     };
 
-    let mut points = Vec::new();
+    // tracks exported by a previous run are not read again
+    if is_track_export(&gpx) {
+        return Ok(Vec::new());
+    }
+
+    let mut activities = Vec::new();
+
+    // e.g. `12345678` for `12345678.gpx.gz`
+    let file_name = file_path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .map(|name| {
+            name.trim_end_matches(".gz")
+                .trim_end_matches(".gpx")
+                .to_string()
+        });
+
+    let kind_of = |type_: &Option<String>| {
+        strava_kind
+            .or_else(|| type_.as_deref().map(ActivityKind::from_label))
+            .unwrap_or_default()
+    };
 
-    for waypoint in &gpx.waypoints {
-        points.push(waypoint.point());
+    if !gpx.waypoints.is_empty() {
+        activities.push(Activity {
+            name: gpx
+                .metadata
+                .as_ref()
+                .and_then(|metadata| metadata.name.clone())
+                .or_else(|| file_name.clone()),
+            points: gpx
+                .waypoints
+                .iter()
+                .map(|waypoint| to_track_point(waypoint, strava_kind.unwrap_or_default()))
+                .collect(),
+        });
     }
 
     for track in &gpx.tracks {
-        for segment in &track.segments {
-            for track_point in &segment.points {
-                points.push(track_point.point());
-            }
-        }
+        let kind = kind_of(&track.type_);
+        let points = track
+            .segments
+            .iter()
+            .flat_map(|segment| &segment.points)
+            .map(|track_point| to_track_point(track_point, kind))
+            .collect();
+        activities.push(Activity {
+            name: track.name.clone().or_else(|| file_name.clone()),
+            points,
+        });
     }
 
     for route in &gpx.routes {
-        for route_point in &route.points {
-            points.push(route_point.point());
-        }
+        let kind = kind_of(&route.type_);
+        activities.push(Activity {
+            name: route.name.clone().or_else(|| file_name.clone()),
+            points: route
+                .points
+                .iter()
+                .map(|route_point| to_track_point(route_point, kind))
+                .collect(),
+        });
     }
 
-    Ok(points)
+    Ok(activities)
+}
+
+fn to_track_point(waypoint: &Waypoint, kind: ActivityKind) -> TrackPoint {
+    TrackPoint::new(waypoint.point(), waypoint_time(waypoint), kind)
+}
+
+fn waypoint_time(waypoint: &Waypoint) -> Option<DateTime<Utc>> {
+    let formatted = waypoint.time?.format().ok()?;
+    DateTime::parse_from_rfc3339(&formatted)
+        .ok()
+        .map(|time| time.with_timezone(&Utc))
 }
@@ -1,13 +1,70 @@
 pub mod fit;
 pub mod google_timeline;
 pub mod gpx;
+pub mod strava_csv;
 
+use crate::activity::ActivityKind;
+use crate::progress::Progress;
+use chrono::{DateTime, Duration, Utc};
 use geo::Point;
 use std::path::Path;
 
-// extract Vec<Point> from different file types
+// a single recorded position, optionally with the time it was recorded at
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TrackPoint {
+    pub point: Point,
+    pub time: Option<DateTime<Utc>>,
+    pub kind: ActivityKind,
+    /// Index of the activity the point belongs to, set when activities are flattened
+    pub activity: usize,
+}
+
+impl TrackPoint {
+    pub fn new(point: Point, time: Option<DateTime<Utc>>, kind: ActivityKind) -> Self {
+        TrackPoint {
+            point,
+            time,
+            kind,
+            activity: 0,
+        }
+    }
+
+    /// Time at `ratio` of the way to `other`, if both points have one.
+    pub fn time_towards(&self, other: &TrackPoint, ratio: f64) -> Option<DateTime<Utc>> {
+        match (self.time, other.time) {
+            (Some(from), Some(to)) => {
+                let offset_ms = ((to - from).num_milliseconds() as f64 * ratio).round() as i64;
+                Some(from + Duration::milliseconds(offset_ms))
+            }
+            _ => self.time,
+        }
+    }
+}
+
+// a single recording, e.g. one GPX track, one FIT file or one timeline entry.
+// Points are in recording order.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Activity {
+    /// Name of the track or the file it was read from, if any
+    pub name: Option<String>,
+    pub points: Vec<TrackPoint>,
+}
+
+pub fn count_points(activities: &[Activity]) -> usize {
+    activities.iter().map(|a| a.points.len()).sum()
+}
+
+// extract Vec<Activity> from different file types
 pub trait Parser {
-    fn parse(&self, data_dir: &Path) -> Result<Vec<Point>, Box<dyn std::error::Error>>;
+    fn parse(
+        &self,
+        data_dir: &Path,
+        progress: Progress,
+    ) -> Result<Vec<Activity>, Box<dyn std::error::Error>>;
+
+    /// Whether the parser reads a file of the data directory, e.g. to re-run it when
+    /// the file changed
+    fn handles(&self, path: &Path) -> bool;
 
     fn name(&self) -> &'static str;
 }
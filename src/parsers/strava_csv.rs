@@ -0,0 +1,86 @@
+use crate::activity::ActivityKind;
+use crate::progress::Progress;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::OnceLock;
+use walkdir::WalkDir;
+
+/**
+ * Reads the `activities.csv` of a Strava bulk export, which knows the activity type
+ * of every exported file. Keyed by file name, e.g. `1234567.fit.gz`.
+ */
+pub fn load_activity_kinds(data_dir: &Path, progress: Progress) -> HashMap<String, ActivityKind> {
+    let mut kinds = HashMap::new();
+
+    let csv_files = WalkDir::new(data_dir)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|entry| entry.file_type().is_file() && is_activities_csv(entry.path()));
+
+    for entry in csv_files {
+        match read_activity_kinds(entry.path()) {
+            Ok(file_kinds) => kinds.extend(file_kinds),
+            Err(e) => progress!(
+                progress,
+                "✗ Error processing {}: {}",
+                entry.path().display(),
+                e
+            ),
+        }
+    }
+
+    kinds
+}
+
+/// Activity types of a Strava export, read on first use. Shared by the GPX and FIT
+/// parsers so the data directory is only searched for `activities.csv` once.
+#[derive(Default)]
+pub struct ActivityKinds(OnceLock<HashMap<String, ActivityKind>>);
+
+impl ActivityKinds {
+    pub fn load(&self, data_dir: &Path, progress: Progress) -> &HashMap<String, ActivityKind> {
+        self.0
+            .get_or_init(|| load_activity_kinds(data_dir, progress))
+    }
+}
+
+pub fn is_activities_csv(path: &Path) -> bool {
+    path.file_name() == Some("activities.csv".as_ref())
+}
+
+fn read_activity_kinds(
+    csv_path: &Path,
+) -> Result<HashMap<String, ActivityKind>, Box<dyn std::error::Error>> {
+    let mut reader = csv::Reader::from_path(csv_path)?;
+    let headers = reader.headers()?.clone();
+
+    let column = |name: &str| {
+        headers
+            .iter()
+            .position(|header| header == name)
+            .ok_or_else(|| format!("missing column '{}'", name))
+    };
+    let type_column = column("Activity Type")?;
+    let filename_column = column("Filename")?;
+
+    let mut kinds = HashMap::new();
+
+    for record in reader.records() {
+        let record = record?;
+        let (Some(activity_type), Some(filename)) =
+            (record.get(type_column), record.get(filename_column))
+        else {
+            continue;
+        };
+
+        // e.g. "activities/1234567.fit.gz", activities without GPS have no file
+        if let Some(file_name) = Path::new(filename).file_name().and_then(|n| n.to_str()) {
+            kinds.insert(
+                file_name.to_string(),
+                ActivityKind::from_label(activity_type),
+            );
+        }
+    }
+
+    Ok(kinds)
+}
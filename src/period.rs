@@ -0,0 +1,81 @@
+use chrono::{DateTime, Datelike, Utc};
+use clap::ValueEnum;
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+use crate::layers::LayerFiles;
+use crate::parsers::TrackPoint;
+use crate::progress::Progress;
+
+/// Calendar period used to split points into snapshots.
+#[derive(Clone, Copy, Debug, ValueEnum, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Period {
+    Year,
+    Month,
+}
+
+impl Period {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Period::Year => "year",
+            Period::Month => "month",
+        }
+    }
+
+    /// Key of the period a timestamp falls into, e.g. `2023` or `2023-04`.
+    /// Keys sort chronologically.
+    pub fn key(&self, time: &DateTime<Utc>) -> String {
+        match self {
+            Period::Year => format!("{:04}", time.year()),
+            Period::Month => format!("{:04}-{:02}", time.year(), time.month()),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct PeriodManifest {
+    pub period: Period,
+    pub periods: Vec<PeriodEntry>,
+}
+
+#[derive(Serialize)]
+pub struct PeriodEntry {
+    pub key: String,
+    pub point_count: usize,
+    /// Layers built from the points of this period only.
+    pub layers: LayerFiles,
+    /// Layers built from all points up to and including this period.
+    pub cumulative: LayerFiles,
+}
+
+/// Groups points by the period they were recorded in, in chronological order.
+/// Points without a timestamp can't be assigned and are skipped.
+pub fn split_by_period(
+    points: &[TrackPoint],
+    period: Period,
+    progress: Progress,
+) -> BTreeMap<String, Vec<TrackPoint>> {
+    let mut by_period: BTreeMap<String, Vec<TrackPoint>> = BTreeMap::new();
+    let mut untimed = 0;
+
+    for track_point in points {
+        match &track_point.time {
+            Some(time) => by_period
+                .entry(period.key(time))
+                .or_default()
+                .push(*track_point),
+            None => untimed += 1,
+        }
+    }
+
+    if untimed > 0 {
+        progress!(
+            progress,
+            "Skipping {} points without timestamp for per-period layers",
+            untimed
+        );
+    }
+
+    by_period
+}
@@ -0,0 +1,831 @@
+use chrono::Utc;
+use geo::{BoundingRect, Geometry as GeoGeometry, LineString, Polygon};
+use indicatif::ParallelProgressIterator;
+use rayon::prelude::*;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::activity::ActivityKind;
+use crate::buffer::{BufferGroup, FogOptions, build_buffered_geometries};
+use crate::crs::OutputCrs;
+use crate::export::ExportRule;
+use crate::filter::exclude_fast_segments;
+use crate::h3_cells::build_h3_layers;
+use crate::hashable_point::{VisitedCell, sanitize};
+use crate::io::{
+    Output, pmtiles_filename, suffixed_path, write_cells_to_flatgeobuf, write_json,
+    write_lines_to_flatgeobuf, write_polygons_to_flatgeobuf,
+};
+use crate::layers::{
+    GeometryType, InitialView, LayerEntry, LayerManifest, LayerOptions, LayerSummary, build_layers,
+    group_by_radius_factor, union_rect,
+};
+use crate::map_match::{MapMatcher, MatchOptions};
+use crate::new_ground::build_new_ground;
+use crate::osm::load_road_network;
+use crate::parsers::{
+    Activity, Parser, TrackPoint, count_points, fit::FitParser,
+    google_timeline::GoogleTimelineParser, gpx::GpxParser, strava_csv::ActivityKinds,
+};
+use crate::period::{Period, PeriodEntry, PeriodManifest, split_by_period};
+use crate::progress::Progress;
+use crate::raster::RasterOptions;
+use crate::regions::load_regions;
+use crate::report::{ReportOptions, SourceStats, distance_m, write_report};
+use crate::resample::{ResampleOptions, resample};
+use crate::tiles::build_tiles;
+use crate::tracks::{TrackExportOptions, TrackFormat, export_tracks};
+use crate::{DATA_DIR, LAYER_MANIFEST_FILE, MATCHED_FILE, PERIOD_MANIFEST_FILE, TRACKS_FILE};
+
+/// Errors of the pipeline steps.
+#[derive(Debug)]
+pub enum Error {
+    /// A parser couldn't read the data directory
+    Parse {
+        parser: &'static str,
+        message: String,
+    },
+    /// An input other than the data directory couldn't be read, e.g. an OSM extract
+    Read { path: String, message: String },
+    /// A point couldn't be transformed to EPSG_METERS
+    Projection(String),
+    /// GEOS failed to buffer or dissolve points
+    Buffer(String),
+    /// A layer couldn't be written
+    Write { path: String, message: String },
+    /// An option is out of range, e.g. an H3 resolution
+    Option(String),
+}
+
+impl Error {
+    /// `path` couldn't be read because of `error`
+    pub fn read(path: &Path, error: impl fmt::Display) -> Self {
+        Error::Read {
+            path: path.display().to_string(),
+            message: error.to_string(),
+        }
+    }
+
+    /// `path` couldn't be written because of `error`
+    pub fn write(path: &str, error: impl fmt::Display) -> Self {
+        Error::Write {
+            path: path.to_string(),
+            message: error.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Parse { parser, message } => write!(f, "{} failed: {}", parser, message),
+            Error::Read { path, message } => write!(f, "reading {} failed: {}", path, message),
+            Error::Projection(message) => write!(f, "projection failed: {}", message),
+            Error::Buffer(message) => write!(f, "buffering failed: {}", message),
+            Error::Write { path, message } => write!(f, "writing {} failed: {}", path, message),
+            Error::Option(message) => write!(f, "invalid option: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// The GPX (with Strava exports), Google Timeline and FIT parsers. The GPX and FIT
+/// parsers share the activity types of the Strava export, new parsers read them again.
+pub fn default_parsers() -> Vec<Box<dyn Parser>> {
+    let strava_kinds = Arc::new(ActivityKinds::default());
+    vec![
+        Box::new(GpxParser::new(strava_kinds.clone())),
+        Box::new(GoogleTimelineParser),
+        Box::new(FitParser::new(strava_kinds)),
+    ]
+}
+
+/// Dissolved buffer polygons of all points, in EPSG_METERS.
+pub struct Coverage {
+    /// Radius before radius factors
+    pub radius_m: f64,
+    pub polygons: Vec<Polygon>,
+}
+
+/// Configures a [`Pipeline`], by default reading `data` with all parsers and without any
+/// filtering, and building only the points, buffer and heatmap layers into `data` and
+/// `ui/public` without printing progress.
+pub struct PipelineBuilder {
+    data_dir: PathBuf,
+    output: Output,
+    parsers: Vec<Box<dyn Parser>>,
+    max_speed_kmh: Option<f64>,
+    resample: Option<ResampleOptions>,
+    include: Vec<ActivityKind>,
+    exclude: Vec<ActivityKind>,
+    radius_factors: HashMap<ActivityKind, f64>,
+    max_gap_m: f64,
+    map_match: Option<(PathBuf, MatchOptions)>,
+    track_formats: Vec<TrackFormat>,
+    split_tracks: bool,
+    tiles: Vec<u8>,
+    h3: Vec<u8>,
+    raster: Option<RasterOptions>,
+    fog: Option<FogOptions>,
+    new_ground: bool,
+    period: Option<Period>,
+    report: Option<Option<(PathBuf, String)>>,
+}
+
+impl Default for PipelineBuilder {
+    fn default() -> Self {
+        PipelineBuilder {
+            data_dir: PathBuf::from(DATA_DIR),
+            output: Output::default(),
+            parsers: default_parsers(),
+            max_speed_kmh: None,
+            resample: None,
+            include: Vec::new(),
+            exclude: Vec::new(),
+            radius_factors: HashMap::new(),
+            max_gap_m: 500.0,
+            map_match: None,
+            track_formats: Vec::new(),
+            split_tracks: false,
+            tiles: Vec::new(),
+            h3: Vec::new(),
+            raster: None,
+            fog: None,
+            new_ground: false,
+            period: None,
+            report: None,
+        }
+    }
+}
+
+impl PipelineBuilder {
+    /// Directory the parsers read, `data` by default
+    pub fn data_dir(mut self, data_dir: impl Into<PathBuf>) -> Self {
+        self.data_dir = data_dir.into();
+        self
+    }
+
+    /// Replaces all output settings at once
+    pub fn output(mut self, output: Output) -> Self {
+        self.output = output;
+        self
+    }
+
+    /// Directory the layers, tables and reports are written to, `data` by default
+    pub fn out_dir(mut self, out_dir: impl Into<PathBuf>) -> Self {
+        self.output.dir = out_dir.into();
+        self
+    }
+
+    /// Directory the PMTiles and manifests for the UI are written to, `ui/public` by
+    /// default
+    pub fn ui_dir(mut self, ui_dir: impl Into<PathBuf>) -> Self {
+        self.output.ui_dir = ui_dir.into();
+        self
+    }
+
+    /// CRS the FlatGeobuf layers are written in, WGS84 by default
+    pub fn crs(mut self, crs: OutputCrs) -> Self {
+        self.output.crs = crs;
+        self
+    }
+
+    /// Also exports the layers matching a rule to its format
+    pub fn export(mut self, rules: impl IntoIterator<Item = ExportRule>) -> Self {
+        self.output.export.extend(rules);
+        self
+    }
+
+    /// Prints the progress of every step
+    pub fn verbose(mut self, verbose: bool) -> Self {
+        self.output.progress = Progress::new(verbose);
+        self
+    }
+
+    /// Replaces the parsers, e.g. to only read GPX files
+    pub fn parsers(mut self, parsers: Vec<Box<dyn Parser>>) -> Self {
+        self.parsers = parsers;
+        self
+    }
+
+    /// Drops segments travelled faster than this many km/h, e.g. by train or plane
+    pub fn max_speed(mut self, max_speed_kmh: f64) -> Self {
+        self.max_speed_kmh = Some(max_speed_kmh);
+        self
+    }
+
+    pub fn resample(mut self, options: ResampleOptions) -> Self {
+        self.resample = Some(options);
+        self
+    }
+
+    /// Only keeps points of these activity types
+    pub fn activities(mut self, kinds: impl IntoIterator<Item = ActivityKind>) -> Self {
+        self.include.extend(kinds);
+        self
+    }
+
+    /// Drops points of these activity types
+    pub fn exclude_activities(mut self, kinds: impl IntoIterator<Item = ActivityKind>) -> Self {
+        self.exclude.extend(kinds);
+        self
+    }
+
+    /// Scales the buffer radius of an activity type, 0 leaves it out of the buffers
+    pub fn radius_factor(mut self, kind: ActivityKind, factor: f64) -> Self {
+        self.radius_factors.insert(kind, factor);
+        self
+    }
+
+    /// Points further apart than this many meters aren't connected when tracing tiles,
+    /// exporting tracks or measuring distances, 500 by default
+    pub fn max_gap(mut self, max_gap_m: f64) -> Self {
+        self.max_gap_m = max_gap_m;
+        self
+    }
+
+    /// Snaps activities onto the streets and paths of an `.osm.pbf` extract
+    pub fn map_match(mut self, osm_path: impl Into<PathBuf>, options: MatchOptions) -> Self {
+        self.map_match = Some((osm_path.into(), options));
+        self
+    }
+
+    /// Writes the cleaned activities as tracks, one file per activity if `split`
+    pub fn export_tracks(
+        mut self,
+        formats: impl IntoIterator<Item = TrackFormat>,
+        split: bool,
+    ) -> Self {
+        self.track_formats.extend(formats);
+        self.split_tracks = split;
+        self
+    }
+
+    /// Counts visited slippy map tiles at these zoom levels
+    pub fn tiles(mut self, zooms: impl IntoIterator<Item = u8>) -> Self {
+        self.tiles.extend(zooms);
+        self
+    }
+
+    /// Aggregates points into H3 cells at these resolutions
+    pub fn h3(mut self, resolutions: impl IntoIterator<Item = u8>) -> Self {
+        self.h3.extend(resolutions);
+        self
+    }
+
+    /// Also renders the heatmap to raster outputs
+    pub fn raster(mut self, options: RasterOptions) -> Self {
+        self.raster = Some(options);
+        self
+    }
+
+    /// Also writes the fog, the complement of the 100m buffers
+    pub fn fog(mut self, options: FogOptions) -> Self {
+        self.fog = Some(options);
+        self
+    }
+
+    /// Works out the ground every activity explored first
+    pub fn new_ground(mut self) -> Self {
+        self.new_ground = true;
+        self
+    }
+
+    /// Additionally builds layers per calendar period, plus cumulative ones
+    pub fn period(mut self, period: Period) -> Self {
+        self.period = Some(period);
+        self
+    }
+
+    /// Summarizes the run in a report, listing the most explored of the regions in
+    /// `regions` if given, named by their `name_property`
+    pub fn report(mut self, regions: Option<(PathBuf, String)>) -> Self {
+        self.report = Some(regions);
+        self
+    }
+
+    pub fn build(self) -> Pipeline {
+        Pipeline {
+            data_dir: self.data_dir,
+            parsers: self.parsers,
+            output: self.output,
+            max_speed_kmh: self.max_speed_kmh,
+            resample: self.resample,
+            include: self.include,
+            exclude: self.exclude,
+            layer_options: LayerOptions {
+                radius_factors: self.radius_factors,
+                raster: self.raster,
+                fog: self.fog,
+            },
+            max_gap_m: self.max_gap_m,
+            map_match: self.map_match,
+            track_export: (!self.track_formats.is_empty()).then_some(TrackExportOptions {
+                formats: self.track_formats,
+                split: self.split_tracks,
+                max_gap_m: self.max_gap_m,
+            }),
+            tiles: self.tiles,
+            h3: self.h3,
+            new_ground: self.new_ground,
+            period: self.period,
+            report: self.report,
+        }
+    }
+}
+
+/// Reads, cleans, sanitizes and buffers location history step by step:
+///
+/// ```no_run
+/// use point_cloud::pipeline::Pipeline;
+///
+/// let pipeline = Pipeline::builder().data_dir("exports").max_speed(200.0).build();
+/// let mut activities = pipeline.read()?;
+/// pipeline.clean(&mut activities)?;
+/// pipeline.select(&mut activities);
+/// let coverage = pipeline.buffer(&activities, 50.0)?;
+/// # Ok::<(), point_cloud::pipeline::Error>(())
+/// ```
+///
+/// or builds all configured layers at once, the way the CLI does:
+///
+/// ```no_run
+/// use point_cloud::pipeline::Pipeline;
+///
+/// let pipeline = Pipeline::builder().tiles([14]).new_ground().build();
+/// let activities = pipeline.read()?;
+/// pipeline.build_layers(Vec::new(), activities)?;
+/// # Ok::<(), point_cloud::pipeline::Error>(())
+/// ```
+pub struct Pipeline {
+    data_dir: PathBuf,
+    parsers: Vec<Box<dyn Parser>>,
+    output: Output,
+    max_speed_kmh: Option<f64>,
+    resample: Option<ResampleOptions>,
+    include: Vec<ActivityKind>,
+    exclude: Vec<ActivityKind>,
+    layer_options: LayerOptions,
+    max_gap_m: f64,
+    map_match: Option<(PathBuf, MatchOptions)>,
+    track_export: Option<TrackExportOptions>,
+    tiles: Vec<u8>,
+    h3: Vec<u8>,
+    new_ground: bool,
+    period: Option<Period>,
+    report: Option<Option<(PathBuf, String)>>,
+}
+
+impl Pipeline {
+    pub fn builder() -> PipelineBuilder {
+        PipelineBuilder::default()
+    }
+
+    pub fn data_dir(&self) -> &Path {
+        &self.data_dir
+    }
+
+    pub fn parsers(&self) -> &[Box<dyn Parser>] {
+        &self.parsers
+    }
+
+    /// Where and how the layers are written
+    pub fn output(&self) -> &Output {
+        &self.output
+    }
+
+    /// Runs every parser on the data directory, WGS84 activities in the order of the
+    /// parsers.
+    pub fn read(&self) -> Result<Vec<Activity>, Error> {
+        let mut activities = Vec::new();
+        for parser in &self.parsers {
+            let parsed = parser
+                .parse(&self.data_dir, self.output.progress)
+                .map_err(|e| Error::Parse {
+                    parser: parser.name(),
+                    message: e.to_string(),
+                })?;
+            activities.extend(parsed);
+        }
+        Ok(activities)
+    }
+
+    /// Drops fast segments, resamples and projects parsed activities to EPSG_METERS.
+    pub fn clean(&self, activities: &mut [Activity]) -> Result<(), Error> {
+        if let Some(max_speed) = self.max_speed_kmh {
+            progress!(
+                self.output.progress,
+                "Excluding segments faster than {} km/h...",
+                max_speed
+            );
+            let removed: usize = activities
+                .par_iter_mut()
+                .map(|activity| exclude_fast_segments(activity, max_speed))
+                .sum();
+            progress!(
+                self.output.progress,
+                "Removed {} points of fast segments",
+                removed
+            );
+        }
+
+        if let Some(options) = &self.resample {
+            progress!(
+                self.output.progress,
+                "Resampling activities to one point every {}m...",
+                options.spacing_m
+            );
+            activities
+                .par_iter_mut()
+                .for_each(|activity| resample(activity, options));
+            progress!(
+                self.output.progress,
+                "Resampled to {} points",
+                count_points(activities)
+            );
+        }
+
+        progress!(self.output.progress, "Transforming coordinates...");
+
+        // every rayon worker projects with its own PROJ instance
+        let projector = self.output.projector;
+        activities
+            .par_iter_mut()
+            .flat_map(|activity| activity.points.par_iter_mut())
+            .try_for_each(|track_point| {
+                track_point.point = projector
+                    .to_meters(track_point.point)
+                    .map_err(Error::Projection)?;
+                Ok(())
+            })?;
+
+        progress!(
+            self.output.progress,
+            "Successfully transformed {} points",
+            count_points(activities)
+        );
+        Ok(())
+    }
+
+    /// Keeps the points of the selected activity types.
+    pub fn select(&self, activities: &mut [Activity]) {
+        if !self.exclude.is_empty() {
+            for activity in activities.iter_mut() {
+                activity.points.retain(|p| !self.exclude.contains(&p.kind));
+            }
+            progress!(
+                self.output.progress,
+                "Kept {} points after excluding activity types {:?}",
+                count_points(activities),
+                self.exclude
+            );
+        }
+
+        if !self.include.is_empty() {
+            for activity in activities.iter_mut() {
+                activity.points.retain(|p| self.include.contains(&p.kind));
+            }
+            progress!(
+                self.output.progress,
+                "Kept {} points of activity types {:?}",
+                count_points(activities),
+                self.include
+            );
+        }
+    }
+
+    /// Rounds the points of projected activities to 10m cells.
+    pub fn sanitize(&self, activities: &[Activity]) -> Vec<VisitedCell> {
+        sanitize(&points_of(activities), self.output.progress).0
+    }
+
+    /// Buffers the points of projected activities with `radius_m`, scaled by the radius
+    /// factors of their types, and dissolves the buffers.
+    pub fn buffer(&self, activities: &[Activity], radius_m: f64) -> Result<Coverage, Error> {
+        let groups = group_by_radius_factor(
+            &points_of(activities),
+            &self.layer_options,
+            self.output.progress,
+        );
+        let buffer_groups: Vec<BufferGroup> = groups
+            .iter()
+            .map(|(factor, points)| BufferGroup {
+                points,
+                radius_m: radius_m * factor,
+            })
+            .collect();
+        let geometries =
+            build_buffered_geometries(&buffer_groups, 8, 1_000, Some(0.5), self.output.progress)
+                .map_err(|e| Error::Buffer(e.to_string()))?;
+
+        let polygons = geometries
+            .iter()
+            .map(|geometry| match GeoGeometry::try_from(geometry) {
+                Ok(GeoGeometry::Polygon(polygon)) => Ok(polygon),
+                Ok(_) => Err(Error::Buffer("expected polygons".to_string())),
+                Err(e) => Err(Error::Buffer(e.to_string())),
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Coverage { radius_m, polygons })
+    }
+
+    /// Cleans and selects the parsed WGS84 activities and builds every configured layer
+    /// from them, followed by `layers.json`. `sources` are only reported. Returns the
+    /// manifest, `None` if no points are left to build layers from.
+    pub fn build_layers(
+        &self,
+        sources: Vec<SourceStats>,
+        mut activities: Vec<Activity>,
+    ) -> Result<Option<LayerManifest>, Error> {
+        progress!(self.output.progress, "\n--- Summary ---");
+        progress!(
+            self.output.progress,
+            "Collected {} total points in {} activities from all parsers",
+            count_points(&activities),
+            activities.len()
+        );
+
+        self.clean(&mut activities)?;
+        if count_points(&activities) == 0 {
+            progress!(self.output.progress, "No points to process.");
+            return Ok(None);
+        }
+
+        // keep filtered layers apart from the full ones, e.g. out_walk_run.fgb
+        let suffix: String = self
+            .include
+            .iter()
+            .map(|kind| format!("_{}", kind.name()))
+            .collect();
+
+        let matched_entry = match &self.map_match {
+            Some((osm_path, options)) => Some(match_activities(
+                &mut activities,
+                osm_path,
+                options,
+                &suffix,
+                &self.output,
+            )?),
+            None => None,
+        };
+
+        self.select(&mut activities);
+        if count_points(&activities) == 0 {
+            progress!(self.output.progress, "No points to process.");
+            return Ok(None);
+        }
+
+        if let Some(options) = &self.track_export {
+            progress!(self.output.progress, "\n--- Tracks ---");
+            export_tracks(&activities, options, &suffix, &self.output).map_err(|e| {
+                Error::write(&self.output.path(&format!("{}{}", TRACKS_FILE, suffix)), e)
+            })?;
+        }
+
+        let mut tile_entries = Vec::new();
+        if !self.tiles.is_empty() {
+            progress!(self.output.progress, "\n--- Tiles ---");
+            tile_entries = build_tiles(
+                &activities,
+                &self.tiles,
+                self.max_gap_m,
+                &suffix,
+                &self.output,
+            )?;
+        }
+
+        let mut h3_entries = Vec::new();
+        if !self.h3.is_empty() {
+            progress!(self.output.progress, "\n--- H3 cells ---");
+            h3_entries = build_h3_layers(&activities, &self.h3, &suffix, &self.output)?;
+        }
+
+        let mut new_ground_entry = None;
+        if self.new_ground {
+            progress!(self.output.progress, "\n--- New ground ---");
+            new_ground_entry = Some(build_new_ground(
+                &activities,
+                &self.layer_options,
+                self.max_gap_m,
+                &suffix,
+                &self.output,
+            )?);
+        }
+
+        let activity_count = activities.len();
+        let distance_m = distance_m(&activities, self.max_gap_m);
+        let points = points_of(&activities);
+        drop(activities);
+
+        let layer_files = build_layers(points.clone(), &suffix, &self.layer_options, &self.output)?;
+        let mut layers = layer_files.entries();
+        layers.extend(matched_entry);
+        layers.extend(new_ground_entry);
+        layers.extend(tile_entries);
+        layers.extend(h3_entries);
+
+        if let Some(period) = self.period {
+            self.build_period_layers(&points, period, &suffix)?;
+        }
+
+        let manifest = LayerManifest {
+            built_at: Utc::now().timestamp(),
+            view: InitialView::new(&points),
+            layers,
+        };
+        let manifest_path = self
+            .output
+            .ui_path(&suffixed_path(LAYER_MANIFEST_FILE, &suffix));
+        write_json(&manifest, &manifest_path).map_err(|e| Error::write(&manifest_path, e))?;
+        progress!(
+            self.output.progress,
+            "\n✓ Wrote manifest for {} layers to {}",
+            manifest.layers.len(),
+            manifest_path
+        );
+
+        if let Some(regions) = &self.report {
+            progress!(self.output.progress, "\n--- Report ---");
+            let regions = match regions {
+                Some((path, name_property)) => load_regions(
+                    path,
+                    name_property,
+                    self.output.progress,
+                    self.output.projector,
+                )
+                .map_err(|e| Error::read(path, e))?,
+                None => Vec::new(),
+            };
+            let options = ReportOptions {
+                sources,
+                activity_count,
+                distance_m,
+                period: self.period.unwrap_or(Period::Year),
+                regions: &regions,
+                suffix: &suffix,
+            };
+            write_report(&points, &options, &self.output)?;
+        }
+
+        Ok(Some(manifest))
+    }
+
+    /// Builds the layers of the points of every period and of all periods up to it,
+    /// plus the manifest listing them.
+    fn build_period_layers(
+        &self,
+        points: &[TrackPoint],
+        period: Period,
+        suffix: &str,
+    ) -> Result<(), Error> {
+        progress!(
+            self.output.progress,
+            "\n--- Building per-{:?} layers ---",
+            period
+        );
+
+        let mut cumulative_points = Vec::new();
+        let mut entries = Vec::new();
+
+        for (key, points) in split_by_period(points, period, self.output.progress) {
+            progress!(
+                self.output.progress,
+                "\n--- Period {} ({} points) ---",
+                key,
+                points.len()
+            );
+            cumulative_points.extend_from_slice(&points);
+
+            let point_count = points.len();
+            let layers = build_layers(
+                points,
+                &format!("{}_{}", suffix, key),
+                &self.layer_options,
+                &self.output,
+            )?;
+            let cumulative = build_layers(
+                cumulative_points.clone(),
+                &format!("{}_upto_{}", suffix, key),
+                &self.layer_options,
+                &self.output,
+            )?;
+
+            entries.push(PeriodEntry {
+                key,
+                point_count,
+                layers,
+                cumulative,
+            });
+        }
+
+        let manifest = PeriodManifest {
+            period,
+            periods: entries,
+        };
+        let manifest_path = self
+            .output
+            .ui_path(&suffixed_path(PERIOD_MANIFEST_FILE, suffix));
+        write_json(&manifest, &manifest_path).map_err(|e| Error::write(&manifest_path, e))?;
+        progress!(
+            self.output.progress,
+            "\n✓ Wrote manifest for {} periods to {}",
+            manifest.periods.len(),
+            manifest_path
+        );
+        Ok(())
+    }
+}
+
+/// Snaps projected activities onto the streets of the OSM extract at `osm_path` and
+/// writes the matched tracks, suffixed with `suffix`. Returns the `layers.json` entry
+/// of the layer.
+fn match_activities(
+    activities: &mut [Activity],
+    osm_path: &Path,
+    options: &MatchOptions,
+    suffix: &str,
+    output: &Output,
+) -> Result<LayerEntry, Error> {
+    progress!(output.progress, "\n--- Map matching ---");
+    let network = load_road_network(osm_path, output.progress, output.projector)
+        .map_err(|e| Error::read(osm_path, e))?;
+    let matcher = MapMatcher::new(&network, options.clone());
+
+    progress!(
+        output.progress,
+        "Matching {} activities...",
+        activities.len()
+    );
+    let bar = output.progress.bar(activities.len());
+    let matched_lines: Vec<LineString> = activities
+        .par_iter_mut()
+        .progress_with(bar)
+        .flat_map(|activity| matcher.match_activity(activity))
+        .collect();
+
+    let matched_path = output.path(&suffixed_path(MATCHED_FILE, suffix));
+    progress!(
+        output.progress,
+        "Writing matched tracks to {}...",
+        matched_path
+    );
+    write_lines_to_flatgeobuf(&matched_lines, &matched_path, output)
+        .map_err(|e| Error::write(&matched_path, e))?;
+    progress!(
+        output.progress,
+        "✓ Successfully wrote {} matched tracks to {}",
+        matched_lines.len(),
+        matched_path
+    );
+
+    let summary = LayerSummary::new(
+        "tracks",
+        matched_lines.len(),
+        union_rect(matched_lines.iter().filter_map(|line| line.bounding_rect())),
+        activities
+            .iter()
+            .flat_map(|activity| &activity.points)
+            .filter_map(|p| p.time),
+    );
+    Ok(LayerEntry::new(
+        "matched",
+        "Matched tracks",
+        &pmtiles_filename(&matched_path).map_err(|e| Error::write(&matched_path, e))?,
+        GeometryType::Lines,
+        &summary,
+    ))
+}
+
+/// Writes sanitized cells to a FlatGeobuf file, plus its PMTiles and exports.
+pub fn write_cells(cells: &[VisitedCell], path: &str, output: &Output) -> Result<(), Error> {
+    write_cells_to_flatgeobuf(cells, path, output).map_err(|e| Error::write(path, e))
+}
+
+/// Writes buffered coverage with the totals of the `cells` in every polygon to a
+/// FlatGeobuf file, plus its PMTiles and exports.
+pub fn write_coverage(
+    coverage: Coverage,
+    cells: &[VisitedCell],
+    path: &str,
+    output: &Output,
+) -> Result<(), Error> {
+    write_polygons_to_flatgeobuf(coverage.polygons, cells, path, output)
+        .map_err(|e| Error::write(path, e))
+}
+
+/// All points, tagged with the index of their activity.
+fn points_of(activities: &[Activity]) -> Vec<TrackPoint> {
+    activities
+        .iter()
+        .enumerate()
+        .flat_map(|(index, activity)| {
+            activity.points.iter().map(move |point| TrackPoint {
+                activity: index,
+                ..*point
+            })
+        })
+        .collect()
+}
@@ -0,0 +1,37 @@
+use indicatif::ProgressBar;
+
+/// Whether the pipeline prints its progress. It is off by default, so embedding the
+/// library doesn't write to stdout.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Progress {
+    verbose: bool,
+}
+
+impl Progress {
+    pub fn new(verbose: bool) -> Self {
+        Progress { verbose }
+    }
+
+    pub fn is_verbose(self) -> bool {
+        self.verbose
+    }
+
+    /// A progress bar for `len` steps, hidden unless progress output is on.
+    pub fn bar(self, len: usize) -> ProgressBar {
+        if self.verbose {
+            ProgressBar::new(len as u64)
+        } else {
+            ProgressBar::hidden()
+        }
+    }
+}
+
+/// `println!` if progress output is on, e.g. `progress!(output.progress, "{} points", n)`.
+#[macro_export]
+macro_rules! progress {
+    ($progress:expr, $($arg:tt)*) => {
+        if $progress.is_verbose() {
+            println!($($arg)*);
+        }
+    };
+}
@@ -0,0 +1,454 @@
+use geo::{BoundingRect, Coord, MultiPoint, Point};
+use pmtiles::{PmTilesWriter, TileCoord, TileId, TileType};
+use rayon::prelude::*;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::BufWriter;
+use std::path::Path;
+use tiff::encoder::{TiffEncoder, colortype};
+use tiff::tags::Tag;
+
+use crate::EPSG_METERS;
+use crate::measure::{HALF_WORLD_M, to_wgs84};
+use crate::progress::Progress;
+
+const TILE_SIZE: usize = 256;
+
+/// Longest side of the GeoTIFF in pixels, larger extents get coarser pixels.
+const MAX_IMAGE_SIZE: usize = 8_192;
+
+/// (column, row, number of points) of the pixels with points in a tile
+type PixelCounts = Vec<(i64, i64, f32)>;
+
+/// Colours densities are mapped to, evenly spaced from lowest to highest.
+#[derive(Clone, Debug)]
+pub struct ColorRamp {
+    stops: Vec<[u8; 4]>,
+}
+
+impl ColorRamp {
+    /// `heat` (the colours of the UI heatmap), `viridis`, `greys`, or comma separated
+    /// hex colours like `#000000,#ff0000,#ffff00`, optionally with alpha.
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let stops = match value {
+            "heat" => vec![
+                [0, 0, 255, 0],
+                [65, 105, 225, 255],
+                [0, 255, 255, 255],
+                [0, 255, 0, 255],
+                [255, 255, 0, 255],
+                [255, 0, 0, 255],
+            ],
+            "viridis" => vec![
+                [68, 1, 84, 255],
+                [59, 82, 139, 255],
+                [33, 145, 140, 255],
+                [94, 201, 98, 255],
+                [253, 231, 37, 255],
+            ],
+            "greys" => vec![[0, 0, 0, 255], [255, 255, 255, 255]],
+            custom => custom
+                .split(',')
+                .map(|color| parse_hex_color(color.trim()))
+                .collect::<Result<_, _>>()?,
+        };
+        if stops.len() < 2 {
+            return Err(format!(
+                "a colour ramp needs at least two colours, got '{}'",
+                value
+            ));
+        }
+        Ok(ColorRamp { stops })
+    }
+
+    /// Colour at `t` between 0 and 1
+    pub fn color(&self, t: f64) -> [u8; 4] {
+        let position = t.clamp(0.0, 1.0) * (self.stops.len() - 1) as f64;
+        let index = (position.floor() as usize).min(self.stops.len() - 2);
+        let ratio = position - index as f64;
+        let (from, to) = (self.stops[index], self.stops[index + 1]);
+        std::array::from_fn(|channel| {
+            (from[channel] as f64 + (to[channel] as f64 - from[channel] as f64) * ratio).round()
+                as u8
+        })
+    }
+}
+
+/// `#rrggbb` or `#rrggbbaa`
+pub fn parse_hex_color(value: &str) -> Result<[u8; 4], String> {
+    let hex = value.trim_start_matches('#');
+    if hex.len() != 6 && hex.len() != 8 {
+        return Err(format!("expected a colour like #rrggbb, got '{}'", value));
+    }
+    let channel = |i: usize| {
+        hex.get(i..i + 2)
+            .and_then(|digits| u8::from_str_radix(digits, 16).ok())
+            .ok_or_else(|| format!("invalid colour '{}'", value))
+    };
+    let alpha = if hex.len() == 8 { channel(6)? } else { 255 };
+    Ok([channel(0)?, channel(2)?, channel(4)?, alpha])
+}
+
+#[derive(Clone, Debug)]
+pub struct RasterOptions {
+    /// Radius of the smoothing kernel in pixels, e.g. 8.0
+    pub radius_px: f64,
+    pub ramp: ColorRamp,
+    /// Map densities logarithmically, so rarely visited places still show
+    pub log_scale: bool,
+    /// Highest zoom level of the raster tiles, e.g. 14
+    pub max_zoom: u8,
+    /// Pixel size of the GeoTIFF, e.g. 10.0
+    pub pixel_size_m: f64,
+}
+
+/// A density grid, row by row from the top.
+pub struct Grid {
+    width: usize,
+    height: usize,
+    values: Vec<f32>,
+}
+
+impl Grid {
+    pub fn new(width: usize, height: usize) -> Self {
+        Grid {
+            width,
+            height,
+            values: vec![0.0; width * height],
+        }
+    }
+
+    pub fn add(&mut self, column: i64, row: i64, count: f32) {
+        if (0..self.width as i64).contains(&column) && (0..self.height as i64).contains(&row) {
+            self.values[row as usize * self.width + column as usize] += count;
+        }
+    }
+
+    pub fn max(&self) -> f32 {
+        self.values.iter().copied().fold(0.0, f32::max)
+    }
+
+    /// Gaussian blur with a kernel of `radius_px`, done separately per axis.
+    pub fn blur(&mut self, radius_px: f64) {
+        let kernel = gaussian_kernel(radius_px);
+        let reach = (kernel.len() / 2) as i64;
+        let (width, height) = (self.width as i64, self.height as i64);
+
+        let mut horizontal = vec![0.0; self.values.len()];
+        horizontal
+            .par_chunks_mut(self.width)
+            .enumerate()
+            .for_each(|(row, out)| {
+                let source = &self.values[row * self.width..(row + 1) * self.width];
+                for (column, &value) in source.iter().enumerate() {
+                    if value == 0.0 {
+                        continue;
+                    }
+                    for (offset, weight) in kernel.iter().enumerate() {
+                        let target = column as i64 + offset as i64 - reach;
+                        if (0..width).contains(&target) {
+                            out[target as usize] += value * weight;
+                        }
+                    }
+                }
+            });
+
+        let mut vertical = vec![0.0; self.values.len()];
+        for row in 0..height {
+            let source = &horizontal[row as usize * self.width..(row as usize + 1) * self.width];
+            for (offset, weight) in kernel.iter().enumerate() {
+                let target = row + offset as i64 - reach;
+                if !(0..height).contains(&target) {
+                    continue;
+                }
+                let out =
+                    &mut vertical[target as usize * self.width..(target as usize + 1) * self.width];
+                for (out, &value) in out.iter_mut().zip(source) {
+                    *out += value * weight;
+                }
+            }
+        }
+
+        self.values = vertical;
+    }
+
+    /// RGBA pixels, empty cells are transparent.
+    pub fn colorize(&self, max: f32, ramp: &ColorRamp, log_scale: bool) -> Vec<u8> {
+        self.values
+            .par_iter()
+            .flat_map_iter(|&value| {
+                if value <= 0.0 || max <= 0.0 {
+                    return [0, 0, 0, 0];
+                }
+                let t = if log_scale {
+                    (value as f64).ln_1p() / (max as f64).ln_1p()
+                } else {
+                    value as f64 / max as f64
+                };
+                ramp.color(t)
+            })
+            .collect()
+    }
+}
+
+/// Normalized weights of a Gaussian reaching `radius_px` to each side.
+fn gaussian_kernel(radius_px: f64) -> Vec<f32> {
+    let reach = radius_px.ceil().max(1.0) as i64;
+    let sigma = (radius_px / 2.0).max(0.5);
+    let weights: Vec<f64> = (-reach..=reach)
+        .map(|offset| (-(offset as f64).powi(2) / (2.0 * sigma * sigma)).exp())
+        .collect();
+    let sum: f64 = weights.iter().sum();
+    weights.into_iter().map(|w| (w / sum) as f32).collect()
+}
+
+pub fn encode_png(
+    rgba: &[u8],
+    width: usize,
+    height: usize,
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let mut bytes = Vec::new();
+    let mut encoder = png::Encoder::new(&mut bytes, width as u32, height as u32);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(rgba)?;
+    writer.finish()?;
+    Ok(bytes)
+}
+
+/// Renders the density of projected points to a GeoTIFF in EPSG_METERS and the same
+/// image as a plain PNG, e.g. for printing.
+pub fn write_heatmap_image(
+    points: &[Point],
+    tiff_path: &str,
+    png_path: &str,
+    options: &RasterOptions,
+    progress: Progress,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let Some(bounds) = MultiPoint::from(points.to_vec()).bounding_rect() else {
+        return Ok(());
+    };
+
+    let longest_side = bounds.width().max(bounds.height());
+    let pixel_size = options
+        .pixel_size_m
+        .max(longest_side / (MAX_IMAGE_SIZE as f64 - 2.0 * options.radius_px.ceil()));
+    // leave room for the kernel around the points
+    let margin = options.radius_px.ceil() * pixel_size;
+    let origin = Coord {
+        x: bounds.min().x - margin,
+        y: bounds.max().y + margin,
+    };
+    let width = ((bounds.width() + 2.0 * margin) / pixel_size).ceil() as usize + 1;
+    let height = ((bounds.height() + 2.0 * margin) / pixel_size).ceil() as usize + 1;
+
+    progress!(
+        progress,
+        "Rendering {}x{} heatmap image at {:.1}m per pixel...",
+        width,
+        height,
+        pixel_size
+    );
+
+    let mut grid = Grid::new(width, height);
+    for point in points {
+        grid.add(
+            ((point.x() - origin.x) / pixel_size).floor() as i64,
+            ((origin.y - point.y()) / pixel_size).floor() as i64,
+            1.0,
+        );
+    }
+    grid.blur(options.radius_px);
+    let rgba = grid.colorize(grid.max(), &options.ramp, options.log_scale);
+
+    if let Some(parent) = Path::new(tiff_path).parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut encoder = TiffEncoder::new(BufWriter::new(File::create(tiff_path)?))?;
+    let mut image = encoder.new_image::<colortype::RGBA8>(width as u32, height as u32)?;
+    // GeoTIFF tags: pixel size, the position of the top left pixel and the CRS
+    image
+        .encoder()
+        .write_tag(Tag::ModelPixelScaleTag, &[pixel_size, pixel_size, 0.0][..])?;
+    image.encoder().write_tag(
+        Tag::ModelTiepointTag,
+        &[0.0, 0.0, 0.0, origin.x, origin.y, 0.0][..],
+    )?;
+    image.encoder().write_tag(
+        Tag::GeoKeyDirectoryTag,
+        &[
+            // version 1.1.0 with 3 keys
+            1,
+            1,
+            0,
+            3,
+            // GTModelTypeGeoKey: projected
+            1024,
+            0,
+            1,
+            1,
+            // GTRasterTypeGeoKey: pixel is area
+            1025,
+            0,
+            1,
+            1,
+            // ProjectedCSTypeGeoKey
+            3072,
+            0,
+            1,
+            EPSG_METERS as u16,
+        ][..],
+    )?;
+    image.write_data(&rgba)?;
+    progress!(progress, "✓ Wrote heatmap GeoTIFF to {}", tiff_path);
+
+    fs::write(png_path, encode_png(&rgba, width, height)?)?;
+    progress!(progress, "✓ Wrote heatmap image to {}", png_path);
+
+    Ok(())
+}
+
+/// Renders the density of projected points to a pyramid of PNG tiles from zoom 0 to
+/// `max_zoom`. The kernel has the same size in pixels on every zoom level, like the
+/// heatmap layer in the UI.
+pub fn write_heatmap_pmtiles(
+    points: &[Point],
+    pmtiles_path: &str,
+    options: &RasterOptions,
+    progress: Progress,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let Some(bounds) = MultiPoint::from(points.to_vec()).bounding_rect() else {
+        return Ok(());
+    };
+    let (south_west, north_east) = (to_wgs84(bounds.min()), to_wgs84(bounds.max()));
+
+    if let Some(parent) = Path::new(pmtiles_path).parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut writer = PmTilesWriter::new(TileType::Png)
+        .min_zoom(0)
+        .max_zoom(options.max_zoom)
+        .bounds(south_west.x, south_west.y, north_east.x, north_east.y)
+        .create(BufWriter::new(File::create(pmtiles_path)?))?;
+
+    let reach = options.radius_px.ceil() as i64;
+    // tiles away from a tile the kernel reaches into
+    let neighbours = (reach + TILE_SIZE as i64 - 1) / TILE_SIZE as i64;
+    let mut tile_count = 0;
+
+    for zoom in 0..=options.max_zoom {
+        let pixels_per_meter = (TILE_SIZE << zoom) as f64 / (2.0 * HALF_WORLD_M);
+        let world_pixels = (TILE_SIZE << zoom) as i64;
+
+        // point counts per pixel, grouped by tile
+        let mut by_tile: HashMap<(i64, i64), PixelCounts> = HashMap::new();
+        let pixel_counts: HashMap<(i64, i64), f32> = points
+            .par_iter()
+            .fold(
+                HashMap::new,
+                |mut counts: HashMap<(i64, i64), f32>, point| {
+                    let column = ((point.x() + HALF_WORLD_M) * pixels_per_meter).floor() as i64;
+                    let row = ((HALF_WORLD_M - point.y()) * pixels_per_meter).floor() as i64;
+                    *counts
+                        .entry((
+                            column.clamp(0, world_pixels - 1),
+                            row.clamp(0, world_pixels - 1),
+                        ))
+                        .or_default() += 1.0;
+                    counts
+                },
+            )
+            .reduce(HashMap::new, |mut merged, counts| {
+                for (pixel, count) in counts {
+                    *merged.entry(pixel).or_default() += count;
+                }
+                merged
+            });
+        for ((column, row), count) in pixel_counts {
+            let tile = (column / TILE_SIZE as i64, row / TILE_SIZE as i64);
+            by_tile.entry(tile).or_default().push((column, row, count));
+        }
+
+        // the kernel reaches into neighbouring tiles
+        let tiles_per_side = 1i64 << zoom;
+        let mut tiles: Vec<(i64, i64)> = by_tile
+            .keys()
+            .flat_map(|&(x, y)| {
+                (-neighbours..=neighbours)
+                    .flat_map(move |dx| (-neighbours..=neighbours).map(move |dy| (x + dx, y + dy)))
+            })
+            .filter(|&(x, y)| (0..tiles_per_side).contains(&x) && (0..tiles_per_side).contains(&y))
+            .collect();
+        tiles.sort_unstable();
+        tiles.dedup();
+
+        let render = |(x, y): (i64, i64)| {
+            let size = TILE_SIZE + 2 * reach as usize;
+            let mut grid = Grid::new(size, size);
+            let (left, top) = (x * TILE_SIZE as i64 - reach, y * TILE_SIZE as i64 - reach);
+            for dx in -neighbours..=neighbours {
+                for dy in -neighbours..=neighbours {
+                    for &(column, row, count) in
+                        by_tile.get(&(x + dx, y + dy)).into_iter().flatten()
+                    {
+                        grid.add(column - left, row - top, count);
+                    }
+                }
+            }
+            grid.blur(options.radius_px);
+
+            // crop the margin
+            let mut tile = Grid::new(TILE_SIZE, TILE_SIZE);
+            for row in 0..TILE_SIZE {
+                let start = (row + reach as usize) * size + reach as usize;
+                tile.values[row * TILE_SIZE..(row + 1) * TILE_SIZE]
+                    .copy_from_slice(&grid.values[start..start + TILE_SIZE]);
+            }
+            tile
+        };
+
+        let rendered: Vec<((i64, i64), Grid)> = tiles
+            .par_iter()
+            .map(|&tile| (tile, render(tile)))
+            .filter(|(_, grid)| grid.max() > 0.0)
+            .collect();
+
+        // densities are scaled to the densest pixel of the zoom level, so tiles match
+        let max = rendered
+            .par_iter()
+            .map(|(_, grid)| grid.max())
+            .reduce(|| 0.0, f32::max);
+
+        let mut encoded: Vec<(TileId, Vec<u8>)> = rendered
+            .par_iter()
+            .map(|&((x, y), ref tile)| {
+                let png = encode_png(
+                    &tile.colorize(max, &options.ramp, options.log_scale),
+                    TILE_SIZE,
+                    TILE_SIZE,
+                )
+                .map_err(|e| e.to_string())?;
+                let coord = TileCoord::new(zoom, x as u32, y as u32).map_err(|e| e.to_string())?;
+                Ok((TileId::from(coord), png))
+            })
+            .collect::<Result<_, String>>()?;
+        encoded.sort_unstable_by_key(|(id, _)| id.value());
+
+        progress!(progress, "Zoom {}: {} raster tiles", zoom, encoded.len());
+        tile_count += encoded.len();
+        for (id, png) in encoded {
+            writer.add_tile(TileCoord::from(id), &png)?;
+        }
+    }
+
+    writer.finalize()?;
+    progress!(
+        progress,
+        "✓ Wrote {} raster tiles to {}",
+        tile_count,
+        pmtiles_path
+    );
+
+    Ok(())
+}
@@ -0,0 +1,98 @@
+use geo::{BooleanOps, MultiPolygon};
+use rayon::prelude::*;
+use serde::Serialize;
+
+use crate::coverage::CoverageIndex;
+use crate::io::{Output, write_features_to_flatgeobuf, write_json};
+use crate::measure::{area_m2, percent, round_km2};
+use crate::regions::Region;
+use crate::{REGION_STATS_CSV_FILE, REGION_STATS_JSON_FILE, REGIONS_FILE};
+
+/// Explored area of one region.
+#[derive(Serialize)]
+pub struct RegionStats<'a> {
+    pub name: &'a str,
+    pub admin_level: Option<&'a str>,
+    pub area_km2: f64,
+    pub explored_km2: f64,
+    pub explored_percent: f64,
+}
+
+/// A region with its stats, for a choropleth in the UI.
+#[derive(Serialize)]
+struct RegionFeature<'a> {
+    geo: MultiPolygon,
+    name: &'a str,
+    admin_level: Option<&'a str>,
+    area_km2: f64,
+    explored_km2: f64,
+    explored_percent: f64,
+}
+
+/// Intersects every region with the dissolved coverage polygons.
+pub fn region_stats<'a>(regions: &'a [Region], coverage: &CoverageIndex) -> Vec<RegionStats<'a>> {
+    regions
+        .par_iter()
+        .map(|region| {
+            // buffer polygons are dissolved and don't overlap, so one intersection is enough
+            let covered = MultiPolygon::new(coverage.candidates(&region.bounds).cloned().collect());
+            let explored = region.geometry.intersection(&covered);
+
+            let area = area_m2(&region.geometry);
+            let explored = area_m2(&explored);
+            RegionStats {
+                name: &region.name,
+                admin_level: region.admin_level.as_deref(),
+                area_km2: round_km2(area),
+                explored_km2: round_km2(explored),
+                explored_percent: percent(explored, area),
+            }
+        })
+        .collect()
+}
+
+/// Writes the stats as CSV and JSON, and the regions with their stats as FlatGeobuf.
+pub fn write_region_stats(
+    stats: &[RegionStats],
+    regions: &[Region],
+    output: &Output,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let csv_path = output.path(REGION_STATS_CSV_FILE);
+    let mut writer = csv::Writer::from_path(&csv_path)?;
+    for row in stats {
+        writer.serialize(row)?;
+    }
+    writer.flush()?;
+    progress!(output.progress, "✓ Wrote region stats to {}", csv_path);
+
+    let json_path = output.path(REGION_STATS_JSON_FILE);
+    write_json(&stats, &json_path)?;
+    progress!(output.progress, "✓ Wrote region stats to {}", json_path);
+
+    let features: Vec<RegionFeature> = regions
+        .iter()
+        .zip(stats)
+        .map(|(region, stats)| {
+            Ok(RegionFeature {
+                geo: output.project(&region.geometry)?,
+                name: stats.name,
+                admin_level: stats.admin_level,
+                area_km2: stats.area_km2,
+                explored_km2: stats.explored_km2,
+                explored_percent: stats.explored_percent,
+            })
+        })
+        .collect::<Result<_, String>>()?;
+
+    let path = output.path(REGIONS_FILE);
+    progress!(output.progress, "Writing regions to {}...", path);
+    write_features_to_flatgeobuf(&features, &path, output)?;
+    progress!(
+        output.progress,
+        "✓ Successfully wrote {} regions to {}",
+        features.len(),
+        path
+    );
+
+    Ok(())
+}
@@ -0,0 +1,144 @@
+use geo::{BoundingRect, Contains, Coord, Geometry, MapCoords, MultiPolygon, Point, Rect};
+use geojson::GeoJson;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use crate::crs::Projector;
+use crate::io::{Feature, read_flatgeobuf};
+use crate::osm::load_admin_boundaries;
+use crate::progress::Progress;
+use crate::{EPSG_METERS, EPSG_WGS84};
+
+/// An administrative area, e.g. a city or district, projected to EPSG_METERS.
+pub struct Region {
+    pub name: String,
+    /// OSM `admin_level` if the boundaries file has one, e.g. `8` for cities
+    pub admin_level: Option<String>,
+    pub geometry: MultiPolygon,
+    pub bounds: Rect,
+}
+
+impl Region {
+    pub fn contains(&self, point: &Point) -> bool {
+        self.bounds.contains(point) && self.geometry.contains(point)
+    }
+}
+
+/// Reads region polygons from a GeoJSON or FlatGeobuf file, e.g. admin boundaries
+/// exported from OSM, or the admin boundary relations of an `.osm.pbf` extract.
+/// The name is taken from the `name_property` of every feature, features without
+/// a polygon are skipped.
+pub fn load_regions(
+    path: &Path,
+    name_property: &str,
+    progress: Progress,
+    projector: Projector,
+) -> Result<Vec<Region>, Box<dyn std::error::Error>> {
+    progress!(progress, "Reading regions from {}...", path.display());
+
+    let extension = path.extension().and_then(|extension| extension.to_str());
+    let (epsg, features) = match extension {
+        Some("fgb") => read_flatgeobuf(path)?,
+        Some("pbf") => (
+            EPSG_WGS84,
+            read_osm_boundaries(path, name_property, progress)?,
+        ),
+        // GeoJSON is always WGS84
+        _ => (EPSG_WGS84, read_geojson(path)?),
+    };
+
+    let needs_projection = match epsg {
+        EPSG_METERS => false,
+        // files without a CRS are most likely WGS84
+        EPSG_WGS84 | 0 => true,
+        other => {
+            return Err(format!("unsupported CRS EPSG:{} of {}", other, path.display()).into());
+        }
+    };
+
+    let mut regions = Vec::new();
+    for feature in features {
+        let geometry = match feature.geometry {
+            Geometry::Polygon(polygon) => MultiPolygon::new(vec![polygon]),
+            Geometry::MultiPolygon(multi_polygon) => multi_polygon,
+            _ => continue,
+        };
+        let geometry = if needs_projection {
+            geometry.try_map_coords(|coord: Coord| projector.to_meters(coord))?
+        } else {
+            geometry
+        };
+        let Some(bounds) = geometry.bounding_rect() else {
+            continue;
+        };
+
+        regions.push(Region {
+            name: feature
+                .properties
+                .get(name_property)
+                .cloned()
+                .unwrap_or_else(|| format!("region {}", regions.len() + 1)),
+            admin_level: feature.properties.get("admin_level").cloned(),
+            geometry,
+            bounds,
+        });
+    }
+
+    progress!(progress, "✓ Loaded {} regions", regions.len());
+    Ok(regions)
+}
+
+fn read_osm_boundaries(
+    path: &Path,
+    name_property: &str,
+    progress: Progress,
+) -> Result<Vec<Feature>, Box<dyn std::error::Error>> {
+    let boundaries = load_admin_boundaries(path, progress)?;
+    Ok(boundaries
+        .into_iter()
+        .map(|boundary| {
+            let mut properties = HashMap::from([(name_property.to_string(), boundary.name)]);
+            if let Some(admin_level) = boundary.admin_level {
+                properties.insert("admin_level".to_string(), admin_level);
+            }
+            Feature {
+                geometry: Geometry::MultiPolygon(boundary.geometry),
+                properties,
+            }
+        })
+        .collect())
+}
+
+fn read_geojson(path: &Path) -> Result<Vec<Feature>, Box<dyn std::error::Error>> {
+    let features = match fs::read_to_string(path)?.parse::<GeoJson>()? {
+        GeoJson::FeatureCollection(collection) => collection.features,
+        GeoJson::Feature(feature) => vec![feature],
+        GeoJson::Geometry(_) => return Err("expected GeoJSON features with properties".into()),
+    };
+
+    let mut result = Vec::new();
+    for feature in features {
+        let properties = feature
+            .properties
+            .iter()
+            .flatten()
+            .map(|(key, value)| {
+                // keep strings unquoted, e.g. "8" rather than "\"8\""
+                let value = match value {
+                    serde_json::Value::String(s) => s.clone(),
+                    other => other.to_string(),
+                };
+                (key.clone(), value)
+            })
+            .collect();
+        if let Some(geometry) = feature.geometry {
+            result.push(Feature {
+                geometry: Geometry::try_from(geometry.value)?,
+                properties,
+            });
+        }
+    }
+
+    Ok(result)
+}
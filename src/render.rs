@@ -0,0 +1,495 @@
+use base64::Engine as _;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use geo::{Coord, Geometry, Point, Polygon, Rect, Simplify};
+use rayon::prelude::*;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::Path;
+
+use crate::coverage::CoverageIndex;
+use crate::crs::Projector;
+use crate::io::{read_flatgeobuf_in_meters, suffixed_path};
+use crate::layers::HEATMAP_LEVELS;
+use crate::progress::Progress;
+use crate::raster::{ColorRamp, Grid, encode_png};
+
+const MM_PER_INCH: f64 = 25.4;
+
+/// Rows sampled per pixel row to smooth the edges of the fog.
+const SUBSAMPLES: usize = 4;
+
+/// Largest poster in pixels, about A0 at 300 dpi.
+const MAX_PIXELS: usize = 150_000_000;
+
+/// Paper dimensions in millimeters.
+#[derive(Clone, Copy, Debug)]
+pub struct PaperSize {
+    pub width_mm: f64,
+    pub height_mm: f64,
+}
+
+impl PaperSize {
+    /// `a0` to `a5`, `letter`, `tabloid`, or `WIDTHxHEIGHT` in millimeters like `500x700`.
+    /// Named sizes are portrait.
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let (width_mm, height_mm) = match value.to_lowercase().as_str() {
+            "a0" => (841.0, 1189.0),
+            "a1" => (594.0, 841.0),
+            "a2" => (420.0, 594.0),
+            "a3" => (297.0, 420.0),
+            "a4" => (210.0, 297.0),
+            "a5" => (148.0, 210.0),
+            "letter" => (215.9, 279.4),
+            "tabloid" => (279.4, 431.8),
+            custom => {
+                let (width, height) = custom.split_once('x').ok_or_else(|| {
+                    format!("expected a paper size or WIDTHxHEIGHT, got '{}'", value)
+                })?;
+                let parse = |mm: &str| match mm.trim().parse::<f64>() {
+                    Ok(mm) if mm > 0.0 => Ok(mm),
+                    _ => Err(format!("invalid paper size '{}'", value)),
+                };
+                (parse(width)?, parse(height)?)
+            }
+        };
+        Ok(PaperSize {
+            width_mm,
+            height_mm,
+        })
+    }
+
+    /// The same paper turned sideways, wider than high
+    pub fn landscape(self) -> Self {
+        PaperSize {
+            width_mm: self.width_mm.max(self.height_mm),
+            height_mm: self.width_mm.min(self.height_mm),
+        }
+    }
+}
+
+/// How to draw the heatmap of a previous run onto the poster.
+pub struct PosterHeatmap {
+    /// Heatmap written by a previous run, e.g. `data/heatmap.fgb`
+    pub path: String,
+    pub radius_mm: f64,
+    pub ramp: ColorRamp,
+    pub log_scale: bool,
+}
+
+pub struct PosterOptions {
+    /// Area to show in EPSG_METERS, the whole coverage if `None`. It is widened to the
+    /// proportions of the paper.
+    pub bbox: Option<Rect>,
+    pub paper: PaperSize,
+    pub dpi: u32,
+    /// RGBA colour of the explored area
+    pub background: [u8; 4],
+    /// RGBA colour of the fog
+    pub fog_color: [u8; 4],
+    pub heatmap: Option<PosterHeatmap>,
+}
+
+/// The area on the poster and how it maps to pixels.
+struct View {
+    /// Top left corner in EPSG_METERS
+    origin: Coord,
+    meters_per_pixel: f64,
+    width: usize,
+    height: usize,
+}
+
+impl View {
+    /// Fits `bbox` into the paper, centered.
+    fn new(bbox: Rect, paper: PaperSize, dpi: u32) -> Self {
+        let width = (paper.width_mm / MM_PER_INCH * dpi as f64).round().max(1.0) as usize;
+        let height = (paper.height_mm / MM_PER_INCH * dpi as f64)
+            .round()
+            .max(1.0) as usize;
+        let meters_per_pixel = (bbox.width() / width as f64).max(bbox.height() / height as f64);
+        let center = bbox.center();
+        View {
+            origin: Coord {
+                x: center.x - width as f64 * meters_per_pixel / 2.0,
+                y: center.y + height as f64 * meters_per_pixel / 2.0,
+            },
+            meters_per_pixel,
+            width,
+            height,
+        }
+    }
+
+    fn bounds(&self) -> Rect {
+        Rect::new(
+            self.origin,
+            Coord {
+                x: self.origin.x + self.width as f64 * self.meters_per_pixel,
+                y: self.origin.y - self.height as f64 * self.meters_per_pixel,
+            },
+        )
+    }
+
+    fn to_pixel(&self, coord: Coord) -> Coord {
+        Coord {
+            x: (coord.x - self.origin.x) / self.meters_per_pixel,
+            y: (self.origin.y - coord.y) / self.meters_per_pixel,
+        }
+    }
+}
+
+/// Renders the fog, i.e. everything outside of the coverage polygons, to an SVG or PNG
+/// poster depending on the extension of `output_path`.
+pub fn render_poster(
+    coverage: &CoverageIndex,
+    options: &PosterOptions,
+    output_path: &Path,
+    progress: Progress,
+    projector: Projector,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let Some(bbox) = options.bbox.or_else(|| {
+        // leave some fog around the explored area
+        coverage.bounds().map(|bounds| {
+            let margin = bounds.width().max(bounds.height()) * 0.05;
+            Rect::new(
+                Coord {
+                    x: bounds.min().x - margin,
+                    y: bounds.min().y - margin,
+                },
+                Coord {
+                    x: bounds.max().x + margin,
+                    y: bounds.max().y + margin,
+                },
+            )
+        })
+    }) else {
+        return Err("the coverage is empty, pass a --bbox".into());
+    };
+
+    let view = View::new(bbox, options.paper, options.dpi);
+    if view.width * view.height > MAX_PIXELS {
+        return Err(format!(
+            "a {}x{} pixel poster is too large, lower the --dpi",
+            view.width, view.height
+        )
+        .into());
+    }
+    progress!(
+        progress,
+        "Rendering {}x{} pixel poster at {:.1}m per pixel...",
+        view.width,
+        view.height,
+        view.meters_per_pixel
+    );
+
+    // vertices closer than a fraction of a pixel don't show
+    let tolerance = view.meters_per_pixel / 4.0;
+    let polygons: Vec<Polygon> = coverage
+        .candidates(&view.bounds())
+        .map(|polygon| polygon.simplify(&tolerance))
+        .collect();
+    let rings: Vec<Vec<Coord>> = polygons
+        .iter()
+        .flat_map(|polygon| std::iter::once(polygon.exterior()).chain(polygon.interiors()))
+        .map(|ring| ring.coords().map(|&coord| view.to_pixel(coord)).collect())
+        .collect();
+    progress!(
+        progress,
+        "Drawing {} rings of {} polygons",
+        rings.len(),
+        polygons.len()
+    );
+
+    let heatmap = match &options.heatmap {
+        Some(heatmap) => Some(render_heatmap(
+            heatmap,
+            &view,
+            options.dpi,
+            progress,
+            projector,
+        )?),
+        None => None,
+    };
+
+    if let Some(parent) = output_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    match output_path
+        .extension()
+        .and_then(|extension| extension.to_str())
+    {
+        Some("svg") => {
+            let svg = poster_svg(&rings, heatmap.as_deref(), &view, options)?;
+            fs::write(output_path, svg)?;
+        }
+        Some("png") => {
+            let rgba = poster_pixels(&rings, heatmap.as_deref(), &view, options);
+            fs::write(output_path, encode_png(&rgba, view.width, view.height)?)?;
+        }
+        _ => {
+            return Err(format!(
+                "can't tell the format of {}, use .svg or .png",
+                output_path.display()
+            )
+            .into());
+        }
+    }
+    progress!(progress, "✓ Wrote poster to {}", output_path.display());
+
+    Ok(())
+}
+
+/// Renders the heatmap level of a previous run whose cells best match the pixel size
+/// to RGBA pixels of the view.
+fn render_heatmap(
+    heatmap: &PosterHeatmap,
+    view: &View,
+    dpi: u32,
+    progress: Progress,
+    projector: Projector,
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    // the coarsest cells still smaller than a pixel, or the finest ones
+    let cell_size_m = HEATMAP_LEVELS
+        .iter()
+        .map(|&(cell_size_m, _, _)| cell_size_m)
+        .filter(|&cell_size_m| cell_size_m <= view.meters_per_pixel)
+        .reduce(f64::max)
+        .or_else(|| {
+            HEATMAP_LEVELS
+                .iter()
+                .map(|&(cell_size_m, _, _)| cell_size_m)
+                .reduce(f64::min)
+        })
+        .ok_or("no heatmap levels")?;
+    let path = suffixed_path(&heatmap.path, &format!("_{}m", cell_size_m));
+    progress!(progress, "Reading heatmap from {}...", path);
+    let features = read_flatgeobuf_in_meters(Path::new(&path), projector)?;
+
+    let mut grid = Grid::new(view.width, view.height);
+    for feature in features {
+        let Geometry::Point(Point(coord)) = feature.geometry else {
+            continue;
+        };
+        let count = feature
+            .properties
+            .get("count")
+            .and_then(|count| count.parse().ok())
+            .unwrap_or(1.0);
+        let pixel = view.to_pixel(coord);
+        grid.add(pixel.x.floor() as i64, pixel.y.floor() as i64, count);
+    }
+    grid.blur(heatmap.radius_mm / MM_PER_INCH * dpi as f64);
+
+    Ok(grid.colorize(grid.max(), &heatmap.ramp, heatmap.log_scale))
+}
+
+/// Share of every pixel covered by fog: inside the view and outside of the rings,
+/// filled even-odd.
+fn fog_coverage(rings: &[Vec<Coord>], view: &View) -> Vec<f32> {
+    struct Edge {
+        top: f64,
+        bottom: f64,
+        x_at_top: f64,
+        dx_dy: f64,
+    }
+
+    let (width, height) = (view.width as f64, view.height as f64);
+    let frame = vec![
+        Coord { x: 0.0, y: 0.0 },
+        Coord { x: width, y: 0.0 },
+        Coord {
+            x: width,
+            y: height,
+        },
+        Coord { x: 0.0, y: height },
+        Coord { x: 0.0, y: 0.0 },
+    ];
+
+    let mut edges: Vec<Edge> = std::iter::once(&frame)
+        .chain(rings)
+        .flat_map(|ring| ring.windows(2))
+        .filter_map(|pair| {
+            let (start, end) = if pair[0].y < pair[1].y {
+                (pair[0], pair[1])
+            } else {
+                (pair[1], pair[0])
+            };
+            // horizontal edges and edges outside of the view don't cross any sample row
+            if start.y == end.y || end.y <= 0.0 || start.y >= height {
+                return None;
+            }
+            Some(Edge {
+                top: start.y,
+                bottom: end.y,
+                x_at_top: start.x,
+                dx_dy: (end.x - start.x) / (end.y - start.y),
+            })
+        })
+        .collect();
+    edges.sort_unstable_by(|a, b| a.top.total_cmp(&b.top));
+
+    let mut coverage = vec![0.0; view.width * view.height];
+    let weight = 1.0 / SUBSAMPLES as f32;
+    let mut next_edge = 0;
+    let mut active: Vec<&Edge> = Vec::new();
+    let mut crossings = Vec::new();
+
+    for (row, out) in coverage.chunks_mut(view.width).enumerate() {
+        for sample in 0..SUBSAMPLES {
+            let y = row as f64 + (sample as f64 + 0.5) / SUBSAMPLES as f64;
+            while next_edge < edges.len() && edges[next_edge].top <= y {
+                active.push(&edges[next_edge]);
+                next_edge += 1;
+            }
+            active.retain(|edge| edge.bottom > y);
+
+            crossings.clear();
+            crossings.extend(
+                active
+                    .iter()
+                    .map(|edge| edge.x_at_top + (y - edge.top) * edge.dx_dy),
+            );
+            crossings.sort_unstable_by(f64::total_cmp);
+            for span in crossings.chunks_exact(2) {
+                add_span(out, span[0], span[1], weight);
+            }
+        }
+    }
+
+    coverage
+}
+
+/// Adds `weight` to the pixels of a row between `start` and `end`, partly covered
+/// pixels by the covered share.
+fn add_span(row: &mut [f32], start: f64, end: f64, weight: f32) {
+    let width = row.len() as f64;
+    let (start, end) = (start.clamp(0.0, width), end.clamp(0.0, width));
+    if end <= start {
+        return;
+    }
+    let (first, last) = (start.floor() as usize, end.floor() as usize);
+    if first == last {
+        row[first] += (end - start) as f32 * weight;
+        return;
+    }
+    row[first] += (first as f64 + 1.0 - start) as f32 * weight;
+    for pixel in &mut row[first + 1..last] {
+        *pixel += weight;
+    }
+    if last < row.len() {
+        row[last] += (end - last as f64) as f32 * weight;
+    }
+}
+
+/// `source` drawn over `target` with `opacity`, both with straight alpha.
+fn blend(target: [u8; 4], source: [u8; 4], opacity: f32) -> [u8; 4] {
+    let source_alpha = source[3] as f32 / 255.0 * opacity;
+    let target_alpha = target[3] as f32 / 255.0;
+    let alpha = source_alpha + target_alpha * (1.0 - source_alpha);
+    if alpha <= 0.0 {
+        return [0, 0, 0, 0];
+    }
+    let mut color = [0, 0, 0, (alpha * 255.0).round() as u8];
+    for channel in 0..3 {
+        color[channel] = ((source[channel] as f32 * source_alpha
+            + target[channel] as f32 * target_alpha * (1.0 - source_alpha))
+            / alpha)
+            .round() as u8;
+    }
+    color
+}
+
+/// RGBA pixels of the poster: background, heatmap and fog on top.
+fn poster_pixels(
+    rings: &[Vec<Coord>],
+    heatmap: Option<&[u8]>,
+    view: &View,
+    options: &PosterOptions,
+) -> Vec<u8> {
+    let fog = fog_coverage(rings, view);
+    let mut rgba = vec![0; view.width * view.height * 4];
+    rgba.par_chunks_mut(4)
+        .zip(fog.par_iter())
+        .enumerate()
+        .for_each(|(index, (pixel, &fog))| {
+            let mut color = options.background;
+            if let Some(heatmap) = heatmap {
+                let source = &heatmap[index * 4..index * 4 + 4];
+                color = blend(color, [source[0], source[1], source[2], source[3]], 1.0);
+            }
+            color = blend(color, options.fog_color, fog.min(1.0));
+            pixel.copy_from_slice(&color);
+        });
+    rgba
+}
+
+/// `fill` and `fill-opacity` attributes of an RGBA colour
+fn svg_fill(color: [u8; 4]) -> String {
+    format!(
+        "fill=\"#{:02x}{:02x}{:02x}\" fill-opacity=\"{:.3}\"",
+        color[0],
+        color[1],
+        color[2],
+        color[3] as f64 / 255.0
+    )
+}
+
+/// An SVG sized to the paper. The fog is a single even-odd path of the frame and all
+/// rings, the heatmap an embedded PNG.
+fn poster_svg(
+    rings: &[Vec<Coord>],
+    heatmap: Option<&[u8]>,
+    view: &View,
+    options: &PosterOptions,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let mut svg = String::new();
+    writeln!(svg, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+    writeln!(
+        svg,
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{}mm" height="{}mm" viewBox="0 0 {} {}">"#,
+        options.paper.width_mm, options.paper.height_mm, view.width, view.height
+    )?;
+    writeln!(
+        svg,
+        r#"<rect width="{}" height="{}" {}/>"#,
+        view.width,
+        view.height,
+        svg_fill(options.background)
+    )?;
+    if let Some(heatmap) = heatmap {
+        writeln!(
+            svg,
+            r#"<image width="{}" height="{}" href="data:image/png;base64,{}"/>"#,
+            view.width,
+            view.height,
+            BASE64.encode(encode_png(heatmap, view.width, view.height)?)
+        )?;
+    }
+
+    write!(
+        svg,
+        r#"<path fill-rule="evenodd" {} d="M0 0H{}V{}H0Z"#,
+        svg_fill(options.fog_color),
+        view.width,
+        view.height
+    )?;
+    for ring in rings {
+        let mut previous = None;
+        for (index, coord) in ring.iter().enumerate() {
+            // a tenth of a pixel is plenty, skip vertices that round to the same one
+            let rounded = (
+                (coord.x * 10.0).round() / 10.0,
+                (coord.y * 10.0).round() / 10.0,
+            );
+            if previous == Some(rounded) {
+                continue;
+            }
+            previous = Some(rounded);
+            let command = if index == 0 { 'M' } else { 'L' };
+            write!(svg, "{}{} {}", command, rounded.0, rounded.1)?;
+        }
+        svg.push('Z');
+    }
+    writeln!(svg, r#""/>"#)?;
+    writeln!(svg, "</svg>")?;
+
+    Ok(svg)
+}
@@ -0,0 +1,545 @@
+use chrono::{DateTime, Utc};
+use geo::{Coord, MultiPolygon, Rect};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write as _;
+use std::fs;
+use std::path::Path;
+
+use crate::coverage::CoverageIndex;
+use crate::io::{Output, suffixed_path};
+use crate::layers::{BUFFER_100_RADIUS_M, BUFFER_1000_RADIUS_M, densest_area};
+use crate::measure::{area_m2, mercator_scale};
+use crate::parsers::{Activity, TrackPoint, count_points};
+use crate::period::Period;
+use crate::pipeline::Error;
+use crate::region_stats::{RegionStats, region_stats};
+use crate::regions::Region;
+use crate::render::{PaperSize, PosterOptions, render_poster};
+use crate::tracks::escape_xml;
+use crate::{
+    OUT_FILE_100, OUT_FILE_1000, REPORT_DENSEST_FILE, REPORT_HTML_FILE, REPORT_MD_FILE,
+    REPORT_OVERVIEW_1000_FILE, REPORT_OVERVIEW_FILE,
+};
+
+/// Cells counted as new ground in the period they were first visited in, about the
+/// size of the 100m buffer around a point. Cheaper than the new ground layer, which cuts
+/// the buffers of earlier activities out of every activity, but only an approximation
+/// of it.
+const NEW_AREA_CELL_M: f64 = 100.0;
+
+/// Tells readers the new ground table is counted in cells, not buffers
+const NEW_AREA_NOTE: &str = "Counted in 100m grid cells, an approximation of the new \
+     ground layer, which cuts the buffers of earlier activities out of every activity.";
+
+/// Regions listed in the report, the most explored first
+const TOP_REGIONS: usize = 10;
+
+const THUMBNAIL_PAPER: PaperSize = PaperSize {
+    width_mm: 80.0,
+    height_mm: 60.0,
+};
+const THUMBNAIL_DPI: u32 = 96;
+/// The default colours of posters
+const THUMBNAIL_BACKGROUND: [u8; 4] = [0xf4, 0xf1, 0xea, 0xff];
+const THUMBNAIL_FOG: [u8; 4] = [0x22, 0x25, 0x2d, 0xff];
+
+/// Activities and points read by one parser, before any filtering.
+pub struct SourceStats {
+    pub name: &'static str,
+    pub activities: usize,
+    pub points: usize,
+}
+
+impl SourceStats {
+    pub fn new(name: &'static str, activities: &[Activity]) -> Self {
+        SourceStats {
+            name,
+            activities: activities.len(),
+            points: count_points(activities),
+        }
+    }
+}
+
+pub struct ReportOptions<'a> {
+    pub sources: Vec<SourceStats>,
+    /// Activities left after filtering
+    pub activity_count: usize,
+    /// Ground distance of all activities, see `distance_m`
+    pub distance_m: f64,
+    /// Period new ground is reported per
+    pub period: Period,
+    /// Regions to list the most explored of, none to leave them out
+    pub regions: &'a [Region],
+    /// Suffix of the layers of this run, e.g. `_walk`
+    pub suffix: &'a str,
+}
+
+/// Explored area of one buffer layer.
+struct ExploredArea {
+    name: &'static str,
+    radius_m: f64,
+    area_km2: f64,
+}
+
+/// Ground first visited in one period.
+struct PeriodArea {
+    key: String,
+    points: usize,
+    new_km2: f64,
+    total_km2: f64,
+}
+
+struct Thumbnail {
+    title: &'static str,
+    /// Relative to the report
+    filename: String,
+}
+
+struct Report<'a> {
+    generated_at: DateTime<Utc>,
+    first_seen: Option<DateTime<Utc>>,
+    last_seen: Option<DateTime<Utc>>,
+    sources: &'a [SourceStats],
+    activity_count: usize,
+    point_count: usize,
+    distance_km: f64,
+    explored: Vec<ExploredArea>,
+    period: Period,
+    new_area: Vec<PeriodArea>,
+    top_regions: Vec<RegionStats<'a>>,
+    thumbnails: Vec<Thumbnail>,
+}
+
+/// Ground distance in meters of projected activities, not counting jumps between
+/// points further apart than `max_gap_m`.
+pub fn distance_m(activities: &[Activity], max_gap_m: f64) -> f64 {
+    activities
+        .iter()
+        .flat_map(|activity| activity.points.windows(2))
+        .map(|pair| {
+            let (from, to) = (pair[0].point, pair[1].point);
+            (to.0 - from.0).x.hypot((to.0 - from.0).y) / mercator_scale((from.y() + to.y()) / 2.0)
+        })
+        .filter(|&length_m| length_m <= max_gap_m)
+        .sum()
+}
+
+/// Summarizes a run as HTML and Markdown, next to the layers it was built from.
+/// Expects the buffer layers of the run to be written already.
+pub fn write_report(
+    points: &[TrackPoint],
+    options: &ReportOptions,
+    output: &Output,
+) -> Result<(), Error> {
+    let layer_path = |file: &str| output.path(&suffixed_path(file, options.suffix));
+    let read_coverage = |file: &str| {
+        let path = layer_path(file);
+        CoverageIndex::from_flatgeobuf(Path::new(&path), output.projector)
+            .map_err(|e| Error::read(Path::new(&path), e))
+    };
+    let coverage_100 = read_coverage(OUT_FILE_100)?;
+    let coverage_1000 = read_coverage(OUT_FILE_1000)?;
+
+    let explored = vec![
+        ExploredArea {
+            name: "Buffer 100m",
+            radius_m: BUFFER_100_RADIUS_M,
+            area_km2: explored_km2(&coverage_100),
+        },
+        ExploredArea {
+            name: "Buffer 1000m",
+            radius_m: BUFFER_1000_RADIUS_M,
+            area_km2: explored_km2(&coverage_1000),
+        },
+    ];
+
+    let mut top_regions = Vec::new();
+    if !options.regions.is_empty() {
+        progress!(
+            output.progress,
+            "Intersecting {} regions with the coverage...",
+            options.regions.len()
+        );
+        top_regions = region_stats(options.regions, &coverage_100);
+        top_regions.retain(|row| row.explored_km2 > 0.0);
+        top_regions.sort_by(|a, b| b.explored_km2.total_cmp(&a.explored_km2));
+        top_regions.truncate(TOP_REGIONS);
+    }
+
+    let thumbnails = write_thumbnails(
+        points,
+        &coverage_100,
+        &coverage_1000,
+        options.suffix,
+        output,
+    )?;
+
+    let times = || points.iter().filter_map(|p| p.time);
+    let report = Report {
+        generated_at: Utc::now(),
+        first_seen: times().min(),
+        last_seen: times().max(),
+        sources: &options.sources,
+        activity_count: options.activity_count,
+        point_count: points.len(),
+        distance_km: options.distance_m / 1_000.0,
+        explored,
+        period: options.period,
+        new_area: new_area_per_period(points, options.period),
+        top_regions,
+        thumbnails,
+    };
+
+    let markdown_path = layer_path(REPORT_MD_FILE);
+    let markdown = report
+        .markdown()
+        .map_err(|e| Error::write(&markdown_path, e))?;
+    fs::write(&markdown_path, markdown).map_err(|e| Error::write(&markdown_path, e))?;
+    progress!(output.progress, "✓ Wrote report to {}", markdown_path);
+
+    let html_path = layer_path(REPORT_HTML_FILE);
+    let html = report.html().map_err(|e| Error::write(&html_path, e))?;
+    fs::write(&html_path, html).map_err(|e| Error::write(&html_path, e))?;
+    progress!(output.progress, "✓ Wrote report to {}", html_path);
+
+    Ok(())
+}
+
+fn explored_km2(coverage: &CoverageIndex) -> f64 {
+    // buffer polygons are dissolved and don't overlap
+    area_m2(&MultiPolygon::new(coverage.polygons().to_vec())) / 1_000_000.0
+}
+
+/// Area of the cells first visited in every period, in chronological order. Points
+/// without a timestamp can't be assigned and are skipped.
+fn new_area_per_period(points: &[TrackPoint], period: Period) -> Vec<PeriodArea> {
+    let mut first_visits: HashMap<(i64, i64), DateTime<Utc>> = HashMap::new();
+    let mut points_per_period: BTreeMap<String, usize> = BTreeMap::new();
+    for track_point in points {
+        let Some(time) = track_point.time else {
+            continue;
+        };
+        *points_per_period.entry(period.key(&time)).or_default() += 1;
+        let cell = (
+            (track_point.point.x() / NEW_AREA_CELL_M).floor() as i64,
+            (track_point.point.y() / NEW_AREA_CELL_M).floor() as i64,
+        );
+        first_visits
+            .entry(cell)
+            .and_modify(|first| *first = (*first).min(time))
+            .or_insert(time);
+    }
+
+    let mut new_m2: BTreeMap<String, f64> = BTreeMap::new();
+    for ((_, y), time) in first_visits {
+        // cells are smaller on the ground the further they are from the equator
+        let side_m = NEW_AREA_CELL_M / mercator_scale((y as f64 + 0.5) * NEW_AREA_CELL_M);
+        *new_m2.entry(period.key(&time)).or_default() += side_m * side_m;
+    }
+
+    let mut total_km2 = 0.0;
+    points_per_period
+        .into_iter()
+        .map(|(key, points)| {
+            let new_km2 = new_m2.get(&key).copied().unwrap_or_default() / 1_000_000.0;
+            total_km2 += new_km2;
+            PeriodArea {
+                key,
+                points,
+                new_km2,
+                total_km2,
+            }
+        })
+        .collect()
+}
+
+/// Renders small posters of all of the coverage and of the densest area.
+fn write_thumbnails(
+    points: &[TrackPoint],
+    coverage_100: &CoverageIndex,
+    coverage_1000: &CoverageIndex,
+    suffix: &str,
+    output: &Output,
+) -> Result<Vec<Thumbnail>, Error> {
+    let densest = densest_area(points).map(|(_, rect)| {
+        // some fog around the points, the buffers reach beyond them
+        let margin = BUFFER_1000_RADIUS_M;
+        Rect::new(
+            Coord {
+                x: rect.min().x - margin,
+                y: rect.min().y - margin,
+            },
+            Coord {
+                x: rect.max().x + margin,
+                y: rect.max().y + margin,
+            },
+        )
+    });
+    let views = [
+        (
+            "Everything, 100m buffer",
+            coverage_100,
+            None,
+            REPORT_OVERVIEW_FILE,
+        ),
+        (
+            "Everything, 1000m buffer",
+            coverage_1000,
+            None,
+            REPORT_OVERVIEW_1000_FILE,
+        ),
+        (
+            "Most visited area",
+            coverage_100,
+            densest,
+            REPORT_DENSEST_FILE,
+        ),
+    ];
+
+    let mut thumbnails = Vec::new();
+    for (title, coverage, bbox, file) in views {
+        if coverage.bounds().is_none() {
+            continue;
+        }
+        let path = output.path(&suffixed_path(file, suffix));
+        let options = PosterOptions {
+            bbox,
+            paper: THUMBNAIL_PAPER,
+            dpi: THUMBNAIL_DPI,
+            background: THUMBNAIL_BACKGROUND,
+            fog_color: THUMBNAIL_FOG,
+            heatmap: None,
+        };
+        render_poster(
+            coverage,
+            &options,
+            Path::new(&path),
+            output.progress,
+            output.projector,
+        )
+        .map_err(|e| Error::write(&path, e))?;
+        thumbnails.push(Thumbnail {
+            title,
+            filename: file_name(&path),
+        });
+    }
+    Ok(thumbnails)
+}
+
+fn file_name(path: &str) -> String {
+    Path::new(path)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or(path)
+        .to_string()
+}
+
+fn format_date(time: Option<DateTime<Utc>>) -> String {
+    time.map(|time| time.format("%Y-%m-%d").to_string())
+        .unwrap_or_else(|| "-".to_string())
+}
+
+/// Text safe to use in a Markdown table cell
+/// `year` as `Year`, for table headers
+fn capitalize(text: &str) -> String {
+    let mut chars = text.chars();
+    chars
+        .next()
+        .map(|first| first.to_uppercase().chain(chars).collect())
+        .unwrap_or_default()
+}
+
+fn escape_markdown(text: &str) -> String {
+    text.replace('|', "\\|")
+}
+
+impl Report<'_> {
+    fn markdown(&self) -> Result<String, std::fmt::Error> {
+        let mut md = String::new();
+        writeln!(md, "# Exploration report\n")?;
+        writeln!(
+            md,
+            "Generated {} from {} points in {} activities, recorded {} to {}.\n",
+            self.generated_at.format("%Y-%m-%d %H:%M UTC"),
+            self.point_count,
+            self.activity_count,
+            format_date(self.first_seen),
+            format_date(self.last_seen)
+        )?;
+        writeln!(md, "Distance travelled: {:.1} km\n", self.distance_km)?;
+
+        writeln!(md, "## Explored area\n")?;
+        writeln!(md, "| Layer | Radius | Area |")?;
+        writeln!(md, "| --- | ---: | ---: |")?;
+        for row in &self.explored {
+            writeln!(
+                md,
+                "| {} | {} m | {:.2} km² |",
+                row.name, row.radius_m, row.area_km2
+            )?;
+        }
+
+        writeln!(md, "\n## Sources\n")?;
+        writeln!(md, "| Source | Activities | Points |")?;
+        writeln!(md, "| --- | ---: | ---: |")?;
+        for source in self.sources {
+            writeln!(
+                md,
+                "| {} | {} | {} |",
+                source.name, source.activities, source.points
+            )?;
+        }
+
+        writeln!(md, "\n## New ground per {}\n", self.period.name())?;
+        writeln!(md, "{}\n", NEW_AREA_NOTE)?;
+        writeln!(
+            md,
+            "| {} | Points | New | Total |",
+            capitalize(self.period.name())
+        )?;
+        writeln!(md, "| --- | ---: | ---: | ---: |")?;
+        for row in &self.new_area {
+            writeln!(
+                md,
+                "| {} | {} | {:.2} km² | {:.2} km² |",
+                row.key, row.points, row.new_km2, row.total_km2
+            )?;
+        }
+
+        if !self.top_regions.is_empty() {
+            writeln!(md, "\n## Most explored regions\n")?;
+            writeln!(md, "| Region | Explored | Area | Share |")?;
+            writeln!(md, "| --- | ---: | ---: | ---: |")?;
+            for row in &self.top_regions {
+                writeln!(
+                    md,
+                    "| {} | {} km² | {} km² | {}% |",
+                    escape_markdown(row.name),
+                    row.explored_km2,
+                    row.area_km2,
+                    row.explored_percent
+                )?;
+            }
+        }
+
+        if !self.thumbnails.is_empty() {
+            writeln!(md, "\n## Maps\n")?;
+            for thumbnail in &self.thumbnails {
+                writeln!(md, "![{}]({})", thumbnail.title, thumbnail.filename)?;
+            }
+        }
+
+        Ok(md)
+    }
+
+    /// A standalone page, the thumbnails are expected next to it.
+    fn html(&self) -> Result<String, std::fmt::Error> {
+        let mut html = String::new();
+        writeln!(html, "<!DOCTYPE html>")?;
+        writeln!(html, r#"<html lang="en">"#)?;
+        writeln!(
+            html,
+            r#"<head><meta charset="utf-8"><title>Exploration report</title>"#
+        )?;
+        writeln!(
+            html,
+            "<style>body{{font-family:sans-serif;max-width:60rem;margin:2rem auto;padding:0 1rem;color:#22252d}}\
+             table{{border-collapse:collapse;margin-bottom:1.5rem}}\
+             th,td{{padding:.25rem .75rem;border-bottom:1px solid #ddd}}\
+             td.n{{text-align:right}}figure{{display:inline-block;margin:0 1rem 1rem 0}}</style>"
+        )?;
+        writeln!(html, "</head>\n<body>\n<h1>Exploration report</h1>")?;
+        writeln!(
+            html,
+            "<p>Generated {} from {} points in {} activities, recorded {} to {}.</p>",
+            self.generated_at.format("%Y-%m-%d %H:%M UTC"),
+            self.point_count,
+            self.activity_count,
+            format_date(self.first_seen),
+            format_date(self.last_seen)
+        )?;
+        writeln!(
+            html,
+            "<p>Distance travelled: {:.1} km</p>",
+            self.distance_km
+        )?;
+
+        writeln!(html, "<h2>Explored area</h2>")?;
+        writeln!(
+            html,
+            "<table><tr><th>Layer</th><th>Radius</th><th>Area</th></tr>"
+        )?;
+        for row in &self.explored {
+            writeln!(
+                html,
+                r#"<tr><td>{}</td><td class="n">{} m</td><td class="n">{:.2} km²</td></tr>"#,
+                row.name, row.radius_m, row.area_km2
+            )?;
+        }
+        writeln!(html, "</table>")?;
+
+        writeln!(html, "<h2>Sources</h2>")?;
+        writeln!(
+            html,
+            "<table><tr><th>Source</th><th>Activities</th><th>Points</th></tr>"
+        )?;
+        for source in self.sources {
+            writeln!(
+                html,
+                r#"<tr><td>{}</td><td class="n">{}</td><td class="n">{}</td></tr>"#,
+                source.name, source.activities, source.points
+            )?;
+        }
+        writeln!(html, "</table>")?;
+
+        writeln!(html, "<h2>New ground per {}</h2>", self.period.name())?;
+        writeln!(html, "<p>{}</p>", NEW_AREA_NOTE)?;
+        writeln!(
+            html,
+            "<table><tr><th>{}</th><th>Points</th><th>New</th><th>Total</th></tr>",
+            capitalize(self.period.name())
+        )?;
+        for row in &self.new_area {
+            writeln!(
+                html,
+                r#"<tr><td>{}</td><td class="n">{}</td><td class="n">{:.2} km²</td><td class="n">{:.2} km²</td></tr>"#,
+                row.key, row.points, row.new_km2, row.total_km2
+            )?;
+        }
+        writeln!(html, "</table>")?;
+
+        if !self.top_regions.is_empty() {
+            writeln!(html, "<h2>Most explored regions</h2>")?;
+            writeln!(
+                html,
+                "<table><tr><th>Region</th><th>Explored</th><th>Area</th><th>Share</th></tr>"
+            )?;
+            for row in &self.top_regions {
+                writeln!(
+                    html,
+                    r#"<tr><td>{}</td><td class="n">{} km²</td><td class="n">{} km²</td><td class="n">{}%</td></tr>"#,
+                    escape_xml(row.name),
+                    row.explored_km2,
+                    row.area_km2,
+                    row.explored_percent
+                )?;
+            }
+            writeln!(html, "</table>")?;
+        }
+
+        if !self.thumbnails.is_empty() {
+            writeln!(html, "<h2>Maps</h2>")?;
+            for thumbnail in &self.thumbnails {
+                writeln!(
+                    html,
+                    r#"<figure><img src="{}" alt="{1}"><figcaption>{1}</figcaption></figure>"#,
+                    escape_xml(&thumbnail.filename),
+                    thumbnail.title
+                )?;
+            }
+        }
+
+        writeln!(html, "</body>\n</html>")?;
+        Ok(html)
+    }
+}
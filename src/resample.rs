@@ -0,0 +1,70 @@
+use chrono::Duration;
+use geo::{Distance, Haversine, InterpolatePoint};
+
+use crate::parsers::{Activity, TrackPoint};
+
+pub struct ResampleOptions {
+    /// Distance between resampled points, e.g. 10.0
+    pub spacing_m: f64,
+    /// Consecutive points further apart are not connected, e.g. 500.0
+    pub max_gap_m: f64,
+    /// Consecutive points recorded further apart are not connected, e.g. 5 minutes
+    pub max_gap_time: Duration,
+}
+
+/// Replaces the points of an activity with points every `spacing_m` meters along its track.
+/// This fills gaps of low-frequency recordings and thins out over-sampled ones. Gaps above
+/// the limits are kept as they are, the track restarts at the next point.
+/// Expects WGS84 coordinates.
+pub fn resample(activity: &mut Activity, options: &ResampleOptions) {
+    let Some(&first) = activity.points.first() else {
+        return;
+    };
+
+    let mut resampled = vec![first];
+    // distance travelled since the last emitted point
+    let mut carried = 0.0;
+
+    for pair in activity.points.windows(2) {
+        let (from, to) = (pair[0], pair[1]);
+        let length = Haversine.distance(from.point, to.point);
+
+        if is_gap(&from, &to, length, options) {
+            // close the section before the gap and start a new one
+            if carried > 0.0 {
+                resampled.push(from);
+            }
+            resampled.push(to);
+            carried = 0.0;
+            continue;
+        }
+
+        let mut position = options.spacing_m - carried;
+        while position <= length {
+            resampled.push(interpolate(&from, &to, position / length));
+            position += options.spacing_m;
+        }
+        carried = length - (position - options.spacing_m);
+    }
+
+    // keep the end of the track, unless it was just emitted
+    let last = activity.points[activity.points.len() - 1];
+    if carried > 0.0 {
+        resampled.push(last);
+    }
+
+    activity.points = resampled;
+}
+
+fn is_gap(from: &TrackPoint, to: &TrackPoint, length: f64, options: &ResampleOptions) -> bool {
+    let too_long_ago = match (from.time, to.time) {
+        (Some(from), Some(to)) => to - from > options.max_gap_time,
+        _ => false,
+    };
+    length > options.max_gap_m || too_long_ago
+}
+
+fn interpolate(from: &TrackPoint, to: &TrackPoint, ratio: f64) -> TrackPoint {
+    let point = Haversine.point_at_ratio_between(from.point, to.point, ratio);
+    TrackPoint::new(point, from.time_towards(to, ratio), from.kind)
+}
@@ -0,0 +1,198 @@
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use tiny_http::{Header, Method, Request, Response, Server, StatusCode};
+
+/// Serves the built UI and the generated layers, e.g. `http://127.0.0.1:8080`.
+pub struct ServeOptions {
+    pub address: String,
+    /// Directories files are looked up in, the first one containing a file wins
+    pub roots: Vec<PathBuf>,
+    pub threads: usize,
+}
+
+/// Listens on the address of `options`, so a taken port fails before serving starts.
+pub fn bind(options: &ServeOptions) -> Result<Server, Box<dyn std::error::Error>> {
+    Ok(Server::http(&options.address).map_err(|e| e.to_string())?)
+}
+
+/// Runs the bound server until the process is stopped. PMTiles are read by the UI with
+/// HTTP range requests, so every file is served with range support.
+pub fn serve(server: Server, options: &ServeOptions) -> Result<(), Box<dyn std::error::Error>> {
+    let server = Arc::new(server);
+
+    let workers: Vec<_> = (0..options.threads.max(1))
+        .map(|_| {
+            let server = Arc::clone(&server);
+            let roots = options.roots.clone();
+            thread::spawn(move || {
+                for request in server.incoming_requests() {
+                    if let Err(e) = handle(request, &roots) {
+                        eprintln!("✗ Failed to respond: {}", e);
+                    }
+                }
+            })
+        })
+        .collect();
+    for worker in workers {
+        worker.join().map_err(|_| "server thread panicked")?;
+    }
+
+    Ok(())
+}
+
+fn handle(request: Request, roots: &[PathBuf]) -> std::io::Result<()> {
+    if !matches!(request.method(), Method::Get | Method::Head) {
+        return request.respond(Response::empty(405));
+    }
+    let Some(path) = resolve(request.url(), roots) else {
+        return request.respond(Response::from_string("Not found").with_status_code(404));
+    };
+
+    let mut file = File::open(&path)?;
+    let length = file.metadata()?.len();
+    let content_type = header("Content-Type", content_type(&path));
+    let accept_ranges = header("Accept-Ranges", "bytes");
+
+    let range = request
+        .headers()
+        .iter()
+        .find(|h| h.field.equiv("Range"))
+        .map(|h| parse_range(h.value.as_str(), length));
+
+    match range {
+        // without a (supported) range, the whole file is sent
+        None | Some(Range::Unsupported) => request.respond(Response::new(
+            StatusCode(200),
+            vec![content_type, accept_ranges],
+            file,
+            Some(length as usize),
+            None,
+        )),
+        Some(Range::Unsatisfiable) => request.respond(
+            Response::empty(416)
+                .with_header(header("Content-Range", &format!("bytes */{}", length))),
+        ),
+        Some(Range::Bytes { start, end }) => {
+            file.seek(SeekFrom::Start(start))?;
+            let content_range = header(
+                "Content-Range",
+                &format!("bytes {}-{}/{}", start, end, length),
+            );
+            request.respond(Response::new(
+                StatusCode(206),
+                vec![content_type, accept_ranges, content_range],
+                file.take(end - start + 1),
+                Some((end - start + 1) as usize),
+                None,
+            ))
+        }
+    }
+}
+
+fn header(field: &str, value: &str) -> Header {
+    Header::from_bytes(field.as_bytes(), value.as_bytes()).expect("header to be valid")
+}
+
+/// The file for a request URL in the first root that has it. `/` is the index, and
+/// paths leaving the roots are rejected.
+fn resolve(url: &str, roots: &[PathBuf]) -> Option<PathBuf> {
+    let path = url.split(['?', '#']).next().unwrap_or_default();
+    let path = percent_decode(path.trim_start_matches('/'))?;
+    let path = if path.is_empty() || path.ends_with('/') {
+        format!("{}index.html", path)
+    } else {
+        path
+    };
+    let relative = Path::new(&path);
+    if !relative
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)))
+    {
+        return None;
+    }
+
+    roots
+        .iter()
+        .map(|root| root.join(relative))
+        .find(|candidate| candidate.is_file())
+}
+
+fn percent_decode(text: &str) -> Option<String> {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+enum Range {
+    /// Inclusive byte range within the file
+    Bytes {
+        start: u64,
+        end: u64,
+    },
+    Unsatisfiable,
+    /// e.g. multiple ranges, answered with the whole file
+    Unsupported,
+}
+
+/// Parses a single `bytes=start-end`, `bytes=start-` or `bytes=-suffix` range.
+fn parse_range(value: &str, length: u64) -> Range {
+    let Some((start, end)) = value
+        .trim()
+        .strip_prefix("bytes=")
+        .filter(|ranges| !ranges.contains(','))
+        .and_then(|range| range.split_once('-'))
+    else {
+        return Range::Unsupported;
+    };
+    let (start, end) = match (start.trim(), end.trim()) {
+        ("", "") => return Range::Unsupported,
+        ("", suffix) => match suffix.parse::<u64>() {
+            Ok(0) => return Range::Unsatisfiable,
+            Ok(suffix) => (length.saturating_sub(suffix), length.saturating_sub(1)),
+            Err(_) => return Range::Unsupported,
+        },
+        (start, "") => match start.parse::<u64>() {
+            Ok(start) => (start, length.saturating_sub(1)),
+            Err(_) => return Range::Unsupported,
+        },
+        (start, end) => match (start.parse::<u64>(), end.parse::<u64>()) {
+            (Ok(start), Ok(end)) if start <= end => (start, end.min(length.saturating_sub(1))),
+            _ => return Range::Unsupported,
+        },
+    };
+    if start >= length {
+        Range::Unsatisfiable
+    } else {
+        Range::Bytes { start, end }
+    }
+}
+
+fn content_type(path: &Path) -> &'static str {
+    match path.extension().and_then(|extension| extension.to_str()) {
+        Some("html") => "text/html; charset=utf-8",
+        Some("js") | Some("mjs") => "text/javascript; charset=utf-8",
+        Some("css") => "text/css; charset=utf-8",
+        Some("json") => "application/json",
+        Some("geojson") => "application/geo+json",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("jpg") | Some("jpeg") => "image/jpeg",
+        Some("ico") => "image/x-icon",
+        Some("woff2") => "font/woff2",
+        _ => "application/octet-stream",
+    }
+}
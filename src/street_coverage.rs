@@ -0,0 +1,263 @@
+use geo::{Coord, LineString, Point};
+use rayon::prelude::*;
+use serde::Serialize;
+use std::collections::HashMap;
+
+use crate::coverage::CoverageIndex;
+use crate::io::{Output, write_features_to_flatgeobuf};
+use crate::measure::{length_m, mercator_scale, percent};
+use crate::osm::{RoadNetwork, Way};
+use crate::regions::Region;
+use crate::{STREET_COVERAGE_AREAS_FILE, STREET_COVERAGE_FILE, UNCOVERED_STREETS_FILE};
+
+/// Distance between coverage checks along a street, in projected meters.
+const SAMPLE_SPACING: f64 = 2.0;
+
+/// How much of one way lies within the coverage.
+pub struct WayCoverage<'a> {
+    pub way: &'a Way,
+    pub length_m: f64,
+    pub covered_m: f64,
+    /// Stretches of the way outside the coverage
+    pub uncovered: Vec<LineString>,
+    /// A point halfway along the way to assign it to regions
+    pub midpoint: Point,
+}
+
+/// Checks every way of the network against the coverage, skipping `excluded_highways`.
+pub fn cover_ways<'a>(
+    network: &'a RoadNetwork,
+    coverage: &CoverageIndex,
+    excluded_highways: &[String],
+) -> Vec<WayCoverage<'a>> {
+    network
+        .ways
+        .par_iter()
+        .filter(|way| !excluded_highways.contains(&way.highway))
+        .map(|way| {
+            let line: LineString = way.nodes.iter().map(|&i| network.nodes[i]).collect();
+            cover_way(way, &line, coverage)
+        })
+        .collect()
+}
+
+/// Walks along the way in small steps and checks the middle of every step,
+/// consecutive uncovered steps make up one uncovered stretch.
+fn cover_way<'a>(way: &'a Way, line: &LineString, coverage: &CoverageIndex) -> WayCoverage<'a> {
+    let mut covered_m = 0.0;
+    let mut uncovered = Vec::new();
+    let mut stretch: Vec<Coord> = Vec::new();
+
+    for segment in line.lines() {
+        let delta = segment.delta();
+        let steps = (delta.x.hypot(delta.y) / SAMPLE_SPACING).ceil().max(1.0) as usize;
+        for step in 0..steps {
+            let start = segment.start + delta * (step as f64 / steps as f64);
+            let end = segment.start + delta * ((step + 1) as f64 / steps as f64);
+            let middle = Point::from((start + end) / 2.0);
+
+            if coverage.covers(&middle) {
+                covered_m += (end - start).x.hypot((end - start).y) / mercator_scale(middle.y());
+                if stretch.len() >= 2 {
+                    uncovered.push(LineString::new(std::mem::take(&mut stretch)));
+                }
+                stretch.clear();
+            } else {
+                if stretch.is_empty() {
+                    stretch.push(start);
+                }
+                stretch.push(end);
+            }
+        }
+    }
+    if stretch.len() >= 2 {
+        uncovered.push(LineString::new(stretch));
+    }
+
+    WayCoverage {
+        way,
+        length_m: length_m(line),
+        covered_m,
+        uncovered,
+        midpoint: Point::from(line.0[line.0.len() / 2]),
+    }
+}
+
+#[derive(Serialize)]
+struct UncoveredStreet<'a> {
+    geo: LineString,
+    way_id: i64,
+    name: &'a str,
+    highway: &'a str,
+    length_m: f64,
+}
+
+/// Writes the uncovered stretches of all ways for the UI.
+pub fn write_uncovered(
+    ways: &[WayCoverage],
+    output: &Output,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let features: Vec<UncoveredStreet> = ways
+        .iter()
+        .flat_map(|coverage| {
+            coverage.uncovered.iter().map(|line| {
+                Ok(UncoveredStreet {
+                    geo: output.project(line)?,
+                    way_id: coverage.way.id,
+                    name: &coverage.way.name,
+                    highway: &coverage.way.highway,
+                    length_m: length_m(line).round(),
+                })
+            })
+        })
+        .collect::<Result<_, String>>()?;
+
+    let path = output.path(UNCOVERED_STREETS_FILE);
+    progress!(output.progress, "Writing uncovered streets to {}...", path);
+    write_features_to_flatgeobuf(&features, &path, output)?;
+    progress!(
+        output.progress,
+        "✓ Successfully wrote {} uncovered stretches to {}",
+        features.len(),
+        path
+    );
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct StreetRow<'a> {
+    name: &'a str,
+    /// Lowest OSM id of the street's ways, to tell streets of the same name apart
+    way_id: i64,
+    ways: usize,
+    length_m: f64,
+    covered_m: f64,
+    covered_percent: f64,
+}
+
+#[derive(Serialize)]
+struct AreaRow<'a> {
+    area: &'a str,
+    admin_level: Option<&'a str>,
+    length_m: f64,
+    covered_m: f64,
+    covered_percent: f64,
+}
+
+/// Writes the coverage per street, unnamed ways only count towards the areas,
+/// and per region plus an `overall` row.
+pub fn write_statistics(
+    ways: &[WayCoverage],
+    regions: &[Region],
+    output: &Output,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut rows: Vec<StreetRow> = streets(ways)
+        .into_iter()
+        .map(|street| {
+            let (length, covered) = sum_lengths(street.iter().map(|&i| &ways[i]));
+            StreetRow {
+                name: &ways[street[0]].way.name,
+                way_id: street
+                    .iter()
+                    .map(|&i| ways[i].way.id)
+                    .min()
+                    .unwrap_or_default(),
+                ways: street.len(),
+                length_m: length.round(),
+                covered_m: covered.round(),
+                covered_percent: percent(covered, length),
+            }
+        })
+        .collect();
+    rows.sort_by(|a, b| a.name.cmp(b.name).then(a.way_id.cmp(&b.way_id)));
+
+    let streets_path = output.path(STREET_COVERAGE_FILE);
+    let mut writer = csv::Writer::from_path(&streets_path)?;
+    for row in rows {
+        writer.serialize(row)?;
+    }
+    writer.flush()?;
+    progress!(
+        output.progress,
+        "✓ Wrote street coverage to {}",
+        streets_path
+    );
+
+    let areas_path = output.path(STREET_COVERAGE_AREAS_FILE);
+    let mut writer = csv::Writer::from_path(&areas_path)?;
+    for region in regions {
+        let (length, covered) = sum_lengths(ways.iter().filter(|c| region.contains(&c.midpoint)));
+        writer.serialize(AreaRow {
+            area: &region.name,
+            admin_level: region.admin_level.as_deref(),
+            length_m: length.round(),
+            covered_m: covered.round(),
+            covered_percent: percent(covered, length),
+        })?;
+    }
+
+    let (length, covered) = sum_lengths(ways.iter());
+    writer.serialize(AreaRow {
+        area: "overall",
+        admin_level: None,
+        length_m: length.round(),
+        covered_m: covered.round(),
+        covered_percent: percent(covered, length),
+    })?;
+    writer.flush()?;
+    progress!(output.progress, "✓ Wrote area coverage to {}", areas_path);
+
+    progress!(
+        output.progress,
+        "Covered {:.1} of {:.1} km of streets ({}%)",
+        covered / 1000.0,
+        length / 1000.0,
+        percent(covered, length)
+    );
+
+    Ok(())
+}
+
+/// (total length, covered length) of some ways
+fn sum_lengths<'a, 'b: 'a>(ways: impl Iterator<Item = &'a WayCoverage<'b>>) -> (f64, f64) {
+    ways.fold((0.0, 0.0), |(length, covered), c| {
+        (length + c.length_m, covered + c.covered_m)
+    })
+}
+
+/// Named ways grouped into streets: ways of the same name that share a node, so every
+/// "Hauptstraße" of an extract stays its own street. Returns the indices into `ways`.
+fn streets(ways: &[WayCoverage]) -> Vec<Vec<usize>> {
+    // union-find over the ways, joined by their first way at every (name, node)
+    let mut parent: Vec<usize> = (0..ways.len()).collect();
+
+    let mut first_at: HashMap<(&str, usize), usize> = HashMap::new();
+    for (i, coverage) in ways.iter().enumerate() {
+        if coverage.way.name.is_empty() {
+            continue;
+        }
+        for &node in &coverage.way.nodes {
+            let other = *first_at.entry((&coverage.way.name, node)).or_insert(i);
+            let (a, b) = (root(&mut parent, i), root(&mut parent, other));
+            parent[a] = b;
+        }
+    }
+
+    let mut streets: HashMap<usize, Vec<usize>> = HashMap::new();
+    for (i, coverage) in ways.iter().enumerate() {
+        if !coverage.way.name.is_empty() {
+            let street = root(&mut parent, i);
+            streets.entry(street).or_default().push(i);
+        }
+    }
+    streets.into_values().collect()
+}
+
+/// Representative of the set `i` belongs to, halving the path on the way.
+fn root(parent: &mut [usize], mut i: usize) -> usize {
+    while parent[i] != i {
+        parent[i] = parent[parent[i]];
+        i = parent[i];
+    }
+    i
+}
@@ -0,0 +1,304 @@
+use geo::{Coord, Polygon, Rect};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use crate::io::{
+    Output, pmtiles_filename, suffixed_path, write_features_to_flatgeobuf, write_json,
+};
+use crate::layers::{GeometryType, LayerEntry, LayerSummary, union_rect};
+use crate::measure::{HALF_WORLD_M, mercator_scale};
+use crate::parsers::Activity;
+use crate::pipeline::Error;
+use crate::{TILES_FILE, TILES_STATS_FILE};
+
+/// x and y of a slippy map tile
+type Tile = (u32, u32);
+
+/// Visited tiles of one zoom level, e.g. 14 for explorer tiles or 17 for squadrats.
+pub struct TileCoverage {
+    pub zoom: u8,
+    pub tiles: HashSet<Tile>,
+}
+
+/// Metrics as VeloViewer and StatsHunters count them.
+#[derive(Serialize)]
+pub struct TileStats {
+    pub zoom: u8,
+    pub tiles: usize,
+    /// Edge length of the largest square of visited tiles
+    pub max_square: u32,
+    /// Size of the largest connected group of tiles whose four neighbours are visited
+    pub max_cluster: usize,
+}
+
+#[derive(Serialize)]
+struct TileFeature {
+    geo: Polygon,
+    x: u32,
+    y: u32,
+    cluster: bool,
+    max_square: bool,
+}
+
+/// Collects the tiles at `zoom` the activities passed through. Consecutive points closer
+/// than `max_gap_m` are connected, so tiles crossed between two fixes count as well.
+/// Expects EPSG_METERS coordinates, which map linearly onto tiles.
+pub fn visited_tiles(activities: &[Activity], zoom: u8, max_gap_m: f64) -> TileCoverage {
+    let tiles_per_side = f64::from(1u32 << zoom);
+    let to_tile_space = |coord: Coord| Coord {
+        x: (coord.x + HALF_WORLD_M) / (2.0 * HALF_WORLD_M) * tiles_per_side,
+        y: (HALF_WORLD_M - coord.y) / (2.0 * HALF_WORLD_M) * tiles_per_side,
+    };
+
+    let mut tiles = HashSet::new();
+    for activity in activities {
+        for point in &activity.points {
+            tiles.insert(tile_at(to_tile_space(point.point.0), zoom));
+        }
+        for pair in activity.points.windows(2) {
+            let (from, to) = (pair[0].point, pair[1].point);
+            let length_m = (to.0 - from.0).x.hypot((to.0 - from.0).y)
+                / mercator_scale((from.y() + to.y()) / 2.0);
+            if length_m <= max_gap_m {
+                trace_segment(to_tile_space(from.0), to_tile_space(to.0), zoom, &mut tiles);
+            }
+        }
+    }
+
+    TileCoverage { zoom, tiles }
+}
+
+fn tile_at(coord: Coord, zoom: u8) -> Tile {
+    let max = (1u32 << zoom) - 1;
+    let clamp = |value: f64| (value.floor().max(0.0) as u32).min(max);
+    (clamp(coord.x), clamp(coord.y))
+}
+
+/// Adds every tile a straight line in tile space crosses, walking from tile border to
+/// tile border (Amanatides & Woo).
+fn trace_segment(from: Coord, to: Coord, zoom: u8, tiles: &mut HashSet<Tile>) {
+    let (mut x, mut y) = (from.x.floor(), from.y.floor());
+    let delta = to - from;
+
+    // per axis: direction, distance in t to the next border, distance in t between borders
+    let axis = |start: f64, cell: f64, delta: f64| {
+        if delta > 0.0 {
+            (1.0, (cell + 1.0 - start) / delta, 1.0 / delta)
+        } else if delta < 0.0 {
+            (-1.0, (start - cell) / -delta, -1.0 / delta)
+        } else {
+            (0.0, f64::INFINITY, f64::INFINITY)
+        }
+    };
+    let (step_x, mut next_x, t_step_x) = axis(from.x, x, delta.x);
+    let (step_y, mut next_y, t_step_y) = axis(from.y, y, delta.y);
+
+    while next_x.min(next_y) <= 1.0 {
+        if next_x < next_y {
+            x += step_x;
+            next_x += t_step_x;
+        } else {
+            y += step_y;
+            next_y += t_step_y;
+        }
+        tiles.insert(tile_at(Coord { x, y }, zoom));
+    }
+}
+
+impl TileCoverage {
+    /// Tiles whose four neighbours are visited as well.
+    fn cluster_tiles(&self) -> HashSet<Tile> {
+        self.tiles
+            .iter()
+            .copied()
+            .filter(|&tile| neighbours(tile).all(|n| self.tiles.contains(&n)))
+            .collect()
+    }
+
+    /// Size of the largest connected group of cluster tiles.
+    fn max_cluster(cluster_tiles: &HashSet<Tile>) -> usize {
+        let mut seen: HashSet<Tile> = HashSet::new();
+        let mut largest = 0;
+
+        for &start in cluster_tiles {
+            if !seen.insert(start) {
+                continue;
+            }
+            let mut size = 0;
+            let mut queue = VecDeque::from([start]);
+            while let Some(tile) = queue.pop_front() {
+                size += 1;
+                for neighbour in neighbours(tile) {
+                    if cluster_tiles.contains(&neighbour) && seen.insert(neighbour) {
+                        queue.push_back(neighbour);
+                    }
+                }
+            }
+            largest = largest.max(size);
+        }
+
+        largest
+    }
+
+    /// Largest square of visited tiles as (top left tile, edge length).
+    fn max_square(&self) -> Option<(Tile, u32)> {
+        let mut sorted: Vec<Tile> = self.tiles.iter().copied().collect();
+        sorted.sort_unstable();
+
+        // edge length of the largest square ending at each tile (its bottom right corner),
+        // the tiles to the left and above are always computed first
+        let mut sizes: HashMap<Tile, u32> = HashMap::with_capacity(sorted.len());
+        let mut best: Option<(Tile, u32)> = None;
+
+        for (x, y) in sorted {
+            let size_at =
+                |tile: Option<Tile>| tile.and_then(|t| sizes.get(&t)).copied().unwrap_or(0);
+            let left = size_at(x.checked_sub(1).map(|x| (x, y)));
+            let up = size_at(y.checked_sub(1).map(|y| (x, y)));
+            let diagonal = size_at(x.checked_sub(1).zip(y.checked_sub(1)));
+            let size = 1 + left.min(up).min(diagonal);
+            sizes.insert((x, y), size);
+
+            if best.is_none_or(|(_, best_size)| size > best_size) {
+                best = Some(((x + 1 - size, y + 1 - size), size));
+            }
+        }
+
+        best
+    }
+
+    pub fn stats(&self) -> TileStats {
+        TileStats {
+            zoom: self.zoom,
+            tiles: self.tiles.len(),
+            max_square: self.max_square().map_or(0, |(_, size)| size),
+            max_cluster: Self::max_cluster(&self.cluster_tiles()),
+        }
+    }
+
+    /// The bounds of a tile in EPSG_METERS.
+    fn bounds(&self, (x, y): Tile) -> Rect {
+        let tile_size = 2.0 * HALF_WORLD_M / f64::from(1u32 << self.zoom);
+        let min_x = f64::from(x) * tile_size - HALF_WORLD_M;
+        let max_y = HALF_WORLD_M - f64::from(y) * tile_size;
+        Rect::new(
+            Coord {
+                x: min_x,
+                y: max_y - tile_size,
+            },
+            Coord {
+                x: min_x + tile_size,
+                y: max_y,
+            },
+        )
+    }
+
+    /// Writes every visited tile as a polygon, flagged if it belongs to a cluster
+    /// or the max square, e.g. to `data/tiles_walk_14.fgb` for suffix `_walk`.
+    /// Returns the path written to.
+    pub fn write(&self, suffix: &str, output: &Output) -> Result<String, Error> {
+        let cluster_tiles = self.cluster_tiles();
+        let square = self.max_square();
+        let in_square = |(x, y): Tile| {
+            square.is_some_and(|((left, top), size)| {
+                (left..left + size).contains(&x) && (top..top + size).contains(&y)
+            })
+        };
+
+        let mut tiles: Vec<Tile> = self.tiles.iter().copied().collect();
+        tiles.sort_unstable();
+        let features: Vec<TileFeature> = tiles
+            .into_iter()
+            .map(|tile| {
+                Ok(TileFeature {
+                    geo: output.project(&self.bounds(tile).to_polygon())?,
+                    x: tile.0,
+                    y: tile.1,
+                    cluster: cluster_tiles.contains(&tile),
+                    max_square: in_square(tile),
+                })
+            })
+            .collect::<Result<_, String>>()
+            .map_err(Error::Projection)?;
+
+        let path = output.path(&suffixed_path(
+            TILES_FILE,
+            &format!("{}_{}", suffix, self.zoom),
+        ));
+        progress!(output.progress, "Writing tiles to {}...", path);
+        write_features_to_flatgeobuf(&features, &path, output)
+            .map_err(|e| Error::write(&path, e))?;
+        progress!(
+            output.progress,
+            "✓ Successfully wrote {} tiles to {}",
+            features.len(),
+            path
+        );
+        Ok(path)
+    }
+}
+
+fn neighbours((x, y): Tile) -> impl Iterator<Item = Tile> {
+    [
+        x.checked_sub(1).map(|x| (x, y)),
+        x.checked_add(1).map(|x| (x, y)),
+        y.checked_sub(1).map(|y| (x, y)),
+        y.checked_add(1).map(|y| (x, y)),
+    ]
+    .into_iter()
+    .flatten()
+}
+
+/// Computes and writes the tiles of every zoom level, plus their stats as JSON.
+/// `suffix` is appended to every output filename. Returns the `layers.json` entry of
+/// every zoom level.
+pub fn build_tiles(
+    activities: &[Activity],
+    zooms: &[u8],
+    max_gap_m: f64,
+    suffix: &str,
+    output: &Output,
+) -> Result<Vec<LayerEntry>, Error> {
+    let mut all_stats = Vec::new();
+    let mut entries = Vec::new();
+
+    for &zoom in zooms {
+        progress!(output.progress, "\nCollecting zoom {} tiles...", zoom);
+        let coverage = visited_tiles(activities, zoom, max_gap_m);
+        let stats = coverage.stats();
+        progress!(
+            output.progress,
+            "Visited {} tiles, max square {}x{}, max cluster {}",
+            stats.tiles,
+            stats.max_square,
+            stats.max_square,
+            stats.max_cluster
+        );
+
+        let path = coverage.write(suffix, output)?;
+        // every point lies in a tile, so the tiles were visited when the points were
+        let summary = LayerSummary::new(
+            "tiles",
+            coverage.tiles.len(),
+            union_rect(coverage.tiles.iter().map(|&tile| coverage.bounds(tile))),
+            activities
+                .iter()
+                .flat_map(|activity| &activity.points)
+                .filter_map(|p| p.time),
+        );
+        entries.push(LayerEntry::new(
+            &format!("tiles_{}", zoom),
+            &format!("Tiles z{}", zoom),
+            &pmtiles_filename(&path).map_err(|e| Error::write(&path, e))?,
+            GeometryType::Polygons,
+            &summary,
+        ));
+        all_stats.push(stats);
+    }
+
+    let stats_path = output.path(&suffixed_path(TILES_STATS_FILE, suffix));
+    write_json(&all_stats, &stats_path).map_err(|e| Error::write(&stats_path, e))?;
+    progress!(output.progress, "✓ Wrote tile stats to {}", stats_path);
+
+    Ok(entries)
+}
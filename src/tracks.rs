@@ -0,0 +1,277 @@
+use chrono::{DateTime, SecondsFormat, Utc};
+use clap::ValueEnum;
+use geo::{Coord, Distance, Euclidean, Point};
+use rayon::prelude::*;
+use std::fmt::Write as _;
+use std::fs::{self, File};
+use std::io::BufWriter;
+use std::path::Path;
+
+use crate::TRACKS_FILE;
+use crate::hashable_point::dedup_consecutive;
+use crate::io::Output;
+use crate::measure::{mercator_scale, to_wgs84};
+use crate::parsers::{Activity, TrackPoint};
+
+/// `creator` of exported GPX files, so the GPX parser can tell them apart
+pub const TRACK_EXPORT_CREATOR: &str = concat!(env!("CARGO_PKG_NAME"), " track export");
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum TrackFormat {
+    /// GPX 1.1 with one track per activity
+    Gpx,
+    /// KML with one placemark per activity, e.g. for Google Earth
+    Kml,
+}
+
+impl TrackFormat {
+    fn extension(&self) -> &'static str {
+        match self {
+            TrackFormat::Gpx => "gpx",
+            TrackFormat::Kml => "kml",
+        }
+    }
+}
+
+pub struct TrackExportOptions {
+    pub formats: Vec<TrackFormat>,
+    /// One file per activity in a TRACKS_FILE directory instead of a single file with all of them
+    pub split: bool,
+    /// Points further apart start a new segment, e.g. where fast segments were removed
+    pub max_gap_m: f64,
+}
+
+/// A cleaned activity, ready to be written.
+struct Track {
+    name: String,
+    kind: &'static str,
+    /// Parts of the track without gaps, in WGS84
+    segments: Vec<Vec<(Coord, Option<DateTime<Utc>>)>>,
+    start: Option<DateTime<Utc>>,
+    end: Option<DateTime<Utc>>,
+}
+
+impl Track {
+    /// Dedups the points of a projected activity and splits it where points are further
+    /// apart than `max_gap_m`.
+    fn new(index: usize, activity: &Activity, max_gap_m: f64) -> Option<Self> {
+        let points = dedup_consecutive(&activity.points);
+        let first = points.first()?;
+
+        let mut segments: Vec<Vec<(Coord, Option<DateTime<Utc>>)>> = Vec::new();
+        let mut previous: Option<&TrackPoint> = None;
+        for track_point in &points {
+            let is_gap = previous.is_none_or(|previous| {
+                let mid_y = (previous.point.y() + track_point.point.y()) / 2.0;
+                Euclidean.distance(previous.point, track_point.point) / mercator_scale(mid_y)
+                    > max_gap_m
+            });
+            if is_gap {
+                segments.push(Vec::new());
+            }
+            if let Some(segment) = segments.last_mut() {
+                segment.push((to_wgs84(track_point.point.0), track_point.time));
+            }
+            previous = Some(track_point);
+        }
+
+        Some(Track {
+            name: activity
+                .name
+                .clone()
+                .unwrap_or_else(|| format!("activity {}", index + 1)),
+            kind: first.kind.name(),
+            segments,
+            start: points.iter().filter_map(|p| p.time).min(),
+            end: points.iter().filter_map(|p| p.time).max(),
+        })
+    }
+
+    /// Name safe to use in a filename, prefixed by the index to keep names unique
+    fn file_stem(&self, index: usize) -> String {
+        let name: String = self
+            .name
+            .chars()
+            .map(|c| {
+                if c.is_alphanumeric() || c == '-' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .take(60)
+            .collect();
+        format!("{:05}_{}", index + 1, name)
+    }
+}
+
+/// Whether a GPX file was written by `export_tracks`, which marks its exports with
+/// [`TRACK_EXPORT_CREATOR`]. Users may name their own files `tracks.gpx` as well.
+pub fn is_track_export(gpx: &gpx::Gpx) -> bool {
+    gpx.creator.as_deref() == Some(TRACK_EXPORT_CREATOR)
+}
+
+/// Writes the cleaned activities as GPX and/or KML, either merged into
+/// `data/tracks.gpx` or one file per activity in `data/tracks/`.
+pub fn export_tracks(
+    activities: &[Activity],
+    options: &TrackExportOptions,
+    suffix: &str,
+    output: &Output,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let tracks: Vec<Track> = activities
+        .par_iter()
+        .enumerate()
+        .filter_map(|(index, activity)| Track::new(index, activity, options.max_gap_m))
+        .collect();
+    progress!(
+        output.progress,
+        "Exporting {} tracks with {} points...",
+        tracks.len(),
+        tracks
+            .iter()
+            .flat_map(|track| &track.segments)
+            .map(|segment| segment.len())
+            .sum::<usize>()
+    );
+
+    for &format in &options.formats {
+        if options.split {
+            let directory = output.path(&format!("{}{}", TRACKS_FILE, suffix));
+            fs::create_dir_all(&directory)?;
+            tracks
+                .par_iter()
+                .enumerate()
+                .try_for_each(|(index, track)| {
+                    let path = format!(
+                        "{}/{}.{}",
+                        directory,
+                        track.file_stem(index),
+                        format.extension()
+                    );
+                    write_tracks(std::slice::from_ref(track), format, &path)
+                })
+                .map_err(|e| -> Box<dyn std::error::Error> { e })?;
+            progress!(
+                output.progress,
+                "✓ Wrote {} tracks to {}/",
+                tracks.len(),
+                directory
+            );
+        } else {
+            let path = output.path(&format!("{}{}.{}", TRACKS_FILE, suffix, format.extension()));
+            write_tracks(&tracks, format, &path)
+                .map_err(|e| -> Box<dyn std::error::Error> { e })?;
+            progress!(
+                output.progress,
+                "✓ Wrote {} tracks to {}",
+                tracks.len(),
+                path
+            );
+        }
+    }
+
+    Ok(())
+}
+
+fn write_tracks(
+    tracks: &[Track],
+    format: TrackFormat,
+    path: &str,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    if let Some(parent) = Path::new(path).parent() {
+        fs::create_dir_all(parent)?;
+    }
+    match format {
+        TrackFormat::Gpx => {
+            let gpx = to_gpx(tracks)?;
+            gpx::write(&gpx, BufWriter::new(File::create(path)?))?;
+        }
+        TrackFormat::Kml => fs::write(path, to_kml(tracks)?)?,
+    }
+    Ok(())
+}
+
+fn to_gpx_time(time: DateTime<Utc>) -> Result<gpx::Time, time::error::ComponentRange> {
+    let nanos = time.timestamp() as i128 * 1_000_000_000 + time.timestamp_subsec_nanos() as i128;
+    Ok(time::OffsetDateTime::from_unix_timestamp_nanos(nanos)?.into())
+}
+
+fn to_gpx(tracks: &[Track]) -> Result<gpx::Gpx, time::error::ComponentRange> {
+    let mut gpx_tracks = Vec::new();
+    for track in tracks {
+        let mut gpx_track = gpx::Track::new();
+        gpx_track.name = Some(track.name.clone());
+        gpx_track.type_ = Some(track.kind.to_string());
+        for segment in &track.segments {
+            let mut gpx_segment = gpx::TrackSegment::new();
+            for &(coord, time) in segment {
+                let mut waypoint = gpx::Waypoint::new(Point(coord));
+                waypoint.time = time.map(to_gpx_time).transpose()?;
+                gpx_segment.points.push(waypoint);
+            }
+            gpx_track.segments.push(gpx_segment);
+        }
+        gpx_tracks.push(gpx_track);
+    }
+
+    Ok(gpx::Gpx {
+        version: gpx::GpxVersion::Gpx11,
+        creator: Some(TRACK_EXPORT_CREATOR.to_string()),
+        tracks: gpx_tracks,
+        ..Default::default()
+    })
+}
+
+pub fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// One placemark per track with its time span, so Google Earth can animate them.
+fn to_kml(tracks: &[Track]) -> Result<String, std::fmt::Error> {
+    let mut kml = String::new();
+    writeln!(kml, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+    writeln!(kml, r#"<kml xmlns="http://www.opengis.net/kml/2.2">"#)?;
+    writeln!(kml, "<Document>")?;
+    for track in tracks {
+        writeln!(kml, "<Placemark>")?;
+        writeln!(kml, "<name>{}</name>", escape_xml(&track.name))?;
+        writeln!(kml, "<description>{}</description>", track.kind)?;
+        if let (Some(start), Some(end)) = (track.start, track.end) {
+            writeln!(
+                kml,
+                "<TimeSpan><begin>{}</begin><end>{}</end></TimeSpan>",
+                start.to_rfc3339_opts(SecondsFormat::Secs, true),
+                end.to_rfc3339_opts(SecondsFormat::Secs, true)
+            )?;
+        }
+        writeln!(kml, "<MultiGeometry>")?;
+        for segment in &track.segments {
+            let coordinates: Vec<String> = segment
+                .iter()
+                .map(|(coord, _)| format!("{:.7},{:.7}", coord.x, coord.y))
+                .collect();
+            if coordinates.len() == 1 {
+                writeln!(
+                    kml,
+                    "<Point><coordinates>{}</coordinates></Point>",
+                    coordinates[0]
+                )?;
+            } else {
+                writeln!(
+                    kml,
+                    "<LineString><tessellate>1</tessellate><coordinates>{}</coordinates></LineString>",
+                    coordinates.join(" ")
+                )?;
+            }
+        }
+        writeln!(kml, "</MultiGeometry>")?;
+        writeln!(kml, "</Placemark>")?;
+    }
+    writeln!(kml, "</Document>")?;
+    writeln!(kml, "</kml>")?;
+    Ok(kml)
+}
@@ -0,0 +1,106 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, SystemTime};
+use walkdir::WalkDir;
+
+/// Size and modification time of every file below a directory.
+pub struct Snapshot {
+    dir: PathBuf,
+    /// Directories below `dir` that are left out, canonicalized
+    skip: Vec<PathBuf>,
+    files: HashMap<PathBuf, (u64, Option<SystemTime>)>,
+}
+
+impl Snapshot {
+    /// Snapshots `dir` without the directories in `skip`, e.g. where the layers are
+    /// written. `dir` itself is never skipped.
+    pub fn new(dir: &Path, skip: &[&Path]) -> Self {
+        let mut snapshot = Snapshot {
+            dir: dir.to_path_buf(),
+            skip: skip
+                .iter()
+                .filter_map(|path| fs::canonicalize(path).ok())
+                .collect(),
+            files: HashMap::new(),
+        };
+        snapshot.files = snapshot.scan();
+        snapshot
+    }
+
+    /// The current state of the same directory
+    fn rescan(&self) -> Snapshot {
+        Snapshot {
+            dir: self.dir.clone(),
+            skip: self.skip.clone(),
+            files: self.scan(),
+        }
+    }
+
+    fn scan(&self) -> HashMap<PathBuf, (u64, Option<SystemTime>)> {
+        WalkDir::new(&self.dir)
+            .into_iter()
+            .filter_entry(|entry| {
+                entry.depth() == 0 || !entry.file_type().is_dir() || !self.is_skipped(entry.path())
+            })
+            .filter_map(|e| e.ok())
+            .filter(|entry| entry.file_type().is_file())
+            .filter_map(|entry| {
+                let metadata = entry.metadata().ok()?;
+                Some((
+                    entry.into_path(),
+                    (metadata.len(), metadata.modified().ok()),
+                ))
+            })
+            .collect()
+    }
+
+    fn is_skipped(&self, dir: &Path) -> bool {
+        fs::canonicalize(dir).is_ok_and(|dir| self.skip.contains(&dir))
+    }
+
+    /// Files added, changed or removed since `self`
+    fn changed_files(&self, other: &Snapshot) -> Vec<PathBuf> {
+        let mut changed: Vec<PathBuf> = other
+            .files
+            .iter()
+            .filter(|(path, state)| self.files.get(*path) != Some(state))
+            .map(|(path, _)| path.clone())
+            .chain(
+                self.files
+                    .keys()
+                    .filter(|path| !other.files.contains_key(*path))
+                    .cloned(),
+            )
+            .collect();
+        changed.sort_unstable();
+        changed
+    }
+
+    /// Polls every `interval` until files changed and then stayed the same for another
+    /// `interval`, e.g. while an export is still being copied. Returns the changed files
+    /// and moves on to the new state.
+    pub fn wait_for_changes(&mut self, interval: Duration) -> Vec<PathBuf> {
+        loop {
+            thread::sleep(interval);
+            let mut current = self.rescan();
+            if self.changed_files(&current).is_empty() {
+                continue;
+            }
+
+            loop {
+                thread::sleep(interval);
+                let settled = self.rescan();
+                if current.changed_files(&settled).is_empty() {
+                    break;
+                }
+                current = settled;
+            }
+
+            let changed = self.changed_files(&current);
+            *self = current;
+            return changed;
+        }
+    }
+}
@@ -1,57 +1,88 @@
 'use client'
 
-import type { Map as MapType } from 'maplibre-gl'
+import type { ExpressionSpecification, Map as MapType } from 'maplibre-gl'
 import maplibregl from 'maplibre-gl'
 import { PMTiles, Protocol } from 'pmtiles'
 import { useEffect, useRef, useState } from 'react'
 
-type PMTilesFile = {
+type GeometryType = 'points' | 'lines' | 'polygons' | 'raster'
+
+// a generated PMTiles file as listed in `layers.json`
+type LayerEntry = {
   id: string
   name: string
   filename: string
-  description: string
-  type: 'points' | 'polygons'
+  geometry_type: GeometryType
+  radius_m?: number
+  feature_count: number
+  feature_name: string
+  bbox: [number, number, number, number] | null
+  first_seen: number | null
+  last_seen: number | null
+}
+
+// where the map opens, on the area with the most points
+type InitialView = {
+  center: [number, number]
+  zoom: number
+  bounds: [number, number, number, number]
+}
+
+type LayerManifest = {
+  built_at: number
+  view: InitialView | null
+  layers: LayerEntry[]
+}
+
+const COLORS: Record<string, { fill: string; stroke: string }> = {
+  points: { fill: '#3b82f6', stroke: '#60a5fa' },
+  heatmap: { fill: '#ef4444', stroke: '#f87171' },
+  buffer_100: { fill: '#10b981', stroke: '#059669' },
+  buffer_1000: { fill: '#f59e0b', stroke: '#d97706' },
+  fog: { fill: '#111827', stroke: '#374151' },
+  matched: { fill: '#ec4899', stroke: '#ec4899' },
+  new_ground: { fill: '#facc15', stroke: '#eab308' }
+}
+const DEFAULT_COLOR = { fill: '#8b5cf6', stroke: '#7c3aed' }
+
+const colorOf = (layer: LayerEntry) => COLORS[layer.id] ?? DEFAULT_COLOR
+
+// new ground from old to recent activities, by the time of the activity that explored it
+const fillOf = (layer: LayerEntry): string | ExpressionSpecification => {
+  const { first_seen: first, last_seen: last } = layer
+  if (layer.id !== 'new_ground' || first === null || last === null || first >= last) return colorOf(layer).fill
+  return ['interpolate', ['linear'], ['get', 'time'], first, '#1e3a8a', (first + last) / 2, '#14b8a6', last, '#facc15']
 }
 
-const PMTILES_FILES: PMTilesFile[] = [
-  {
-    id: 'base',
-    name: 'Base Points',
-    filename: 'out.pmtiles',
-    description: 'Original point data',
-    type: 'points'
-  },
-  {
-    id: 'buffer100',
-    name: 'Buffer 100m',
-    filename: 'out_buffer_100.pmtiles',
-    description: '100m buffer polygons',
-    type: 'polygons'
-  },
-  {
-    id: 'buffer1000',
-    name: 'Buffer 1000m',
-    filename: 'out_buffer_1000.pmtiles',
-    description: '1000m buffer polygons',
-    type: 'polygons'
-  },
-  {
-    id: 'heatmap',
-    name: 'Heatmap',
-    filename: 'heatmap.pmtiles',
-    description: '1m accuracy heatmap points',
-    type: 'points'
+const describe = (layer: LayerEntry) => {
+  const parts = [`${layer.feature_count.toLocaleString()} ${layer.feature_name}`]
+  if (layer.radius_m) parts.push(`${layer.radius_m}m radius`)
+  if (layer.first_seen !== null && layer.last_seen !== null) {
+    const first = new Date(layer.first_seen * 1000).getFullYear()
+    const last = new Date(layer.last_seen * 1000).getFullYear()
+    parts.push(first === last ? `${first}` : `${first}–${last}`)
   }
-]
+  return parts.join(' · ')
+}
 
 export const Map = () => {
   const mapContainer = useRef<HTMLDivElement>(null)
   const map = useRef<MapType>(null)
-  const [lng, setLng] = useState(8.4)
-  const [lat, setLat] = useState(49)
-  const [z, setZoom] = useState(12.25)
-  const [selectedFile, setSelectedFile] = useState<PMTilesFile>(PMTILES_FILES[0])
-  const [currentSourceId, setCurrentSourceId] = useState<string | null>(null)
+  // the whole world until the manifest tells where the data is
+  const [lng, setLng] = useState(0)
+  const [lat, setLat] = useState(20)
+  const [z, setZoom] = useState(1.5)
+  const [manifest, setManifest] = useState<LayerManifest | null>(null)
+  const [selectedId, setSelectedId] = useState<string | null>(null)
+  const [mapLoaded, setMapLoaded] = useState(false)
+  // ids of the map layers and the source showing the selected layer
+  const currentLayers = useRef<{ sourceId: string; layerIds: string[] } | null>(null)
+  // zoom to the data when switching layers, but keep the view when they were rebuilt
+  const fitToData = useRef(true)
+  // the initial view is only applied once, later builds keep the current view
+  const viewApplied = useRef(false)
+
+  const selectedLayer = manifest?.layers.find((layer) => layer.id === selectedId) ?? manifest?.layers[0]
 
   // Initialize PMTiles protocol
   useEffect(() => {
@@ -83,131 +114,173 @@ export const Map = () => {
     })
   }, [])
 
+  // Read the layers of the last build and check for a new one, e.g. by `watch`
+  useEffect(() => {
+    const checkManifest = async () => {
+      try {
+        const response = await fetch('layers.json', { cache: 'no-store' })
+        if (!response.ok) return
+        const next = (await response.json()) as LayerManifest
+        setManifest((current) => {
+          if (current?.built_at === next.built_at) return current
+          if (current !== null) fitToData.current = false
+          return next
+        })
+      } catch (error) {
+        console.error('Error reading layers.json:', error)
+      }
+    }
+    checkManifest()
+    const interval = setInterval(checkManifest, 5000)
+    return () => clearInterval(interval)
+  }, [])
+
   // Clear current layer if exists
   const clearCurrentLayer = () => {
-    if (!map.current || !currentSourceId) return
+    if (!map.current || !currentLayers.current) return
 
     const currentMap = map.current
+    const { sourceId, layerIds } = currentLayers.current
     try {
-      // Remove all possible layers for the current source
-      const layers = [`${currentSourceId}-points`, `${currentSourceId}-polygons`, `${currentSourceId}-polygons-stroke`]
-      layers.forEach((layerId) => {
+      layerIds.forEach((layerId) => {
         if (currentMap.getLayer(layerId)) {
           currentMap.removeLayer(layerId)
         }
       })
 
-      if (currentMap.getSource(currentSourceId)) {
-        currentMap.removeSource(currentSourceId)
+      if (currentMap.getSource(sourceId)) {
+        currentMap.removeSource(sourceId)
       }
     } catch (error) {
       console.error('Error clearing current layer:', error)
     }
+    currentLayers.current = null
   }
 
-  // Load PMTiles file based on selected file
-  const loadPMTilesFile = async (file: PMTilesFile) => {
+  // Load the PMTiles file of a layer, `version` makes sure rebuilt files aren't taken from the cache
+  const loadLayer = async (layer: LayerEntry, version: number, fit: boolean) => {
     if (!map.current) return
 
     // Clear previous layer
     clearCurrentLayer()
 
     try {
-      const pmtilesUrl = `pmtiles://${file.filename}`
-      const sourceId = `pmtiles-${file.filename}`
-      const layerId = `${sourceId}-${file.type}`
+      const url = `${layer.filename}?v=${version}`
+      const pmtilesUrl = `pmtiles://${url}`
+      const sourceId = `pmtiles-${url}`
+      const layerId = `${sourceId}-${layer.geometry_type}`
+      const color = colorOf(layer)
 
       // Try to read PMTiles metadata to get layer information
-      const pmtiles = new PMTiles(file.filename)
+      const pmtiles = new PMTiles(url)
       const metadata = (await pmtiles.getMetadata()) as any
 
-      console.log('metadata', metadata)
-
       // Get the first layer name (assuming single layer as per requirement)
       const sourceLayer =
         metadata?.vector_layers && metadata.vector_layers.length > 0 ? metadata.vector_layers[0].id : 'default'
 
-      // Add the PMTiles source
-      if (!map.current.getSource(sourceId))
-        map.current.addSource(sourceId, {
-          type: 'vector',
-          url: pmtilesUrl
+      const layerIds = [layerId]
+      if (layer.geometry_type === 'raster') {
+        map.current.addSource(sourceId, { type: 'raster', url: pmtilesUrl, tileSize: 256 })
+        map.current.addLayer({
+          id: layerId,
+          type: 'raster',
+          source: sourceId,
+          paint: { 'raster-opacity': 0.8 }
         })
-
-      // Add appropriate layer based on file type
-      if (file.id === 'heatmap') {
-        if (!map.current.getLayer(layerId))
-          map.current.addLayer({
-            id: layerId,
-            type: 'heatmap',
-            source: sourceId,
-            'source-layer': sourceLayer,
-            paint: {
-              'heatmap-weight': 0.2,
-              'heatmap-intensity': 1,
-              'heatmap-color': [
-                'interpolate',
-                ['linear'],
-                ['heatmap-density'],
-                0, 'rgba(0,0,255,0)',
-                0.1, 'royalblue',
-                0.3, 'cyan',
-                0.5, 'lime',
-                0.7, 'yellow',
-                1, 'red'
-              ],
-              'heatmap-radius': 10,
-              'heatmap-opacity': 0.8
-            }
-          })
-      } else if (file.type === 'points') {
-        if (!map.current.getLayer(layerId))
-          map.current.addLayer({
-            id: layerId,
-            type: 'circle',
-            source: sourceId,
-            'source-layer': sourceLayer,
-            paint: {
-              'circle-pitch-scale': 'map',
-              'circle-radius': 4,
-              'circle-opacity': 0.2,
-              'circle-color': '#3b82f6'
-            }
-          })
       } else {
-        // For polygons (buffer zones)
-        if (!map.current.getLayer(layerId))
-          map.current.addLayer({
-            id: layerId,
-            type: 'fill',
-            source: sourceId,
-            'source-layer': sourceLayer,
-            paint: {
-              'fill-color': file.id === 'buffer100' ? '#10b981' : '#f59e0b',
-              'fill-opacity': 0.3
-            }
-          })
+        map.current.addSource(sourceId, { type: 'vector', url: pmtilesUrl })
+      }
+
+      if (layer.id === 'heatmap') {
+        map.current.addLayer({
+          id: layerId,
+          type: 'heatmap',
+          source: sourceId,
+          'source-layer': sourceLayer,
+          paint: {
+            // every feature is a grid cell with the number of points in it
+            'heatmap-weight': ['*', 0.2, ['coalesce', ['get', 'count'], 1]],
+            'heatmap-intensity': 1,
+            'heatmap-color': [
+              'interpolate',
+              ['linear'],
+              ['heatmap-density'],
+              0, 'rgba(0,0,255,0)',
+              0.1, 'royalblue',
+              0.3, 'cyan',
+              0.5, 'lime',
+              0.7, 'yellow',
+              1, 'red'
+            ],
+            'heatmap-radius': 10,
+            'heatmap-opacity': 0.8
+          }
+        })
+      } else if (layer.geometry_type === 'points') {
+        map.current.addLayer({
+          id: layerId,
+          type: 'circle',
+          source: sourceId,
+          'source-layer': sourceLayer,
+          paint: {
+            'circle-pitch-scale': 'map',
+            'circle-radius': 4,
+            // cells visited more often are more opaque
+            'circle-opacity': [
+              'interpolate',
+              ['linear'],
+              ['ln', ['coalesce', ['get', 'count'], 1]],
+              0, 0.2,
+              5, 0.8
+            ],
+            'circle-color': color.fill
+          }
+        })
+      } else if (layer.geometry_type === 'lines') {
+        map.current.addLayer({
+          id: layerId,
+          type: 'line',
+          source: sourceId,
+          'source-layer': sourceLayer,
+          paint: {
+            'line-color': color.stroke,
+            'line-width': 2,
+            'line-opacity': 0.8
+          }
+        })
+      } else if (layer.geometry_type === 'polygons') {
+        map.current.addLayer({
+          id: layerId,
+          type: 'fill',
+          source: sourceId,
+          'source-layer': sourceLayer,
+          paint: {
+            'fill-color': fillOf(layer),
+            'fill-opacity': 0.3
+          }
+        })
 
         // Add stroke for polygons
-        if (!map.current.getLayer(`${layerId}-stroke`))
-          map.current.addLayer({
-            id: `${layerId}-stroke`,
-            type: 'line',
-            source: sourceId,
-            'source-layer': sourceLayer,
-            paint: {
-              'line-color': file.id === 'buffer100' ? '#059669' : '#d97706',
-              'line-width': 1,
-              'line-opacity': 0.8
-            }
-          })
+        map.current.addLayer({
+          id: `${layerId}-stroke`,
+          type: 'line',
+          source: sourceId,
+          'source-layer': sourceLayer,
+          paint: {
+            'line-color': color.stroke,
+            'line-width': 1,
+            'line-opacity': 0.8
+          }
+        })
+        layerIds.push(`${layerId}-stroke`)
       }
 
-      setCurrentSourceId(sourceId)
-      console.log(`Loaded PMTiles file: ${file.filename} with layer: ${sourceLayer}`)
+      currentLayers.current = { sourceId, layerIds }
+      console.log(`Loaded PMTiles file: ${layer.filename} with layer: ${sourceLayer}`)
 
       // Zoom to the bounds of the data if available
-      if (metadata?.bounds && Array.isArray(metadata.bounds) && metadata.bounds.length === 4) {
+      if (fit && metadata?.bounds && Array.isArray(metadata.bounds) && metadata.bounds.length === 4) {
         const [minLng, minLat, maxLng, maxLat] = metadata.bounds
         map.current.fitBounds(
           [
@@ -222,29 +295,32 @@ export const Map = () => {
     }
   }
 
-  // Switch to a different file
-  const switchToFile = (file: PMTilesFile) => {
-    setSelectedFile(file)
-    if (map.current && map.current.isStyleLoaded()) {
-      loadPMTilesFile(file)
-    }
+  // Switch to a different layer
+  const switchToLayer = (layer: LayerEntry) => {
+    fitToData.current = true
+    setSelectedId(layer.id)
   }
 
-  // Load initial PMTiles when map is ready
   useEffect(() => {
     if (!map.current) return
 
-    map.current.on('load', () => {
-      loadPMTilesFile(selectedFile)
-    })
+    map.current.on('load', () => setMapLoaded(true))
   }, [])
 
-  // Load new file when selection changes
+  // Open on the densest area of the data instead of the bounds of the first layer
   useEffect(() => {
-    if (map.current && map.current.isStyleLoaded()) {
-      loadPMTilesFile(selectedFile)
-    }
-  }, [selectedFile])
+    if (!mapLoaded || !manifest?.view || !map.current || viewApplied.current) return
+    viewApplied.current = true
+    fitToData.current = false
+    map.current.jumpTo({ center: manifest.view.center, zoom: manifest.view.zoom })
+  }, [mapLoaded, manifest])
+
+  // Load the selected layer once the map is ready, and again when it was rebuilt
+  useEffect(() => {
+    if (!mapLoaded || !manifest || !selectedLayer) return
+    loadLayer(selectedLayer, manifest.built_at, fitToData.current)
+    fitToData.current = false
+  }, [mapLoaded, manifest, selectedLayer?.id])
 
   return (
     <div className="flex flex-col flex-grow">
@@ -256,34 +332,25 @@ export const Map = () => {
         <div className="flex items-center justify-center space-x-4">
           <span className="text-white text-sm font-medium">Choose renderer:</span>
           <div className="flex space-x-2">
-            {PMTILES_FILES.map((file) => (
+            {manifest === null && <span className="text-gray-400 text-sm">No layers built yet</span>}
+            {manifest?.layers.map((layer) => (
               <button
-                key={file.id}
-                onClick={() => switchToFile(file)}
+                key={layer.id}
+                onClick={() => switchToLayer(layer)}
                 className={`px-4 py-2 rounded-lg text-sm font-medium transition-all duration-200 ${
-                  selectedFile.id === file.id
+                  selectedLayer?.id === layer.id
                     ? 'bg-blue-600 text-white shadow-lg'
                     : 'bg-gray-700 text-gray-300 hover:bg-gray-600 hover:text-white'
                 }`}
               >
                 <div className="flex items-center space-x-2">
-                  <div
-                    className={`w-3 h-3 rounded ${
-                      file.type === 'points'
-                        ? file.id === 'heatmap'
-                          ? 'bg-red-400'
-                          : 'bg-blue-400'
-                        : file.id === 'buffer100'
-                          ? 'bg-emerald-400'
-                          : 'bg-amber-400'
-                    }`}
-                  ></div>
-                  <span>{file.name}</span>
+                  <div className="w-3 h-3 rounded" style={{ backgroundColor: colorOf(layer).stroke }}></div>
+                  <span>{layer.name}</span>
                 </div>
               </button>
             ))}
           </div>
-          <div className="text-gray-400 text-xs w-xs">{selectedFile.description}</div>
+          {selectedLayer && <div className="text-gray-400 text-xs w-xs">{describe(selectedLayer)}</div>}
         </div>
       </div>
     </div>